@@ -0,0 +1,8 @@
+DROP SEQUENCE fork_seq;
+DROP INDEX blocks_parent_hash;
+DROP INDEX blocks_canonical_block_num;
+DELETE FROM blocks WHERE NOT canonical;
+ALTER TABLE blocks DROP COLUMN fork;
+ALTER TABLE blocks DROP COLUMN finalized;
+ALTER TABLE blocks DROP COLUMN canonical;
+ALTER TABLE blocks ADD CONSTRAINT blocks_block_num_key UNIQUE (block_num);
@@ -0,0 +1,11 @@
+-- Blocks that are not yet finalized may be on a fork that is later abandoned
+-- More than one block may exist at a height, but only one of them is canonical
+ALTER TABLE blocks DROP CONSTRAINT blocks_block_num_key;
+ALTER TABLE blocks ADD COLUMN canonical bool NOT NULL DEFAULT true;
+ALTER TABLE blocks ADD COLUMN finalized bool NOT NULL DEFAULT true;
+-- blocks built on top of each other share a fork id. A new id is handed out whenever a block
+-- does not extend the current canonical head
+ALTER TABLE blocks ADD COLUMN fork bigint NOT NULL DEFAULT 0;
+CREATE UNIQUE INDEX blocks_canonical_block_num ON blocks (block_num) WHERE canonical;
+CREATE INDEX blocks_parent_hash ON blocks (parent_hash);
+CREATE SEQUENCE fork_seq START 1;
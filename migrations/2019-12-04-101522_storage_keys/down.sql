@@ -0,0 +1,4 @@
+DROP INDEX storage_hash_key;
+DELETE FROM storage WHERE parameters IS NULL;
+ALTER TABLE storage ALTER COLUMN parameters SET NOT NULL;
+ALTER TABLE storage DROP COLUMN key;
@@ -0,0 +1,6 @@
+-- the key a storage value was read from. Together with the block hash, identifies
+-- a value that has already been crawled
+ALTER TABLE storage ADD COLUMN key bytea NOT NULL;
+-- NULL if nothing was stored under the key at that block
+ALTER TABLE storage ALTER COLUMN parameters DROP NOT NULL;
+CREATE UNIQUE INDEX storage_hash_key ON storage (hash, key);
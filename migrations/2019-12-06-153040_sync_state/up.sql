@@ -0,0 +1,14 @@
+-- How far each phase of the historical sync has gotten
+-- Read on startup so that sync resumes where it left off
+CREATE TABLE sync_state (
+  phase varchar PRIMARY KEY,
+  -- every block up to and including this one is done
+  completed_to bigint check (completed_to >= 0 and completed_to < '9223372036854775807'::bigint) NOT NULL,
+  -- the latest finalized block when the phase was last checked
+  target bigint check (target >= 0 and target < '9223372036854775807'::bigint) NOT NULL,
+  -- blocks per second
+  rate double precision NOT NULL,
+  -- estimated seconds until target is reached
+  eta bigint,
+  updated_at timestamptz NOT NULL
+);
@@ -0,0 +1,12 @@
+-- Which runtime the archived blocks were executed with
+-- A runtime upgrade starts a new spec version from the block it is enacted in
+CREATE TABLE runtime_versions (
+  spec_version integer PRIMARY KEY,
+  spec_name varchar NOT NULL,
+  -- first archived block executed with this spec version
+  from_block bigint check (from_block >= 0 and from_block < '9223372036854775807'::bigint) NOT NULL,
+  -- last archived block executed with this spec version
+  to_block bigint check (to_block >= 0 and to_block < '9223372036854775807'::bigint) NOT NULL,
+  -- hash of `from_block`, the metadata of this version is fetched at this block
+  from_hash bytea NOT NULL
+);
@@ -0,0 +1,2 @@
+ALTER TABLE events DROP CONSTRAINT events_hash_event_index_key;
+ALTER TABLE events DROP COLUMN event_index;
@@ -0,0 +1,4 @@
+-- Position of each event in `System.Events` of its block
+-- events are only written once per block, whether they come from the live subscription or the crawl
+ALTER TABLE events ADD COLUMN event_index integer NOT NULL;
+ALTER TABLE events ADD CONSTRAINT events_hash_event_index_key UNIQUE (hash, event_index);
@@ -0,0 +1,16 @@
+-- Extrinsics seen in the transaction pool of the node
+CREATE TABLE pending_extrinsics (
+  -- hash of the encoded extrinsic
+  hash bytea PRIMARY KEY,
+  data bytea NOT NULL,
+  -- the decoded extrinsic, if it could be decoded
+  signed boolean,
+  module varchar,
+  call varchar,
+  parameters jsonb,
+  -- when the extrinsic was first seen in the pool
+  first_seen timestamptz NOT NULL,
+  -- the block the extrinsic was included in, once that block is archived
+  included_in bytea REFERENCES blocks(hash) ON DELETE SET NULL ON UPDATE CASCADE
+);
+CREATE INDEX pending_extrinsics_included_in_idx ON pending_extrinsics (included_in);
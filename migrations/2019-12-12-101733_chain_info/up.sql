@@ -0,0 +1,10 @@
+-- The chain this database archives
+-- checked on startup, so blocks of another chain are never written into the same database
+CREATE TABLE chain_info (
+  genesis_hash bytea PRIMARY KEY,
+  -- `system_chain` of the node
+  chain varchar NOT NULL,
+  -- `system_properties` of the node, such as the SS58 prefix and token decimals
+  properties jsonb NOT NULL,
+  updated_at timestamptz NOT NULL
+);
@@ -0,0 +1 @@
+DROP TABLE child_storage;
@@ -0,0 +1,12 @@
+-- Values of child tries, such as the storage of contracts
+CREATE TABLE child_storage (
+    id SERIAL PRIMARY KEY,
+    block_num bigint check (block_num >= 0 and block_num < '9223372036854775807'::bigint) NOT NULL,
+    hash bytea NOT NULL REFERENCES blocks(hash) ON DELETE CASCADE ON UPDATE CASCADE,
+    -- top-level key the child trie is stored under, starting with `:child_storage:`
+    child_storage_key bytea NOT NULL,
+    -- key within the child trie
+    key bytea NOT NULL,
+    data bytea NOT NULL
+);
+CREATE UNIQUE INDEX child_storage_hash_key ON child_storage (hash, child_storage_key, key);
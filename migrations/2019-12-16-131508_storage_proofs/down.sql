@@ -0,0 +1,3 @@
+DROP INDEX storage_unverified_idx;
+ALTER TABLE storage DROP COLUMN verified;
+ALTER TABLE storage DROP COLUMN data;
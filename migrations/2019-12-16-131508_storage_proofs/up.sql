@@ -0,0 +1,12 @@
+-- the raw value read from the key, NULL if nothing was stored under it
+ALTER TABLE storage ADD COLUMN data bytea;
+-- values archived so far are kept in `parameters` as an array of bytes
+UPDATE storage SET data = (
+    SELECT decode(string_agg(lpad(to_hex(v::int), 2, '0'), '' ORDER BY i), 'hex')
+    FROM jsonb_array_elements_text(parameters->'value') WITH ORDINALITY AS bytes(v, i)
+) WHERE parameters IS NOT NULL;
+UPDATE storage SET data = '\x' WHERE parameters IS NOT NULL AND data IS NULL;
+-- whether the value was checked against a read proof of the state root of its block
+-- NULL until it is checked, false if it does not match the proof
+ALTER TABLE storage ADD COLUMN verified boolean;
+CREATE INDEX storage_unverified_idx ON storage (block_num) WHERE verified IS NULL;
@@ -0,0 +1,15 @@
+-- Blocks from the node that failed an integrity check, kept here instead of being archived
+CREATE TABLE quarantine (
+  id SERIAL PRIMARY KEY,
+  -- hash of the header, recomputed by the archive
+  hash bytea NOT NULL,
+  block_num bigint check (block_num >= 0 and block_num < '9223372036854775807'::bigint) NOT NULL,
+  -- which check the block failed
+  reason text NOT NULL,
+  -- the block as the node returned it: the SCALE-encoded header, and each encoded extrinsic
+  header bytea NOT NULL,
+  extrinsics bytea[] NOT NULL,
+  received_at timestamptz NOT NULL
+);
+CREATE UNIQUE INDEX quarantine_hash_reason ON quarantine (hash, reason);
+CREATE INDEX quarantine_block_num_idx ON quarantine (block_num);
@@ -0,0 +1,3 @@
+DROP INDEX events_hash_extrinsic_index_idx;
+ALTER TABLE events DROP COLUMN extrinsic_index;
+ALTER TABLE events DROP COLUMN phase;
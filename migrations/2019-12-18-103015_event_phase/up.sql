@@ -0,0 +1,9 @@
+-- When each event was deposited: while applying an extrinsic, or in block initialization/finalization
+-- Events archived so far have no phase; they are kept with phase 'Unknown' until the events crawl,
+-- which starts over, archives them again with their phase
+ALTER TABLE events ADD COLUMN phase varchar NOT NULL DEFAULT 'Unknown';
+ALTER TABLE events ALTER COLUMN phase DROP DEFAULT;
+DELETE FROM sync_state WHERE phase = 'events';
+-- index of the extrinsic in its block, to join with `signed_extrinsics.tx_index` and `inherents.in_index`
+ALTER TABLE events ADD COLUMN extrinsic_index integer;
+CREATE INDEX events_hash_extrinsic_index_idx ON events (hash, extrinsic_index);
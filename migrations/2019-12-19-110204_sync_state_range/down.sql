@@ -0,0 +1,7 @@
+-- only the checkpoints of the open range from genesis fit the single checkpoint per phase
+DELETE FROM sync_state WHERE from_block <> 0 OR to_block IS NOT NULL;
+DROP INDEX sync_state_range;
+ALTER TABLE sync_state DROP COLUMN to_block;
+ALTER TABLE sync_state DROP COLUMN from_block;
+ALTER TABLE sync_state DROP COLUMN id;
+ALTER TABLE sync_state ADD PRIMARY KEY (phase);
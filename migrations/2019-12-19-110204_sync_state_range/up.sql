@@ -0,0 +1,10 @@
+-- Each checkpoint belongs to the range of blocks it was saved for,
+-- so archives of different ranges into the same database keep their own progress
+-- Checkpoints saved so far were saved from the genesis block on, with no end to the range
+ALTER TABLE sync_state DROP CONSTRAINT sync_state_pkey;
+ALTER TABLE sync_state ADD COLUMN id SERIAL PRIMARY KEY;
+ALTER TABLE sync_state ADD COLUMN from_block bigint check (from_block >= 0 and from_block < '9223372036854775807'::bigint) NOT NULL DEFAULT 0;
+ALTER TABLE sync_state ALTER COLUMN from_block DROP DEFAULT;
+-- NULL if the range has no end
+ALTER TABLE sync_state ADD COLUMN to_block bigint check (to_block >= 0 and to_block < '9223372036854775807'::bigint);
+CREATE UNIQUE INDEX sync_state_range ON sync_state (phase, from_block, COALESCE(to_block, -1));
@@ -0,0 +1 @@
+DROP TABLE undecoded_events;
@@ -0,0 +1,12 @@
+-- `System.Events` of blocks that could not be decoded, kept as the node returned them
+-- so the events crawl moves past them instead of retrying forever
+CREATE TABLE undecoded_events (
+  id SERIAL PRIMARY KEY,
+  block_num bigint check (block_num >= 0 and block_num < '9223372036854775807'::bigint) NOT NULL,
+  hash bytea NOT NULL REFERENCES blocks(hash) ON DELETE CASCADE ON UPDATE CASCADE,
+  -- the SCALE-encoded events
+  data bytea NOT NULL,
+  -- why decoding failed
+  error text NOT NULL
+);
+CREATE UNIQUE INDEX undecoded_events_hash ON undecoded_events (hash);
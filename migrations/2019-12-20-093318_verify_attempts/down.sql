@@ -0,0 +1 @@
+ALTER TABLE storage DROP COLUMN verify_attempts;
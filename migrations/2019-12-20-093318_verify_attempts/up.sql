@@ -0,0 +1,3 @@
+-- times a read proof for the value could not be fetched
+-- values are no longer tried once this reaches the limit of the archive, and stay unverified
+ALTER TABLE storage ADD COLUMN verify_attempts integer NOT NULL DEFAULT 0;
@@ -0,0 +1,2 @@
+DROP INDEX signed_extrinsics_transaction_hash_idx;
+ALTER TABLE signed_extrinsics DROP COLUMN transaction_hash;
@@ -0,0 +1,4 @@
+-- hash of the encoded extrinsic, as the transaction pool hashes it
+-- links extrinsics seen in the pool to blocks that were archived before they were seen
+ALTER TABLE signed_extrinsics ADD COLUMN transaction_hash bytea;
+CREATE INDEX signed_extrinsics_transaction_hash_idx ON signed_extrinsics (transaction_hash);
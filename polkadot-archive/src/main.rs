@@ -16,6 +16,7 @@
 
 //! Specify types for a specific Blockchain -- E.G Kusama/Polkadot and run the archive node with these types
 
+use clap::{App, Arg};
 use failure::Error;
 // use substrate_archive::prelude::*;
 use polkadot_runtime::{
@@ -30,19 +31,115 @@ use runtime_primitives::{
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use substrate_archive::{
-    frame::frame_system as system, init_logger, Archive, Error as ArchiveError, ExtractCall,
-    FrameExt, Module, NotHandled, OldExtrinsic, RawExtrinsic, System, ToDatabaseExtrinsic,
+    frame::frame_system as system, init_logger, Archive, ArchiveConfig, Error as ArchiveError,
+    ExtractCall, FrameExt, Module, NotHandled, OldExtrinsic, RawExtrinsic, System,
+    ToDatabaseExtrinsic,
 };
 
 use codec::{Decode, Encode, Error as CodecError, Input};
 use polkadot_primitives::Signature;
 
-use std::fmt::Debug;
+use std::{fmt::Debug, time::Duration};
 
 fn main() -> Result<(), Error> {
     // convenience log function from substrate_archive which logs to .local/share/substrate_archive
     init_logger(log::LevelFilter::Warn, log::LevelFilter::Debug);
-    Archive::<Runtime>::new()?.run()?;
+    let matches = App::new("polkadot-archive")
+        .about("Archive the Polkadot chain into PostgreSQL")
+        .arg(
+            Arg::with_name("rpc")
+                .long("rpc")
+                .value_name("URL")
+                .help("Node to archive from; repeat for failover and load balancing")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("from")
+                .long("from")
+                .value_name("BLOCK")
+                .help("First block to archive")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("to")
+                .long("to")
+                .value_name("BLOCK")
+                .help("Last block to archive; new heads are not followed")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("metrics")
+                .long("metrics")
+                .value_name("ADDR")
+                .help("Serve Prometheus metrics at http://<ADDR>/metrics")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("pending")
+                .long("pending")
+                .value_name("SECS")
+                .help("Archive the transaction pool, checking it every SECS seconds")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("record")
+                .long("record")
+                .value_name("FILE")
+                .help("Append every RPC request and response to FILE")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("replay")
+                .long("replay")
+                .value_name("FILE")
+                .help("Archive from a recording made with --record instead of a node")
+                .takes_value(true)
+                .conflicts_with("rpc"),
+        )
+        .arg(
+            Arg::with_name("verify-storage")
+                .long("verify-storage")
+                .help("Check archived storage against read proofs of each block's state root"),
+        )
+        .arg(
+            Arg::with_name("stop-after-range")
+                .long("stop-after-range")
+                .help("Exit once every block in the range is archived"),
+        )
+        .get_matches();
+
+    let mut config = ArchiveConfig::default()
+        .stop_after_range(matches.is_present("stop-after-range"))
+        .verify_storage(matches.is_present("verify-storage"));
+    if let Some(mut urls) = matches.values_of("rpc") {
+        if let Some(url) = urls.next() {
+            config = config.rpc_url(url.parse()?);
+        }
+        for url in urls {
+            config = config.extra_rpc_url(url.parse()?);
+        }
+    }
+    if let Some(from) = matches.value_of("from") {
+        config = config.from_block(from.parse()?);
+    }
+    if let Some(to) = matches.value_of("to") {
+        config = config.to_block(to.parse()?);
+    }
+    if let Some(addr) = matches.value_of("metrics") {
+        config = config.metrics_addr(addr.parse()?);
+    }
+    if let Some(secs) = matches.value_of("pending") {
+        config = config.pending_interval(Duration::from_secs(secs.parse()?));
+    }
+    if let Some(path) = matches.value_of("record") {
+        config = config.record(path);
+    }
+    if let Some(path) = matches.value_of("replay") {
+        config = config.replay(path);
+    }
+    Archive::<Runtime>::with_config(config)?.run()?;
     Ok(())
 }
 
@@ -288,5 +385,6 @@ impl System for Runtime {
     type AccountId = <RuntimeT as system::Trait>::AccountId;
     type Header = <RuntimeT as system::Trait>::Header;
     type Event = <RuntimeT as system::Trait>::Event;
+    type Balance = polkadot_primitives::Balance;
     type SignedExtra = polkadot_runtime::SignedExtra;
 }
@@ -5,5 +5,20 @@ diesel migration revert
 diesel migration revert
 diesel migration revert
 diesel migration revert
+diesel migration revert
+diesel migration revert
+diesel migration revert
+diesel migration revert
+diesel migration revert
+diesel migration revert
+diesel migration revert
+diesel migration revert
+diesel migration revert
+diesel migration revert
+diesel migration revert
+diesel migration revert
+diesel migration revert
+diesel migration revert
+diesel migration revert
 diesel migration run
 
@@ -17,170 +17,1073 @@
 //! Spawning of all tasks happens in this module
 //! Nowhere else is anything ever spawned
 
+use codec::Decode;
 use futures::{
-    channel::mpsc::{self, UnboundedReceiver, UnboundedSender},
-    future, FutureExt, StreamExt, TryFutureExt,
+    channel::mpsc::{self, Receiver, Sender},
+    future::{self, AbortHandle, AbortRegistration, Abortable},
+    stream, FutureExt, SinkExt, StreamExt, TryFutureExt,
 };
 use log::*;
 use runtime_primitives::traits::Header;
-use substrate_primitives::U256;
-use substrate_rpc_primitives::number::NumberOrHex;
-use tokio::runtime::Runtime;
+use substrate_primitives::storage::StorageKey;
+use tokio::{
+    runtime::Runtime,
+    signal::{
+        self,
+        unix::{signal as unix_signal, SignalKind},
+    },
+    task, time,
+};
 
-use std::{fmt::Debug, marker::PhantomData, sync::Arc};
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    marker::PhantomData,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
 use crate::{
-    database::Database,
+    config::ArchiveConfig,
+    database::{models::ArchivedStorage, Database, DecodedBlocks},
     error::Error as ArchiveError,
-    rpc::Rpc,
-    types::{BatchBlock, Data, System},
+    metrics,
+    progress::{Phase, Progress},
+    rpc::{Recorder, ReplayNode, RequestOptions, Rpc},
+    types::{
+        BatchBlock, BatchChildStorage, BatchEvent, BatchStorage, Data, RuntimeVersionRange, System,
+    },
+    verify,
 };
 
+/// how long to wait before checking for new blocks once sync has caught up
+const SYNC_INTERVAL: Duration = Duration::from_secs(6);
+/// longest wait before syncing again after a round of sync failed
+/// the wait starts at `SYNC_INTERVAL` and doubles each time sync fails again
+const MAX_SYNC_BACKOFF: Duration = Duration::from_secs(300);
+/// how often a waiting sync checks whether it was told to stop
+const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
 // with the hopeful and long-anticipated release of async-await
 pub struct Archive<T: System> {
     rpc: Arc<Rpc<T>>,
     db: Arc<Database>,
     runtime: Runtime,
+    config: ArchiveConfig,
+    progress: Progress,
+    handle: ShutdownHandle,
+    registration: AbortRegistration,
+}
+
+/// Stops a running Archive
+/// The head subscription is stopped and everything already received is committed to the
+/// database. Sync stops between phases, once the blocks it is fetching are committed and
+/// checkpointed, and then `Archive::run` returns
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+    subscription: AbortHandle,
+    /// checked by sync between phases and between batches
+    stop_sync: Arc<AtomicBool>,
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        log::info!("Shutting down, finishing queued inserts");
+        self.subscription.abort();
+        self.stop_sync.store(true, Ordering::SeqCst);
+    }
 }
 
 impl<T> Archive<T>
 where
     T: System,
 {
+    /// Archive a node running on localhost, into the database at `DATABASE_URL`
     pub fn new() -> Result<Self, ArchiveError> {
+        Self::with_config(ArchiveConfig::default())
+    }
+
+    /// Errors before connecting to anything if the config is invalid
+    pub fn with_config(config: ArchiveConfig) -> Result<Self, ArchiveError> {
+        config.validate()?;
         let mut runtime = Runtime::new()?;
-        let rpc = runtime.block_on(Rpc::<T>::new(url::Url::parse("ws://127.0.0.1:9944")?))?;
-        let db = Database::new()?;
+        let recorder = match config.record_path {
+            Some(ref path) => Some(Arc::new(Recorder::open(path)?)),
+            None => None,
+        };
+        // a replayed run talks to a local server serving the recording
+        let urls = match config.replay_path {
+            Some(ref path) => {
+                let node = ReplayNode::start(path)?;
+                log::info!("Replaying {} from {}", path.display(), node.url());
+                vec![node.url().clone()]
+            }
+            None => config.rpc_urls(),
+        };
+        let rpc = runtime.block_on(Rpc::<T>::new(
+            urls,
+            RequestOptions {
+                max_attempts: config.rpc_max_attempts,
+                batch_size: config.json_rpc_batch_size,
+                batch_connections: config.rpc_concurrency,
+                recorder,
+            },
+        ))?;
+        let db = Database::new(&config, rpc.chain_info().clone())?;
+        runtime.block_on(db.check_chain_info())?;
+        // checkpoints saved by an archive of another range are not loaded
+        let progress = Progress::new(runtime.block_on(db.sync_state())?);
         let (rpc, db) = (Arc::new(rpc), Arc::new(db));
         log::debug!("METADATA: {}", rpc.metadata());
         log::debug!("KEYS: {:?}", rpc.keys());
-        // log::debug!("PROPERTIES: {:?}", rpc.properties());
-        Ok(Self { rpc, db, runtime })
+        log::info!("Archiving {}", rpc.chain_info());
+        let (subscription, registration) = AbortHandle::new_pair();
+        let handle = ShutdownHandle {
+            subscription,
+            stop_sync: Arc::new(AtomicBool::new(false)),
+        };
+        Ok(Self {
+            rpc,
+            db,
+            runtime,
+            config,
+            progress,
+            handle,
+            registration,
+        })
+    }
+
+    /// Get a handle that can stop the archive from another thread
+    pub fn handle(&self) -> ShutdownHandle {
+        self.handle.clone()
     }
 
+    /// Progress of the historical sync, updated while the archive is running
+    /// Starts out at the checkpoints saved by a previous run
+    pub fn progress(&self) -> Progress {
+        self.progress.clone()
+    }
+
+    /// Run the archive until it is told to shut down
+    /// Connection drops are survived by reconnecting to the node
+    /// If the archive does not follow the chain, also returns once every block in range
+    /// is archived
+    /// SIGINT and SIGTERM trigger a shutdown
     pub fn run(mut self) -> Result<(), ArchiveError> {
-        let (sender, receiver) = mpsc::unbounded();
-        let data_in = Self::handle_data(receiver, self.db.clone());
-        let blocks = Self::blocks(self.rpc.clone(), sender.clone());
+        let (sender, receiver) = mpsc::channel(self.config.channel_capacity);
+        let data_in = Self::handle_data(receiver, self.db.clone(), self.config.insert_workers);
+        // the subscription owns the only sender, so the receiver finishes once it is aborted
+        let blocks = Self::blocks(self.rpc.clone(), sender, self.config.clone());
+        let blocks = Abortable::new(blocks, self.registration);
         // .map_err(|e| log::error!("{:?}", e));
-        let sync = Self::sync(self.rpc.clone(), self.db.clone()).map_err(|e| error!("{:?}", e));
+        let sync = Self::sync(
+            self.rpc.clone(),
+            self.db.clone(),
+            self.config.clone(),
+            self.progress.clone(),
+            self.handle.stop_sync.clone(),
+        )
+        .map_err(|e| error!("{:?}", e));
+        self.runtime
+            .spawn(Self::signals(self.handle.clone()).map_err(|e| error!("{:?}", e)));
+        if let Some(addr) = self.config.metrics_addr {
+            self.runtime
+                .spawn(metrics::serve(addr).map_err(|e| error!("{:?}", e)));
+        }
         let handle = self.runtime.spawn(sync);
         self.runtime.block_on(future::join(data_in, blocks));
-        self.runtime.block_on(handle);
+        // sync finishes what it is committing before it stops
+        if let Err(e) = self.runtime.block_on(handle) {
+            error!("{:?}", e);
+        }
         log::info!("All Done");
         Ok(())
     }
 
-    async fn blocks(rpc: Arc<Rpc<T>>, sender: UnboundedSender<Data<T>>) {
-        match rpc.subscribe_blocks(sender).await {
-            Ok(_) => (),
-            Err(e) => error!("{:?}", e),
+    /// shutdown on SIGINT or SIGTERM
+    async fn signals(handle: ShutdownHandle) -> Result<(), ArchiveError> {
+        let mut terminate = unix_signal(SignalKind::terminate())?;
+        future::select(signal::ctrl_c().boxed(), terminate.next()).await;
+        handle.shutdown();
+        Ok(())
+    }
+
+    async fn blocks(rpc: Arc<Rpc<T>>, sender: Sender<Data<T>>, config: ArchiveConfig) {
+        if !config.follows_chain() {
+            // new heads are outside of the range, sync takes care of everything
+            return;
+        }
+        // keep the metadata up to date with runtime upgrades
+        let runtime = rpc.clone().subscribe_runtime_versions().map(|res| {
+            if let Err(e) = res {
+                error!("{:?}", e);
+            }
+        });
+        let events = rpc.clone().subscribe_events(sender.clone()).map(|res| {
+            if let Err(e) = res {
+                error!("{:?}", e);
+            }
+        });
+        let pending = {
+            let (rpc, sender, interval) = (rpc.clone(), sender.clone(), config.pending_interval);
+            async move {
+                if let Some(interval) = interval {
+                    if let Err(e) = rpc.poll_pending_extrinsics(sender, interval).await {
+                        error!("{:?}", e);
+                    }
+                }
+            }
         };
+        let finalized = rpc.clone().subscribe_blocks(sender.clone());
+        let heads = async move {
+            if config.follow_best {
+                let best = rpc.subscribe_best_blocks(sender);
+                let (finalized, best) = future::join(finalized, best).await;
+                if let Err(e) = best {
+                    error!("{:?}", e);
+                }
+                if let Err(e) = finalized {
+                    error!("{:?}", e);
+                }
+            } else {
+                drop(sender);
+                if let Err(e) = finalized.await {
+                    error!("{:?}", e);
+                }
+            }
+        };
+        future::join4(runtime, events, pending, heads).await;
     }
 
-    /// Verification task that ensures all blocks are in the database
-    async fn sync(rpc: Arc<Rpc<T>>, db: Arc<Database>) -> Result<(), ArchiveError> {
-        'sync: loop {
+    /// Verification task that ensures all blocks and their state are in the database
+    /// Once caught up, keeps checking for newly finalized blocks,
+    /// unless configured to stop after the range
+    /// A round that fails is logged and tried again after a backoff, so sync keeps going
+    /// through errors such as the node or the database being down for a while
+    /// Returns once `stop` is set, after committing what it was working on
+    async fn sync(
+        rpc: Arc<Rpc<T>>,
+        db: Arc<Database>,
+        config: ArchiveConfig,
+        progress: Progress,
+        stop: Arc<AtomicBool>,
+    ) -> Result<(), ArchiveError> {
+        let mut sync = Sync::new(&config);
+        let mut backoff = SYNC_INTERVAL;
+        while !stop.load(Ordering::SeqCst) {
             let (db, rpc) = (db.clone(), rpc.clone());
-            let (sync, done) = Sync::default().sync(db.clone(), rpc.clone()).await?;
-            if done {
-                break 'sync;
+            match sync.sync(db, rpc, &progress, &stop).await {
+                Ok(done) => {
+                    backoff = SYNC_INTERVAL;
+                    if done && config.stop_after_range {
+                        log::info!("Archived every block in range");
+                        return Ok(());
+                    } else if done {
+                        Self::wait(&stop, SYNC_INTERVAL).await;
+                    }
+                }
+                Err(e) => {
+                    error!("Sync failed, retrying in {:?}: {:?}", backoff, e);
+                    Self::wait(&stop, backoff).await;
+                    backoff = std::cmp::min(backoff * 2, MAX_SYNC_BACKOFF);
+                }
             }
         }
+        log::info!("Sync stopped");
         Ok(())
     }
 
-    async fn handle_data(mut receiver: UnboundedReceiver<Data<T>>, db: Arc<Database>) {
-        while let Some(data) = receiver.next().await {
-            match data {
-                Data::SyncProgress(missing_blocks) => {
-                    println!("{} blocks missing", missing_blocks);
-                }
-                c => {
-                    let db = db.clone();
-                    let fut = async move || db.insert(c).map_err(|e| log::error!("{:?}", e)).await;
-                    tokio::spawn(fut());
-                }
-            }
+    /// Sleep for `duration`, or until `stop` is set
+    async fn wait(stop: &AtomicBool, duration: Duration) {
+        let until = Instant::now() + duration;
+        while !stop.load(Ordering::SeqCst) && Instant::now() < until {
+            time::delay_for(STOP_CHECK_INTERVAL).await;
         }
     }
+
+    /// Insert everything received into the database, with at most `workers` inserts running at once
+    /// while every worker is busy nothing is taken off the channel, so the RPC side waits
+    /// returns once the sender is gone and every insert has finished
+    async fn handle_data(receiver: Receiver<Data<T>>, db: Arc<Database>, workers: usize) {
+        receiver
+            .for_each_concurrent(workers, move |data| {
+                let db = db.clone();
+                metrics::channel_pop();
+                async move {
+                    if let Err(e) = db.insert(data).await {
+                        log::error!("{:?}", e);
+                    }
+                }
+            })
+            .await;
+    }
 }
 
 #[derive(Debug, PartialEq, Eq)]
 struct Sync<T: System + Debug> {
     looped: usize,
+    /// blocks requested from the rpc at once
+    batch_size: usize,
+    /// block requests in flight at once
+    rpc_concurrency: usize,
+    /// batches of blocks buffered between each stage of the block pipeline
+    pipeline_buffer: usize,
+    /// storage values requested from the rpc at once
+    storage_batch_size: usize,
+    /// check archived storage against read proofs
+    verify_storage: bool,
+    from_block: u64,
+    to_block: Option<u64>,
     _marker: PhantomData<T>,
 }
 
-impl<T> Default for Sync<T>
+impl<T> Sync<T>
 where
     T: System + Debug,
 {
-    fn default() -> Self {
+    fn new(config: &ArchiveConfig) -> Self {
         Self {
             looped: 0,
+            batch_size: config.rpc_batch_size,
+            rpc_concurrency: config.rpc_concurrency,
+            pipeline_buffer: config.pipeline_buffer,
+            storage_batch_size: config.storage_batch_size,
+            verify_storage: config.verify_storage,
+            from_block: config.from_block,
+            to_block: config.to_block,
             _marker: PhantomData,
         }
     }
-}
 
-impl<T> Sync<T>
-where
-    T: System + Debug,
-{
-    async fn sync(self, db: Arc<Database>, rpc: Arc<Rpc<T>>) -> Result<(Self, bool), ArchiveError>
-where {
-        let blocks_done = Self::blocks(db.clone(), rpc.clone()).await?;
-        let state_done = Self::state(db.clone(), rpc.clone()).await?;
-
-        let looped = self.looped + 1;
-        log::info!("Looped: {}", looped);
-        let done = blocks_done && state_done;
-        Ok((
-            Self {
-                looped,
-                _marker: PhantomData,
-            },
-            done,
-        ))
+    /// Run each phase once, in order
+    /// Once `stop` is set, the phases left are skipped and the round does not count as done
+    async fn sync(
+        &mut self,
+        db: Arc<Database>,
+        rpc: Arc<Rpc<T>>,
+        progress: &Progress,
+        stop: &AtomicBool,
+    ) -> Result<bool, ArchiveError> {
+        let stopped = || stop.load(Ordering::SeqCst);
+        let blocks_done = self.blocks(db.clone(), rpc.clone(), progress, stop).await?;
+        if stopped() {
+            return Ok(false);
+        }
+        let state_done = self.state(db.clone(), rpc.clone(), progress).await?;
+        if stopped() {
+            return Ok(false);
+        }
+        let events_done = self.events(db.clone(), rpc.clone(), progress).await?;
+        if stopped() {
+            return Ok(false);
+        }
+        let child_done = self
+            .child_storage(db.clone(), rpc.clone(), progress)
+            .await?;
+        if stopped() {
+            return Ok(false);
+        }
+        let verify_done = self.verify(db.clone(), rpc.clone()).await?;
+
+        self.looped += 1;
+        log::info!("Looped: {}", self.looped);
+        Ok(blocks_done && state_done && events_done && child_done && verify_done)
     }
 
     /// Crawl all state
-    async fn state(db: Arc<Database>, rpc: Arc<Rpc<T>>) -> Result<bool, ArchiveError> {
-        Ok(true)
-    }
-
-    async fn blocks(db: Arc<Database>, rpc: Arc<Rpc<T>>) -> Result<bool, ArchiveError> {
-        let latest = rpc.clone().latest_block().await?;
-        log::debug!("Latest Block: {:?}", latest);
-        let latest = *latest
-            .expect("should always be a latest; qed")
-            .block
-            .header
-            .number();
-
-        let blocks = db.query_missing_blocks(Some(latest.into())).await?;
-        let mut futures = Vec::new();
-        log::info!("Fetching {} blocks from rpc", blocks.len());
-        let rpc0 = rpc.clone();
-        for chunk in blocks.chunks(100_000) {
-            let b = chunk
+    /// Fetches up to `batch_size` storage values that are not yet archived, lowest blocks first.
+    /// Values already in the database are skipped, so the crawl picks up where it left off
+    /// The entries of storage maps are crawled after the plain values of the same blocks
+    /// Returns true once every known storage value of every archived block has been crawled
+    /// Blocks below the storage checkpoint are not checked again
+    /// Each runtime has its own storage entries, so blocks are crawled one runtime at a time,
+    /// with the metadata of the runtime they were executed with. Blocks whose runtime is not
+    /// known yet are not crawled, and the checkpoint stays below them
+    async fn state(
+        &self,
+        db: Arc<Database>,
+        rpc: Arc<Rpc<T>>,
+        progress: &Progress,
+    ) -> Result<bool, ArchiveError> {
+        // nothing can be crawled before the first blocks are archived
+        let blocks = match progress.saved(Phase::Blocks) {
+            Some(blocks) => blocks,
+            None => return Ok(false),
+        };
+        let (from, to) = (self.resume(progress, Phase::Storage), blocks.completed_to);
+        if from > to {
+            return Ok(true);
+        }
+        let versions = self.runtime_versions(&db, &rpc, from, to).await?;
+        let covered_to = match versions.last() {
+            Some(version) => std::cmp::min(version.to_block(), to),
+            None => return Ok(false),
+        };
+        let mut found = None;
+        for version in versions.iter() {
+            let metadata = rpc
+                .metadata_of(version.spec_version(), *version.from_hash())
+                .await?;
+            let missing = db
+                .query_missing_storage(
+                    metadata.keys(),
+                    std::cmp::max(from, version.from_block()),
+                    Some(std::cmp::min(to, version.to_block())),
+                    self.storage_batch_size,
+                )
+                .await?;
+            if !missing.is_empty() {
+                found = Some((missing, metadata));
+                break;
+            }
+        }
+        // storage is never ahead of the blocks it belongs to, or of the runtimes known
+        let plain_to = match found
+            .as_ref()
+            .and_then(|(missing, _)| missing.first())
+            .map(|(num, _, _)| *num)
+        {
+            Some(first) if first <= from => None,
+            Some(first) => Some(first - 1),
+            None => Some(covered_to),
+        };
+        let plain_done = found.is_none();
+        if let Some((missing, metadata)) = found {
+            log::info!("Fetching {} storage values from rpc", missing.len());
+            let (mut keys, mut hashes) = (Vec::new(), Vec::new());
+            for (_, hash, key) in missing.into_iter() {
+                let hash: T::Hash = Decode::decode(&mut hash.as_slice())?;
+                keys.push(key);
+                hashes.push(hash);
+            }
+            let storage = rpc
+                .batch_storage(keys, hashes, &metadata)
+                .await?
+                .log_failed();
+            db.insert(Data::BatchStorage(BatchStorage::new(storage)))
+                .await?;
+        }
+        let plain_to = match plain_to {
+            Some(plain_to) => plain_to,
+            None => return Ok(false),
+        };
+        // maps are crawled behind plain values, and the checkpoint moves once both are archived
+        let completed_to = match self.maps(&db, &rpc, &versions, from, plain_to).await? {
+            Some(completed_to) => completed_to,
+            None => return Ok(false),
+        };
+        Self::checkpoint(&db, progress, Phase::Storage, completed_to, blocks.target).await?;
+        Ok(plain_done && completed_to >= to)
+    }
+
+    /// Crawl the entries of every storage map of the blocks from `from` on, up to `to`
+    /// Only blocks of the runtime that `from` was executed with are crawled at once. The keys of
+    /// each map are enumerated at every block by the prefix of the map, `rpc_concurrency` blocks
+    /// at a time, until `storage_batch_size` values are found. Values already archived are not
+    /// written again
+    /// Returns how far the crawl got, see `crawled_to`
+    async fn maps(
+        &self,
+        db: &Database,
+        rpc: &Rpc<T>,
+        versions: &[RuntimeVersionRange<T>],
+        from: u64,
+        to: u64,
+    ) -> Result<Option<u64>, ArchiveError> {
+        let version = match versions.first() {
+            Some(version) => version,
+            None => return Ok(None),
+        };
+        let to = std::cmp::min(to, version.to_block());
+        let metadata = rpc
+            .metadata_of(version.spec_version(), *version.from_hash())
+            .await?;
+        let prefixes = metadata.map_prefixes();
+        if prefixes.is_empty() {
+            return Ok(Some(to));
+        }
+
+        let (mut keys, mut hashes, mut numbers) = (Vec::new(), Vec::new(), HashMap::new());
+        let (mut last, mut failed) = (None, Vec::new());
+        let blocks = db.query_block_hashes(from, to, self.batch_size).await?;
+        // keys are enumerated for `rpc_concurrency` blocks at once
+        for chunk in blocks.chunks(self.rpc_concurrency) {
+            if keys.len() >= self.storage_batch_size {
+                break;
+            }
+            let mut chunk_hashes = Vec::new();
+            for (num, hash) in chunk.iter() {
+                let hash: T::Hash = Decode::decode(&mut hash.as_slice())?;
+                chunk_hashes.push((*num, hash));
+            }
+            let enumerated = chunk_hashes
                 .iter()
-                .map(|b| NumberOrHex::Hex(U256::from(*b)))
-                .collect::<Vec<NumberOrHex<T::BlockNumber>>>();
-            futures.push(rpc.batch_block_from_number(b));
+                .map(|(_, hash)| rpc.map_keys(prefixes.clone(), *hash));
+            let enumerated = future::join_all(enumerated).await;
+            for ((num, hash), found) in chunk_hashes.into_iter().zip(enumerated.into_iter()) {
+                last = Some(num);
+                match found {
+                    Ok(found) => {
+                        numbers.insert(hash, num);
+                        for key in found.into_iter() {
+                            keys.push(key);
+                            hashes.push(hash);
+                        }
+                    }
+                    Err(e) => failed.push((num, e)),
+                }
+            }
+        }
+        let last = match last {
+            Some(last) => last,
+            None => return Ok(None),
+        };
+        if !keys.is_empty() {
+            log::info!("Fetching {} storage map values from rpc", keys.len());
+            let storage = rpc.batch_storage(keys, hashes, &metadata).await?;
+            for ((_, hash), e) in storage.failed.into_iter() {
+                failed.push((numbers[&hash], e));
+            }
+            db.insert(Data::BatchStorage(BatchStorage::new(storage.fetched)))
+                .await?;
+        }
+        Ok(Self::crawled_to(from, last, &failed))
+    }
+
+    /// Runtime versions of the blocks `from` to `to`, as ranges without a gap from `from` on
+    /// Versions of blocks that no archived range covers are asked of the node, for up to
+    /// `batch_size` blocks of the first gap, and archived. The ranges stop short of any
+    /// block whose version is still unknown
+    async fn runtime_versions(
+        &self,
+        db: &Database,
+        rpc: &Rpc<T>,
+        from: u64,
+        to: u64,
+    ) -> Result<Vec<RuntimeVersionRange<T>>, ArchiveError> {
+        let known = db.query_runtime_versions::<T>(from, Some(to)).await?;
+        let (gap_from, gap_to) = match Self::covered(from, to, known) {
+            (covered, None) => return Ok(covered),
+            (_, Some(gap)) => gap,
+        };
+        let hashes = db
+            .query_block_hashes(gap_from, gap_to, self.batch_size)
+            .await?;
+        log::info!(
+            "Fetching runtime versions of {} blocks from rpc",
+            hashes.len()
+        );
+        let mut numbers = Vec::new();
+        for (num, hash) in hashes.into_iter() {
+            let hash: T::Hash = Decode::decode(&mut hash.as_slice())?;
+            numbers.push((num, hash));
+        }
+        for version in rpc.runtime_versions(numbers).await?.into_iter() {
+            db.insert(Data::RuntimeVersion(version)).await?;
+        }
+        let known = db.query_runtime_versions::<T>(from, Some(to)).await?;
+        Ok(Self::covered(from, to, known).0)
+    }
+
+    /// The ranges of `versions` that cover the blocks `from` to `to` without a gap from `from` on,
+    /// and the first range of blocks they leave uncovered, if any
+    fn covered(
+        from: u64,
+        to: u64,
+        versions: Vec<RuntimeVersionRange<T>>,
+    ) -> (Vec<RuntimeVersionRange<T>>, Option<(u64, u64)>) {
+        let mut covered = Vec::new();
+        let mut next = from;
+        for version in versions.into_iter() {
+            if next > to {
+                break;
+            }
+            if version.from_block() > next {
+                return (covered, Some((next, version.from_block() - 1)));
+            }
+            if version.to_block() >= next {
+                next = version.to_block() + 1;
+                covered.push(version);
+            }
+        }
+        let gap = if next <= to { Some((next, to)) } else { None };
+        (covered, gap)
+    }
+
+    /// Crawl the events of every archived block
+    /// Fetches `System.Events` of up to `batch_size` blocks from the events checkpoint onwards.
+    /// Events the live subscription already archived are left as they are, and events that
+    /// can not be decoded are archived encoded; only blocks that fail to fetch are crawled again
+    /// Returns true once events are crawled up to the blocks checkpoint
+    async fn events(
+        &self,
+        db: Arc<Database>,
+        rpc: Arc<Rpc<T>>,
+        progress: &Progress,
+    ) -> Result<bool, ArchiveError> {
+        // nothing can be crawled before the first blocks are archived
+        let blocks = match progress.saved(Phase::Blocks) {
+            Some(blocks) => blocks,
+            None => return Ok(false),
+        };
+        let from = self.resume(progress, Phase::Events);
+        let hashes = db
+            .query_block_hashes(from, blocks.completed_to, self.batch_size)
+            .await?;
+        let last = match hashes.last() {
+            Some((num, _)) => *num,
+            None => {
+                let (to, target) = (blocks.completed_to, blocks.target);
+                Self::checkpoint(&db, progress, Phase::Events, to, target).await?;
+                return Ok(true);
+            }
+        };
+        log::info!("Fetching events of {} blocks from rpc", hashes.len());
+
+        let mut numbers = Vec::new();
+        for (num, hash) in hashes.into_iter() {
+            let hash: T::Hash = Decode::decode(&mut hash.as_slice())?;
+            numbers.push((num, hash));
         }
+        let events = rpc.batch_events(numbers).await?;
+        let completed_to = Self::crawled_to(from, last, &events.failed);
+        db.insert(Data::BatchEvent(BatchEvent::new(events.log_failed())))
+            .await?;
+        let completed_to = match completed_to {
+            Some(completed_to) => completed_to,
+            None => return Ok(false),
+        };
+        Self::checkpoint(&db, progress, Phase::Events, completed_to, blocks.target).await?;
+        Ok(completed_to >= blocks.completed_to)
+    }
 
-        let mut blocks = Vec::new();
-        for chunk in future::join_all(futures).await.into_iter() {
-            blocks.extend(chunk?.into_iter());
+    /// Check archived storage against the state root of its block, if verification is on
+    /// Fetches one read proof per block for up to `storage_batch_size` unchecked values.
+    /// Values the proof shows are marked verified; values it does not show, or whose block
+    /// root the proof does not match, are marked as mismatched. Values of blocks whose proof
+    /// could not be fetched stay unchecked, and are tried again a limited number of times
+    /// Returns true once every archived value is checked, or has run out of attempts
+    async fn verify(&self, db: Arc<Database>, rpc: Arc<Rpc<T>>) -> Result<bool, ArchiveError> {
+        if !self.verify_storage {
+            return Ok(true);
+        }
+        let unverified = db.query_unverified_storage(self.storage_batch_size).await?;
+        if unverified.is_empty() {
+            return Ok(true);
         }
+        log::info!("Verifying {} storage values", unverified.len());
 
-        log::info!("inserting {} blocks", blocks.len());
-        let len = blocks.len();
-        let b = db
-            .insert(Data::BatchBlock(BatchBlock::<T>::new(blocks)))
+        let mut blocks: HashMap<T::Hash, (T::Hash, Vec<ArchivedStorage>)> = HashMap::new();
+        for value in unverified.into_iter() {
+            let hash: T::Hash = Decode::decode(&mut value.hash.as_slice())?;
+            let root: T::Hash = Decode::decode(&mut value.state_root.as_slice())?;
+            blocks
+                .entry(hash)
+                .or_insert_with(|| (root, Vec::new()))
+                .1
+                .push(value);
+        }
+        let requests = blocks
+            .iter()
+            .map(|(hash, (_, values))| {
+                let keys = values.iter().map(|v| StorageKey(v.key.clone())).collect();
+                (*hash, keys)
+            })
+            .collect();
+        let proofs = rpc.batch_read_proofs(requests).await?.log_failed();
+
+        let (mut verified, mut mismatched) = (Vec::new(), Vec::new());
+        for (hash, proof) in proofs.into_iter() {
+            let (root, values) = match blocks.remove(&hash) {
+                Some(b) => b,
+                None => continue,
+            };
+            let archived = values
+                .iter()
+                .map(|v| (v.key.clone(), v.data.clone()))
+                .collect::<Vec<_>>();
+            let checked = verify::check::<T>(root, proof, &archived).unwrap_or_else(|e| {
+                log::warn!("Block {:?} does not match its state root: {}", hash, e);
+                vec![false; archived.len()]
+            });
+            for (value, ok) in values.iter().zip(checked.into_iter()) {
+                if ok {
+                    verified.push(value.id);
+                } else {
+                    log::warn!(
+                        "Storage {:?} at {:?} does not match the proof",
+                        value.key,
+                        hash
+                    );
+                    mismatched.push(value.id);
+                }
+            }
+        }
+        // the blocks left are those whose proof could not be fetched
+        let failed = blocks
+            .values()
+            .flat_map(|(_, values)| values.iter().map(|v| v.id))
+            .collect::<Vec<i32>>();
+        metrics::storage_verified(verified.len(), mismatched.len());
+        db.mark_verified(verified, mismatched, failed).await?;
+        Ok(false)
+    }
+
+    /// Crawl the child tries of every archived block
+    /// Fetches every child trie referenced from the top-level storage of up to `batch_size`
+    /// blocks, from the child storage checkpoint onwards. A trie is only fetched again at blocks
+    /// where its root changed
+    /// Returns true once child storage is crawled up to the blocks checkpoint
+    async fn child_storage(
+        &self,
+        db: Arc<Database>,
+        rpc: Arc<Rpc<T>>,
+        progress: &Progress,
+    ) -> Result<bool, ArchiveError> {
+        let blocks = match progress.saved(Phase::Blocks) {
+            Some(blocks) => blocks,
+            None => return Ok(false),
+        };
+        let from = self.resume(progress, Phase::ChildStorage);
+        let hashes = db
+            .query_block_hashes(from, blocks.completed_to, self.batch_size)
             .await?;
-        Ok(len == 0)
+        let last = match hashes.last() {
+            Some((num, _)) => *num,
+            None => {
+                let (to, target) = (blocks.completed_to, blocks.target);
+                Self::checkpoint(&db, progress, Phase::ChildStorage, to, target).await?;
+                return Ok(true);
+            }
+        };
+        log::info!("Fetching child storage of {} blocks from rpc", hashes.len());
+
+        let mut numbers = Vec::new();
+        for (num, hash) in hashes.into_iter() {
+            let hash: T::Hash = Decode::decode(&mut hash.as_slice())?;
+            numbers.push((num, hash));
+        }
+        let storage = rpc.batch_child_storage(numbers).await?;
+        let completed_to = Self::crawled_to(from, last, &storage.failed);
+        db.insert(Data::BatchChildStorage(BatchChildStorage::new(
+            storage.log_failed(),
+        )))
+        .await?;
+        let completed_to = match completed_to {
+            Some(completed_to) => completed_to,
+            None => return Ok(false),
+        };
+        Self::checkpoint(
+            &db,
+            progress,
+            Phase::ChildStorage,
+            completed_to,
+            blocks.target,
+        )
+        .await?;
+        Ok(completed_to >= blocks.completed_to)
+    }
+
+    /// Fetch every block between the blocks checkpoint and the latest finalized block
+    /// (or the end of the range) that is not yet in the database
+    /// Blocks stream through fetch -> decode -> insert, with at most `rpc_concurrency` requests
+    /// in flight and `pipeline_buffer` batches waiting between stages. Each batch is committed
+    /// on its own, and the checkpoint moves forward after every commit
+    /// Once `stop` is set no more batches are fetched; those already fetched are still committed
+    /// Returns true once every block up to the end of the range is archived
+    async fn blocks(
+        &self,
+        db: Arc<Database>,
+        rpc: Arc<Rpc<T>>,
+        progress: &Progress,
+        stop: &AtomicBool,
+    ) -> Result<bool, ArchiveError> {
+        // blocks above the finalized head may still be reorged out, so are left to the subscription
+        let finalized: u64 = (*rpc.finalized_head().await?.number()).into();
+        log::debug!("Finalized head: {}", finalized);
+        let target = self
+            .to_block
+            .map(|to| std::cmp::min(to, finalized))
+            .unwrap_or(finalized);
+        let from = self.start(progress.phase(Phase::Blocks).completed_to);
+        let missing = db.query_missing_blocks(from, Some(target)).await?;
+        log::info!("Fetching {} blocks from rpc", missing.len());
+        let chunks = missing
+            .chunks(self.batch_size)
+            .map(|chunk| chunk.to_vec())
+            .collect::<Vec<Vec<u64>>>();
+
+        // blocks that fail to fetch or decode are skipped; they are still missing next round
+        // so is a batch whose runtime versions could not be fetched
+        let (mut fetched_tx, mut fetched_rx) = mpsc::channel(self.pipeline_buffer);
+        let fetch = async move {
+            let mut fetched = stream::iter(chunks)
+                .take_while(|_| future::ready(!stop.load(Ordering::SeqCst)))
+                .map(|numbers| {
+                    let rpc = rpc.clone();
+                    async move {
+                        let blocks = rpc.batch_block_from_number(numbers).await?.log_failed();
+                        let versions = rpc.block_runtime_versions(&blocks).await?;
+                        Ok::<_, ArchiveError>((blocks, versions))
+                    }
+                })
+                .buffered(self.rpc_concurrency);
+            while let Some(blocks) = fetched.next().await {
+                match blocks {
+                    Ok(blocks) => {
+                        if fetched_tx.send(blocks).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => error!("{:?}", e),
+                }
+            }
+        };
+
+        // decoding is CPU-bound, so it runs on the blocking pool instead of the runtime's threads
+        let (mut decoded_tx, mut decoded_rx) = mpsc::channel(self.pipeline_buffer);
+        let decode = async move {
+            while let Some((blocks, versions)) = fetched_rx.next().await {
+                let decoded = task::spawn_blocking(move || {
+                    DecodedBlocks::decode(BatchBlock::<T>::new(blocks))
+                })
+                .await
+                .map_err(ArchiveError::from)
+                .and_then(|decoded| decoded);
+                match decoded {
+                    Ok(decoded) => {
+                        let decoded = decoded
+                            .finalized_to(finalized)
+                            .with_runtime_versions(&versions);
+                        if decoded_tx.send(decoded).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => error!("{:?}", e),
+                }
+            }
+        };
+
+        // an insert error drops the receiver, which stops the stages before it
+        let db0 = db.clone();
+        let from_block = self.from_block;
+        let insert = async move {
+            while let Some(decoded) = decoded_rx.next().await {
+                log::info!("inserting {} blocks", decoded.len());
+                let max = decoded.max_block();
+                db0.insert(decoded).await?;
+                if let Some(max) = max {
+                    Self::checkpoint_blocks(&db0, progress, from_block, max, target).await?;
+                }
+            }
+            Ok::<_, ArchiveError>(())
+        };
+        let (_, _, inserted) = future::join3(fetch, decode, insert).await;
+        inserted?;
+
+        Self::checkpoint_blocks(&db, progress, self.from_block, target, target).await?;
+        // with the end of the range beyond the chain, blocks up to the end are still to come
+        let reached_end = self.to_block.map(|to| target >= to).unwrap_or(true);
+        Ok(missing.is_empty() && reached_end)
+    }
+
+    /// Move the blocks and extrinsics checkpoints as far towards `to` as the database allows
+    /// extrinsics are committed in the same transaction as their block
+    async fn checkpoint_blocks(
+        db: &Database,
+        progress: &Progress,
+        from_block: u64,
+        to: u64,
+        target: u64,
+    ) -> Result<(), ArchiveError> {
+        let from = std::cmp::max(progress.phase(Phase::Blocks).completed_to, from_block);
+        let completed_to = match db.query_contiguous(from, to).await? {
+            Some(completed_to) => completed_to,
+            None => return Ok(()),
+        };
+        for phase in [Phase::Blocks, Phase::Extrinsics].iter() {
+            Self::checkpoint(db, progress, *phase, completed_to, target).await?;
+        }
+        Ok(())
+    }
+
+    /// How far a crawl of the blocks `from` to `last` got, stopping short of the first block
+    /// that failed so it is crawled again
+    /// `None` if the first block of the crawl failed, leaving the checkpoint where it is
+    fn crawled_to(from: u64, last: u64, failed: &[(u64, ArchiveError)]) -> Option<u64> {
+        match failed.iter().map(|(num, _)| *num).min() {
+            Some(first) if first <= from => None,
+            Some(first) => Some(first - 1),
+            None => Some(last),
+        }
+    }
+
+    /// where to resume from, given the checkpoint of a phase
+    fn start(&self, checkpoint: u64) -> u64 {
+        std::cmp::max(checkpoint, self.from_block)
+    }
+
+    /// the first block not yet crawled for `phase`
+    fn resume(&self, progress: &Progress, phase: Phase) -> u64 {
+        progress
+            .saved(phase)
+            .map(|p| self.start(p.completed_to + 1))
+            .unwrap_or(self.from_block)
+    }
+
+    /// Move `phase` forward to `completed_to` and save it, so a restart resumes from there
+    async fn checkpoint(
+        db: &Database,
+        progress: &Progress,
+        phase: Phase,
+        completed_to: u64,
+        target: u64,
+    ) -> Result<(), ArchiveError> {
+        let next = progress.phase(phase).advance(completed_to, target);
+        log::debug!(
+            "{} synced to {} of {}",
+            phase,
+            next.completed_to,
+            next.target
+        );
+        progress.set(next.clone());
+        db.update_sync_state(next).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        rpc::mock::{Fixture, MockNode},
+        tests::Runtime,
+    };
+    use tokio::runtime::Runtime as TokioRuntime;
+
+    fn failed(blocks: &[u64]) -> Vec<(u64, ArchiveError)> {
+        blocks
+            .iter()
+            .map(|b| (*b, ArchiveError::DataNotFound(b.to_string())))
+            .collect()
+    }
+
+    #[test]
+    fn should_stop_crawl_short_of_the_first_failure() {
+        assert_eq!(Sync::<Runtime>::crawled_to(0, 9, &failed(&[])), Some(9));
+        assert_eq!(Sync::<Runtime>::crawled_to(0, 9, &failed(&[7, 4])), Some(3));
+        assert_eq!(Sync::<Runtime>::crawled_to(0, 9, &failed(&[0])), None);
+        assert_eq!(Sync::<Runtime>::crawled_to(5, 9, &failed(&[5, 8])), None);
+    }
+
+    fn versions(ranges: &[(u64, u64)]) -> Vec<RuntimeVersionRange<Runtime>> {
+        ranges
+            .iter()
+            .map(|(from, to)| {
+                RuntimeVersionRange::new("node".into(), 1, (*from, Default::default()), *to)
+            })
+            .collect()
+    }
+
+    fn bounds(versions: &[RuntimeVersionRange<Runtime>]) -> Vec<(u64, u64)> {
+        versions
+            .iter()
+            .map(|v| (v.from_block(), v.to_block()))
+            .collect()
+    }
+
+    #[test]
+    fn should_stop_crawl_short_of_blocks_without_a_runtime_version() {
+        let (covered, gap) = Sync::<Runtime>::covered(5, 20, versions(&[(0, 9), (10, 30)]));
+        assert_eq!(bounds(&covered), vec![(0, 9), (10, 30)]);
+        assert_eq!(gap, None);
+
+        let (covered, gap) = Sync::<Runtime>::covered(5, 20, versions(&[(0, 9), (12, 30)]));
+        assert_eq!(bounds(&covered), vec![(0, 9)]);
+        assert_eq!(gap, Some((10, 11)));
+
+        let (covered, gap) = Sync::<Runtime>::covered(5, 20, versions(&[(0, 14)]));
+        assert_eq!(bounds(&covered), vec![(0, 14)]);
+        assert_eq!(gap, Some((15, 20)));
+
+        let (covered, gap) = Sync::<Runtime>::covered(5, 20, versions(&[(8, 30)]));
+        assert!(covered.is_empty());
+        assert_eq!(gap, Some((5, 7)));
+    }
+
+    /// the mock node's chain, archived up to block 2 into the database at `DATABASE_URL`
+    fn mock_config(node: &MockNode) -> ArchiveConfig {
+        ArchiveConfig::default()
+            .rpc_url(node.url().clone())
+            .to_block(2)
+    }
+
+    // needs a migrated database at `DATABASE_URL` that is empty, or archives the mock chain
+    #[test]
+    #[ignore]
+    fn should_sync_blocks_then_events_and_save_checkpoints() {
+        let node = MockNode::start(Fixture::load("node"));
+        let config = mock_config(&node);
+        let mut rt = TokioRuntime::new().unwrap();
+        let rpc = rt
+            .block_on(Rpc::<Runtime>::new(
+                vec![node.url().clone()],
+                RequestOptions::default(),
+            ))
+            .unwrap();
+        let db = Database::new(&config, rpc.chain_info().clone()).unwrap();
+        rt.block_on(db.check_chain_info()).unwrap();
+        let progress = Progress::new(rt.block_on(db.sync_state()).unwrap());
+        let (rpc, db) = (Arc::new(rpc), Arc::new(db));
+        let mut sync = Sync::<Runtime>::new(&config);
+        let stop = AtomicBool::new(false);
+
+        let blocks = sync.blocks(db.clone(), rpc.clone(), &progress, &stop);
+        assert!(rt.block_on(blocks).unwrap());
+        assert!(rt
+            .block_on(db.query_missing_blocks(0, Some(2)))
+            .unwrap()
+            .is_empty());
+        assert!(rt
+            .block_on(sync.events(db.clone(), rpc.clone(), &progress))
+            .unwrap());
+        for phase in [Phase::Blocks, Phase::Extrinsics, Phase::Events].iter() {
+            assert_eq!(progress.phase(*phase).completed_to, 2);
+        }
+
+        // the first round crawls storage, the next finds nothing left
+        let _ = rt
+            .block_on(sync.sync(db.clone(), rpc.clone(), &progress, &stop))
+            .unwrap();
+        assert!(rt
+            .block_on(sync.sync(db.clone(), rpc.clone(), &progress, &stop))
+            .unwrap());
+        let saved = Progress::new(rt.block_on(db.sync_state()).unwrap());
+        for phase in Phase::all().iter() {
+            assert_eq!(saved.phase(*phase).completed_to, 2);
+        }
+    }
+
+    // needs a migrated database at `DATABASE_URL` that is empty, or archives the mock chain
+    #[test]
+    #[ignore]
+    fn should_return_from_run_once_the_range_is_archived() {
+        let node = MockNode::start(Fixture::load("node"));
+        let config = mock_config(&node).stop_after_range(true);
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let archive = Archive::<Runtime>::with_config(config).unwrap();
+            let progress = archive.progress();
+            let _ = tx.send(archive.run().map(|_| progress));
+        });
+        let progress = rx
+            .recv_timeout(Duration::from_secs(60))
+            .expect("Archive returns once the range is archived")
+            .unwrap();
+        for phase in Phase::all().iter() {
+            assert_eq!(progress.phase(*phase).completed_to, 2);
+        }
     }
 }
@@ -0,0 +1,179 @@
+// Copyright 2017-2019 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Identity of the chain being archived: its genesis hash, name and properties
+
+use serde_json::Value;
+use substrate_primitives::{hashing::blake2_512, hexdisplay::HexDisplay};
+use substrate_rpc_api::system::Properties;
+
+use std::convert::TryFrom;
+
+/// The chain a node is on, as reported by `system_chain` and `system_properties`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChainInfo {
+    genesis_hash: Vec<u8>,
+    chain: String,
+    properties: Properties,
+}
+
+impl ChainInfo {
+    pub fn new(genesis_hash: Vec<u8>, chain: String, properties: Properties) -> Self {
+        Self {
+            genesis_hash,
+            chain,
+            properties,
+        }
+    }
+
+    pub fn genesis_hash(&self) -> &[u8] {
+        self.genesis_hash.as_slice()
+    }
+
+    pub fn chain(&self) -> &str {
+        &self.chain
+    }
+
+    pub fn properties(&self) -> &Properties {
+        &self.properties
+    }
+
+    /// prefix addresses of this chain are encoded with in SS58, if the node sets one
+    pub fn ss58_prefix(&self) -> Option<u8> {
+        self.properties
+            .get("ss58Format")
+            .and_then(Value::as_u64)
+            .and_then(|p| u8::try_from(p).ok())
+    }
+
+    /// number of decimals in one unit of the native token, if the node sets them
+    pub fn token_decimals(&self) -> Option<u32> {
+        self.properties
+            .get("tokenDecimals")
+            .and_then(Value::as_u64)
+            .and_then(|d| u32::try_from(d).ok())
+    }
+
+    /// ticker of the native token, if the node sets one
+    pub fn token_symbol(&self) -> Option<&str> {
+        self.properties.get("tokenSymbol").and_then(Value::as_str)
+    }
+
+    /// SS58 address of a 32-byte account on this chain
+    /// `None` if the node sets no prefix, or the account is of another length
+    pub fn ss58(&self, account: &[u8]) -> Option<String> {
+        let prefix = self.ss58_prefix()?;
+        if account.len() != 32 {
+            return None;
+        }
+        let mut address = vec![prefix];
+        address.extend_from_slice(account);
+        let mut preimage = b"SS58PRE".to_vec();
+        preimage.extend_from_slice(&address);
+        address.extend_from_slice(&blake2_512(&preimage)[..2]);
+        Some(bs58::encode(address).into_string())
+    }
+
+    /// An amount of the native token in units, e.g. `1.5` for 1_500_000_000_000 on a chain
+    /// with 12 decimals
+    /// Balances are archived as raw amounts; this is for showing them
+    /// `None` if the node sets no decimals
+    pub fn units(&self, amount: u128) -> Option<String> {
+        let decimals = self.token_decimals()?;
+        let one = 10u128.checked_pow(decimals)?;
+        let fraction = format!("{:0width$}", amount % one, width = decimals as usize);
+        let fraction = fraction.trim_end_matches('0');
+        if fraction.is_empty() {
+            Some((amount / one).to_string())
+        } else {
+            Some(format!("{}.{}", amount / one, fraction))
+        }
+    }
+}
+
+impl std::fmt::Display for ChainInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} (genesis 0x{})",
+            self.chain,
+            HexDisplay::from(&self.genesis_hash)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn info(properties: Value) -> ChainInfo {
+        let properties = match properties {
+            Value::Object(map) => map,
+            _ => panic!("properties are an object"),
+        };
+        ChainInfo::new(vec![0xab; 32], "Kusama".to_string(), properties)
+    }
+
+    #[test]
+    fn should_read_properties() {
+        let info = info(json!({
+            "ss58Format": 2,
+            "tokenDecimals": 12,
+            "tokenSymbol": "KSM"
+        }));
+        assert_eq!(info.ss58_prefix(), Some(2));
+        assert_eq!(info.token_decimals(), Some(12));
+        assert_eq!(info.token_symbol(), Some("KSM"));
+    }
+
+    #[test]
+    fn should_ignore_missing_or_invalid_properties() {
+        let info = info(json!({ "ss58Format": 300, "tokenDecimals": "12" }));
+        assert_eq!(info.ss58_prefix(), None);
+        assert_eq!(info.token_decimals(), None);
+        assert_eq!(info.token_symbol(), None);
+    }
+
+    #[test]
+    fn should_encode_ss58_addresses() {
+        let alice =
+            hex_to_bytes("d43593c715fdd31c61141abd04a99fd6822c8558854ccde39a5684e7a56da27d");
+        let chain = info(json!({ "ss58Format": 42 }));
+        assert_eq!(
+            chain.ss58(&alice),
+            Some("5GrwvaEF5zXb26Fz9rcQpDWS57CtERHpNehXCPcNoHGKutQY".to_string())
+        );
+        assert_eq!(chain.ss58(&alice[..20]), None);
+        assert_eq!(info(json!({})).ss58(&alice), None);
+    }
+
+    #[test]
+    fn should_format_units() {
+        let chain = info(json!({ "tokenDecimals": 12 }));
+        assert_eq!(chain.units(1_500_000_000_000), Some("1.5".to_string()));
+        assert_eq!(chain.units(2_000_000_000_000), Some("2".to_string()));
+        assert_eq!(chain.units(1), Some("0.000000000001".to_string()));
+        assert_eq!(info(json!({})).units(1), None);
+    }
+
+    fn hex_to_bytes(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+}
@@ -0,0 +1,332 @@
+// Copyright 2017-2019 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Configuration of the Archive: where to find the node and database,
+//! and how much work to do at once
+
+use url::Url;
+
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
+
+use crate::error::Error as ArchiveError;
+
+const DEFAULT_RPC_URL: &str = "ws://127.0.0.1:9944";
+
+/// Settings the Archive is started with
+/// `ArchiveConfig::default()` connects to a local node and reads `DATABASE_URL` from the environment
+#[derive(Debug, Clone)]
+pub struct ArchiveConfig {
+    /// Url of the substrate node RPC
+    /// `ws://` and `wss://` subscribe to new heads; `http://` and `https://` poll for them
+    pub rpc_url: Url,
+    /// more nodes of the same chain
+    /// subscriptions fail over to these if `rpc_url` goes down, and historical block
+    /// requests are spread over every node
+    pub extra_rpc_urls: Vec<Url>,
+    /// PostgreSQL connection string
+    /// if `None`, `DATABASE_URL` is read from the environment (or a `.env` file)
+    pub database_url: Option<String>,
+    /// maximum number of connections kept in the r2d2 pool
+    pub pool_size: u32,
+    /// number of blocks requested from the RPC in one request during sync
+    pub rpc_batch_size: usize,
+    /// times an RPC request is attempted before giving up
+    /// only timeouts and transport errors are retried
+    pub rpc_max_attempts: usize,
+    /// number of calls sent to a websocket node in one JSON-RPC batch message
+    /// HTTP nodes are sent one call per request
+    pub json_rpc_batch_size: usize,
+    /// number of block requests in flight at once during sync
+    /// each one in flight to a websocket node has a batch connection of its own,
+    /// so this is also how many connections are kept open to each node for batches
+    pub rpc_concurrency: usize,
+    /// number of fetched batches of blocks buffered between each stage of the sync pipeline
+    pub pipeline_buffer: usize,
+    /// number of blocks committed to the database in one INSERT
+    pub block_insert_chunk: usize,
+    /// number of extrinsics committed to the database in one INSERT
+    pub extrinsic_insert_chunk: usize,
+    /// number of storage values committed to the database in one INSERT
+    pub storage_insert_chunk: usize,
+    /// number of (block, key) storage values fetched from the RPC in one round of the state crawl
+    pub storage_batch_size: usize,
+    /// items buffered between the RPC and the database
+    /// once full, the RPC waits for the database to catch up
+    pub channel_capacity: usize,
+    /// number of inserts that may run against the database at once
+    /// should not be larger than `pool_size`
+    pub insert_workers: usize,
+    /// also archive blocks on the best chain before they are finalized
+    /// blocks on forks that are abandoned are marked as not canonical, and removed once
+    /// their height is finalized
+    pub follow_best: bool,
+    /// first block to archive
+    pub from_block: u64,
+    /// last block to archive, if any
+    /// when set, new heads from the node are not archived
+    pub to_block: Option<u64>,
+    /// return from `Archive::run` once every block in the range is archived,
+    /// instead of following the chain
+    pub stop_after_range: bool,
+    /// address to serve Prometheus metrics on, at `/metrics`
+    /// if `None`, metrics are still collected but not served
+    pub metrics_addr: Option<SocketAddr>,
+    /// how often to check the transaction pool for new extrinsics
+    /// if `None`, the transaction pool is not archived
+    pub pending_interval: Option<Duration>,
+    /// check every archived storage value against a read proof of the state root of its block
+    pub verify_storage: bool,
+    /// file every RPC request, response and notification is appended to
+    pub record_path: Option<PathBuf>,
+    /// recording to serve instead of a node, answering calls the way the node did
+    /// notifications are all sent on subscribing, not when they were recorded
+    /// when set, `rpc_url` and `extra_rpc_urls` are not used
+    pub replay_path: Option<PathBuf>,
+}
+
+impl Default for ArchiveConfig {
+    fn default() -> Self {
+        Self {
+            rpc_url: Url::parse(DEFAULT_RPC_URL).expect("Default url is valid; qed"),
+            extra_rpc_urls: Vec::new(),
+            database_url: None,
+            pool_size: 10,
+            rpc_batch_size: 1_000,
+            rpc_max_attempts: 5,
+            json_rpc_batch_size: 100,
+            rpc_concurrency: 4,
+            pipeline_buffer: 8,
+            block_insert_chunk: 10_000,
+            extrinsic_insert_chunk: 2_500,
+            storage_insert_chunk: 5_000,
+            storage_batch_size: 5_000,
+            channel_capacity: 256,
+            insert_workers: 4,
+            follow_best: false,
+            from_block: 0,
+            to_block: None,
+            stop_after_range: false,
+            metrics_addr: None,
+            pending_interval: None,
+            verify_storage: false,
+            record_path: None,
+            replay_path: None,
+        }
+    }
+}
+
+impl ArchiveConfig {
+    pub fn rpc_url(mut self, url: Url) -> Self {
+        self.rpc_url = url;
+        self
+    }
+
+    /// add another node of the same chain
+    pub fn extra_rpc_url(mut self, url: Url) -> Self {
+        self.extra_rpc_urls.push(url);
+        self
+    }
+
+    /// every node url, `rpc_url` first
+    pub fn rpc_urls(&self) -> Vec<Url> {
+        std::iter::once(self.rpc_url.clone())
+            .chain(self.extra_rpc_urls.iter().cloned())
+            .collect()
+    }
+
+    pub fn database_url<S: Into<String>>(mut self, url: S) -> Self {
+        self.database_url = Some(url.into());
+        self
+    }
+
+    pub fn pool_size(mut self, size: u32) -> Self {
+        self.pool_size = size;
+        self
+    }
+
+    pub fn rpc_batch_size(mut self, size: usize) -> Self {
+        self.rpc_batch_size = size;
+        self
+    }
+
+    pub fn rpc_max_attempts(mut self, attempts: usize) -> Self {
+        self.rpc_max_attempts = attempts;
+        self
+    }
+
+    pub fn json_rpc_batch_size(mut self, size: usize) -> Self {
+        self.json_rpc_batch_size = size;
+        self
+    }
+
+    pub fn rpc_concurrency(mut self, requests: usize) -> Self {
+        self.rpc_concurrency = requests;
+        self
+    }
+
+    pub fn pipeline_buffer(mut self, batches: usize) -> Self {
+        self.pipeline_buffer = batches;
+        self
+    }
+
+    pub fn block_insert_chunk(mut self, size: usize) -> Self {
+        self.block_insert_chunk = size;
+        self
+    }
+
+    pub fn extrinsic_insert_chunk(mut self, size: usize) -> Self {
+        self.extrinsic_insert_chunk = size;
+        self
+    }
+
+    pub fn storage_insert_chunk(mut self, size: usize) -> Self {
+        self.storage_insert_chunk = size;
+        self
+    }
+
+    pub fn storage_batch_size(mut self, size: usize) -> Self {
+        self.storage_batch_size = size;
+        self
+    }
+
+    pub fn channel_capacity(mut self, capacity: usize) -> Self {
+        self.channel_capacity = capacity;
+        self
+    }
+
+    pub fn insert_workers(mut self, workers: usize) -> Self {
+        self.insert_workers = workers;
+        self
+    }
+
+    pub fn follow_best(mut self, follow: bool) -> Self {
+        self.follow_best = follow;
+        self
+    }
+
+    pub fn from_block(mut self, block: u64) -> Self {
+        self.from_block = block;
+        self
+    }
+
+    pub fn to_block(mut self, block: u64) -> Self {
+        self.to_block = Some(block);
+        self
+    }
+
+    pub fn stop_after_range(mut self, stop: bool) -> Self {
+        self.stop_after_range = stop;
+        self
+    }
+
+    pub fn metrics_addr(mut self, addr: SocketAddr) -> Self {
+        self.metrics_addr = Some(addr);
+        self
+    }
+
+    /// archive the transaction pool, checking it every `interval`
+    pub fn pending_interval(mut self, interval: Duration) -> Self {
+        self.pending_interval = Some(interval);
+        self
+    }
+
+    pub fn verify_storage(mut self, verify: bool) -> Self {
+        self.verify_storage = verify;
+        self
+    }
+
+    /// record RPC traffic into `path`
+    pub fn record<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.record_path = Some(path.into());
+        self
+    }
+
+    /// archive from the recording at `path` instead of a node
+    pub fn replay<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.replay_path = Some(path.into());
+        self
+    }
+
+    /// Error if a setting would make the archive unable to work,
+    /// such as a chunk size of 0, or a range that ends before it starts
+    pub fn validate(&self) -> Result<(), ArchiveError> {
+        let sizes = [
+            ("pool_size", self.pool_size as usize),
+            ("rpc_batch_size", self.rpc_batch_size),
+            ("rpc_max_attempts", self.rpc_max_attempts),
+            ("json_rpc_batch_size", self.json_rpc_batch_size),
+            ("rpc_concurrency", self.rpc_concurrency),
+            ("pipeline_buffer", self.pipeline_buffer),
+            ("block_insert_chunk", self.block_insert_chunk),
+            ("extrinsic_insert_chunk", self.extrinsic_insert_chunk),
+            ("storage_insert_chunk", self.storage_insert_chunk),
+            ("storage_batch_size", self.storage_batch_size),
+            ("channel_capacity", self.channel_capacity),
+            ("insert_workers", self.insert_workers),
+        ];
+        for (name, size) in sizes.iter() {
+            if *size == 0 {
+                return Err(ArchiveError::InvalidConfig(format!(
+                    "{} must not be 0",
+                    name
+                )));
+            }
+        }
+        if let Some(to) = self.to_block.filter(|to| *to < self.from_block) {
+            return Err(ArchiveError::InvalidConfig(format!(
+                "to_block {} is before from_block {}",
+                to, self.from_block
+            )));
+        }
+        Ok(())
+    }
+
+    /// whether `block` is inside the range this archive is configured for
+    pub fn in_range(&self, block: u64) -> bool {
+        block >= self.from_block && self.to_block.map(|to| block <= to).unwrap_or(true)
+    }
+
+    /// whether the archive keeps archiving new heads as the chain grows
+    pub(crate) fn follows_chain(&self) -> bool {
+        self.to_block.is_none() && !self.stop_after_range
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_reject_zero_sizes() {
+        assert!(ArchiveConfig::default().validate().is_ok());
+        assert!(ArchiveConfig::default()
+            .rpc_concurrency(0)
+            .validate()
+            .is_err());
+        assert!(ArchiveConfig::default()
+            .insert_workers(0)
+            .validate()
+            .is_err());
+        assert!(ArchiveConfig::default().pool_size(0).validate().is_err());
+    }
+
+    #[test]
+    fn should_reject_ranges_that_end_before_they_start() {
+        let config = ArchiveConfig::default().from_block(10);
+        assert!(config.clone().to_block(10).validate().is_ok());
+        assert!(config.to_block(9).validate().is_err());
+    }
+}
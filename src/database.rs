@@ -19,33 +19,69 @@
 pub mod db_middleware;
 pub mod models;
 pub mod schema;
+mod state;
 
 use async_trait::async_trait;
+use chrono::Utc;
 use codec::Decode;
-use diesel::{pg::PgConnection, prelude::*, sql_types::BigInt};
+use diesel::{
+    pg::PgConnection,
+    prelude::*,
+    r2d2::ConnectionManager,
+    sql_types::{BigInt, Bool, Bytea},
+};
 use dotenv::dotenv;
 use log::*;
-use runtime_primitives::traits::Header;
+use r2d2::PooledConnection;
+use runtime_primitives::{generic::Block as BlockT, traits::Header};
+use serde_json::Value;
+use substrate_primitives::{hexdisplay::HexDisplay, storage::StorageKey};
 
-use std::{convert::TryFrom, env};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+    env,
+    time::Duration,
+};
 
 use crate::{
+    chain::ChainInfo,
+    config::ArchiveConfig,
     database::{
         db_middleware::AsyncDiesel,
-        models::{InsertBlock, InsertBlockOwned},
-        schema::{blocks, inherents, signed_extrinsics},
+        models::{
+            ArchivedStorage, ChainInfoRow, InsertBestBlockOwned, InsertBlock, InsertBlockOwned,
+            InsertChildStorage, InsertEvent, InsertInherentOwned, InsertPendingExtrinsic,
+            InsertQuarantine, InsertRuntimeVersion, InsertTransactionOwned, InsertUndecodedEvents,
+            SyncState,
+        },
+        schema::{
+            blocks, chain_info, child_storage, events, inherents, pending_extrinsics, quarantine,
+            runtime_versions, signed_extrinsics, storage, sync_state, undecoded_events,
+        },
     },
     error::Error as ArchiveError,
-    extrinsics::{DbExtrinsic, Extrinsics},
+    extrinsics::{self, DbExtrinsic, Extrinsics},
+    integrity::{self, Fault, Link, Received},
+    metrics,
+    progress::{Phase, PhaseProgress},
     queries,
-    types::{BatchBlock, BatchStorage, Block, Data, Storage, System},
+    types::{
+        BatchBlock, BatchChildStorage, BatchEvent, BestBlock, Block, ChildStorage, Data, Event,
+        PendingExtrinsics, RuntimeVersionRange, System,
+    },
 };
 
 pub type DbReturn = Result<(), ArchiveError>;
 
+/// times the read proof of a storage value is fetched before it is left unverified
+const MAX_VERIFY_ATTEMPTS: i32 = 5;
+/// phase of events archived before phases were recorded, see the `event_phase` migration
+const UNKNOWN_EVENT_PHASE: &str = "Unknown";
+
 #[async_trait]
 pub trait Insert: Sync {
-    async fn insert(self, db: AsyncDiesel<PgConnection>) -> DbReturn
+    async fn insert(self, db: &Database) -> DbReturn
     where
         Self: Sized;
 }
@@ -55,12 +91,18 @@ impl<T> Insert for Data<T>
 where
     T: System,
 {
-    async fn insert(self, db: AsyncDiesel<PgConnection>) -> DbReturn {
+    async fn insert(self, db: &Database) -> DbReturn {
         match self {
             Data::Block(block) => block.insert(db).await,
+            Data::BestBlock(block) => block.insert(db).await,
             Data::Storage(storage) => storage.insert(db).await,
             Data::BatchBlock(blocks) => blocks.insert(db).await,
             Data::BatchStorage(storage) => storage.insert(db).await,
+            Data::BatchChildStorage(storage) => storage.insert(db).await,
+            Data::RuntimeVersion(version) => version.insert(db).await,
+            Data::Event(event) => event.insert(db).await,
+            Data::BatchEvent(events) => events.insert(db).await,
+            Data::PendingExtrinsics(pending) => pending.insert(db).await,
             o => Err(ArchiveError::UnhandledDataType(format!("{:?}", o))),
         }
     }
@@ -70,23 +112,60 @@ where
 /// via `AsyncDiesel`
 pub struct Database {
     db: AsyncDiesel<PgConnection>,
+    block_chunk: usize,
+    extrinsic_chunk: usize,
+    storage_chunk: usize,
+    /// range of blocks that sync state is saved for
+    range: (u64, Option<u64>),
+    /// chain being archived, whose accounts and balances storage values are formatted for
+    chain: ChainInfo,
 }
 
 impl Database {
     /// Connect to the database
-    pub fn new() -> Result<Self, ArchiveError> {
-        dotenv().ok();
-        let database_url = env::var("DATABASE_URL")?;
-        let db = AsyncDiesel::new(&database_url)?;
-        Ok(Self { db })
+    /// falls back to `DATABASE_URL` if the config does not specify a database
+    /// errors if the config has a chunk size of 0
+    pub fn new(config: &ArchiveConfig, chain: ChainInfo) -> Result<Self, ArchiveError> {
+        config.validate()?;
+        let database_url = match &config.database_url {
+            Some(url) => url.clone(),
+            None => {
+                dotenv().ok();
+                env::var("DATABASE_URL")?
+            }
+        };
+        let builder = r2d2::Builder::default().max_size(config.pool_size);
+        let db = AsyncDiesel::new_pool(&database_url, builder)?;
+        Ok(Self {
+            db,
+            block_chunk: config.block_insert_chunk,
+            extrinsic_chunk: config.extrinsic_insert_chunk,
+            storage_chunk: config.storage_insert_chunk,
+            range: (config.from_block, config.to_block),
+            chain,
+        })
     }
 
     pub async fn insert(&self, data: impl Insert) -> Result<(), ArchiveError> {
-        data.insert(self.db.clone()).await
+        data.insert(self).await
     }
 
+    /// Run a closure against a pooled connection on the blocking threadpool
+    pub(crate) async fn run<F, R>(&self, fun: F) -> Result<R, ArchiveError>
+    where
+        F: FnOnce(PooledConnection<ConnectionManager<PgConnection>>) -> Result<R, ArchiveError>
+            + Send
+            + std::marker::Unpin
+            + 'static,
+        R: Send + 'static,
+    {
+        self.db.run(fun).await
+    }
+
+    /// Blocks from `from` up to `latest` that have not been archived
     pub async fn query_missing_blocks(
         &self,
+        from: u64,
         latest: Option<u64>,
     ) -> Result<Vec<u64>, ArchiveError> {
         #[derive(QueryableByName, PartialEq, Debug)]
@@ -98,7 +177,7 @@ impl Database {
 
         self.db
             .run(move |conn| {
-                let blocks: Vec<Blocks> = queries::missing_blocks(latest).load(&conn)?;
+                let blocks: Vec<Blocks> = queries::missing_blocks(from, latest).load(&conn)?;
                 Ok(blocks
                     .iter()
                     .map(|b| {
@@ -109,176 +188,1124 @@ impl Database {
             })
             .await
     }
+
+    /// The highest block up to `to` such that every block from `from` up to it is archived
+    /// None if block `from` itself is missing
+    pub async fn query_contiguous(&self, from: u64, to: u64) -> Result<Option<u64>, ArchiveError> {
+        #[derive(QueryableByName, PartialEq, Debug)]
+        pub struct Contiguous {
+            #[sql_type = "BigInt"]
+            block_num: i64,
+        };
+
+        self.db
+            .run(move |conn| {
+                let contiguous: Contiguous = queries::contiguous_to(from, to).get_result(&conn)?;
+                // block_num is `from - 1` if the first block is missing
+                Ok(u64::try_from(contiguous.block_num)
+                    .ok()
+                    .filter(|num| *num >= from))
+            })
+            .await
+    }
+
+    /// (block number, block hash, key) of storage values that have not been crawled yet
+    /// for blocks from `from` up to `to`, lowest blocks first
+    pub async fn query_missing_storage(
+        &self,
+        keys: Vec<StorageKey>,
+        from: u64,
+        to: Option<u64>,
+        limit: usize,
+    ) -> Result<Vec<(u64, Vec<u8>, StorageKey)>, ArchiveError> {
+        #[derive(QueryableByName, PartialEq, Debug)]
+        pub struct MissingStorage {
+            #[sql_type = "BigInt"]
+            block_num: i64,
+            #[sql_type = "Bytea"]
+            hash: Vec<u8>,
+            #[sql_type = "Bytea"]
+            key: Vec<u8>,
+        };
+
+        let keys = keys.into_iter().map(|k| k.0).collect::<Vec<Vec<u8>>>();
+        self.db
+            .run(move |conn| {
+                let missing: Vec<MissingStorage> = queries::missing_storage(
+                    keys,
+                    from as i64,
+                    to.map(|t| t as i64).unwrap_or(i64::max_value()),
+                    limit as i64,
+                )
+                .load(&conn)?;
+                Ok(missing
+                    .into_iter()
+                    .map(|m| {
+                        let num = u64::try_from(m.block_num)
+                            .expect("Block number should never be negative; qed");
+                        (num, m.hash, StorageKey(m.key))
+                    })
+                    .collect())
+            })
+            .await
+    }
+
+    /// (block number, block hash) of finalized, canonical blocks from `from` up to `to`,
+    /// at most `limit` of them, lowest first
+    pub async fn query_block_hashes(
+        &self,
+        from: u64,
+        to: u64,
+        limit: usize,
+    ) -> Result<Vec<(u64, Vec<u8>)>, ArchiveError> {
+        self.db
+            .run(move |conn| {
+                let hashes = blocks::table
+                    .select((blocks::block_num, blocks::hash))
+                    .filter(blocks::block_num.between(from as i64, to as i64))
+                    .filter(blocks::canonical)
+                    .filter(blocks::finalized)
+                    .order(blocks::block_num.asc())
+                    .limit(limit as i64)
+                    .load::<(i64, Vec<u8>)>(&conn)?;
+                Ok(hashes
+                    .into_iter()
+                    .map(|(num, hash)| {
+                        let num =
+                            u64::try_from(num).expect("Block number should never be negative; qed");
+                        (num, hash)
+                    })
+                    .collect())
+            })
+            .await
+    }
+
+    /// Runtimes that blocks from `from` up to `to` were executed with, lowest blocks first
+    pub async fn query_runtime_versions<T: System>(
+        &self,
+        from: u64,
+        to: Option<u64>,
+    ) -> Result<Vec<RuntimeVersionRange<T>>, ArchiveError> {
+        let to = to.map(|t| t as i64).unwrap_or(i64::max_value());
+        self.db
+            .run(move |conn| {
+                let versions = runtime_versions::table
+                    .select((
+                        runtime_versions::spec_name,
+                        runtime_versions::spec_version,
+                        runtime_versions::from_block,
+                        runtime_versions::to_block,
+                        runtime_versions::from_hash,
+                    ))
+                    .filter(runtime_versions::from_block.le(to))
+                    .filter(runtime_versions::to_block.ge(from as i64))
+                    .order(runtime_versions::from_block.asc())
+                    .load::<(String, i32, i64, i64, Vec<u8>)>(&conn)?;
+                versions
+                    .into_iter()
+                    .map(|(name, version, from_block, to_block, from_hash)| {
+                        let from_hash: T::Hash = Decode::decode(&mut from_hash.as_slice())?;
+                        Ok(RuntimeVersionRange::new(
+                            name,
+                            version as u32,
+                            (from_block as u64, from_hash),
+                            to_block as u64,
+                        ))
+                    })
+                    .collect::<Result<Vec<_>, ArchiveError>>()
+            })
+            .await
+    }
+
+    /// Storage values not yet checked against a read proof, lowest blocks first,
+    /// with the state root of their block
+    /// Values whose proof failed to fetch `MAX_VERIFY_ATTEMPTS` times are left out
+    pub async fn query_unverified_storage(
+        &self,
+        limit: usize,
+    ) -> Result<Vec<ArchivedStorage>, ArchiveError> {
+        self.db
+            .run(move |conn| {
+                let unverified = storage::table
+                    .inner_join(blocks::table)
+                    .select((
+                        storage::id,
+                        storage::hash,
+                        blocks::state_root,
+                        storage::key,
+                        storage::data,
+                    ))
+                    .filter(storage::verified.is_null())
+                    .filter(storage::verify_attempts.lt(MAX_VERIFY_ATTEMPTS))
+                    .order(storage::block_num.asc())
+                    .limit(limit as i64)
+                    .load(&conn)?;
+                Ok(unverified)
+            })
+            .await
+    }
+
+    /// Record the outcome of checking storage values against read proofs
+    /// `failed` are the values whose proof could not be fetched
+    pub async fn mark_verified(
+        &self,
+        verified: Vec<i32>,
+        mismatched: Vec<i32>,
+        failed: Vec<i32>,
+    ) -> DbReturn {
+        self.db
+            .run(move |conn| {
+                conn.transaction::<_, ArchiveError, _>(|| {
+                    diesel::update(storage::table.filter(storage::id.eq_any(failed)))
+                        .set(storage::verify_attempts.eq(storage::verify_attempts + 1))
+                        .execute(&conn)?;
+                    diesel::update(storage::table.filter(storage::id.eq_any(verified)))
+                        .set(storage::verified.eq(true))
+                        .execute(&conn)?;
+                    diesel::update(storage::table.filter(storage::id.eq_any(mismatched)))
+                        .set(storage::verified.eq(false))
+                        .execute(&conn)?;
+                    Ok(())
+                })
+            })
+            .await
+    }
+
+    /// Record the chain this database archives
+    /// Errors if the database already holds another chain, whether recorded in `chain_info`
+    /// or by a finalized genesis block from before the chain was recorded
+    pub async fn check_chain_info(&self) -> DbReturn {
+        let info = &self.chain;
+        let row = ChainInfoRow {
+            genesis_hash: info.genesis_hash().to_vec(),
+            chain: info.chain().to_string(),
+            properties: Value::Object(info.properties().clone()),
+            updated_at: Utc::now(),
+        };
+        let expected = info.to_string();
+        self.db
+            .run(move |conn| {
+                conn.transaction::<_, ArchiveError, _>(|| {
+                    let other: Option<ChainInfoRow> = chain_info::table
+                        .filter(chain_info::genesis_hash.ne(&row.genesis_hash))
+                        .first(&conn)
+                        .optional()?;
+                    if let Some(other) = other {
+                        return Err(ArchiveError::ChainMismatch(format!(
+                            "database has {} (genesis 0x{}), node is on {}",
+                            other.chain,
+                            HexDisplay::from(&other.genesis_hash),
+                            expected
+                        )));
+                    }
+                    let genesis: Option<Vec<u8>> = blocks::table
+                        .select(blocks::hash)
+                        .filter(blocks::block_num.eq(0))
+                        .filter(blocks::finalized.eq(true))
+                        .first(&conn)
+                        .optional()?;
+                    if let Some(genesis) = genesis.filter(|g| *g != row.genesis_hash) {
+                        return Err(ArchiveError::ChainMismatch(format!(
+                            "database has genesis 0x{}, node is on {}",
+                            HexDisplay::from(&genesis),
+                            expected
+                        )));
+                    }
+                    diesel::insert_into(chain_info::table)
+                        .values(&row)
+                        .on_conflict(chain_info::genesis_hash)
+                        .do_update()
+                        .set(&row)
+                        .execute(&conn)?;
+                    Ok(())
+                })
+            })
+            .await
+    }
+
+    /// Progress of every phase of sync over the configured range of blocks,
+    /// as of the last time it was saved
+    pub async fn sync_state(&self) -> Result<Vec<PhaseProgress>, ArchiveError> {
+        let (from, to) = self.range;
+        self.db
+            .run(move |conn| {
+                let state: Vec<SyncState> = sync_state::table
+                    .select((
+                        sync_state::phase,
+                        sync_state::completed_to,
+                        sync_state::target,
+                        sync_state::rate,
+                        sync_state::eta,
+                        sync_state::updated_at,
+                        sync_state::from_block,
+                        sync_state::to_block,
+                    ))
+                    .filter(sync_state::from_block.eq(from as i64))
+                    .filter(sync_state::to_block.is_not_distinct_from(to.map(|to| to as i64)))
+                    .load(&conn)?;
+                Ok(state
+                    .into_iter()
+                    .filter_map(|s| {
+                        let phase = Phase::from_name(&s.phase)?;
+                        Some(PhaseProgress {
+                            phase,
+                            completed_to: u64::try_from(s.completed_to).ok()?,
+                            target: u64::try_from(s.target).ok()?,
+                            rate: s.rate,
+                            eta: s.eta.map(|e| Duration::from_secs(e as u64)),
+                            updated_at: s.updated_at,
+                        })
+                    })
+                    .collect())
+            })
+            .await
+    }
+
+    /// Save the progress of a phase of sync over the configured range of blocks
+    pub async fn update_sync_state(&self, progress: PhaseProgress) -> DbReturn {
+        let state = SyncState {
+            phase: progress.phase.as_str().to_string(),
+            completed_to: progress.completed_to as i64,
+            target: progress.target as i64,
+            rate: progress.rate,
+            eta: progress.eta.map(|e| e.as_secs() as i64),
+            updated_at: progress.updated_at,
+            from_block: self.range.0 as i64,
+            to_block: self.range.1.map(|to| to as i64),
+        };
+        self.db
+            .run(move |conn| {
+                conn.transaction::<_, ArchiveError, _>(|| {
+                    // an open range has a NULL `to_block`, which ON CONFLICT can not match on
+                    let updated = diesel::update(
+                        sync_state::table
+                            .filter(sync_state::phase.eq(&state.phase))
+                            .filter(sync_state::from_block.eq(state.from_block))
+                            .filter(sync_state::to_block.is_not_distinct_from(state.to_block)),
+                    )
+                    .set(&state)
+                    .execute(&conn)?;
+                    if updated == 0 {
+                        diesel::insert_into(sync_state::table)
+                            .values(&state)
+                            .execute(&conn)?;
+                    }
+                    Ok(())
+                })
+            })
+            .await
+    }
 }
 
-// TODO Make storage insertions generic over any type of insertin
-// not only timestamps
 #[async_trait]
-impl<T> Insert for Storage<T>
+impl<T> Insert for BatchChildStorage<T>
 where
     T: System,
 {
-    async fn insert(self, db: AsyncDiesel<PgConnection>) -> DbReturn {
-        // use self::schema::blocks::dsl::{blocks, hash, time};
-        let date_time = self.get_timestamp()?;
-        let hsh = self.hash().clone();
-        db.run(move |conn| {
-            trace!("inserting timestamp for: {}", hsh);
-            let len = 1;
-            // WARN this just inserts a timestamp
-            /*
-            diesel::update(blocks.filter(hash.eq(hsh.as_ref())))
-                .set(time.eq(Some(&date_time)))
-                .execute(&conn)
-                .map_err(|e| ArchiveError::from(e))?;
-             */
-            Ok(())
-        })
-        .await
+    async fn insert(self, db: &Database) -> DbReturn {
+        debug!("Inserting {} child storage values", self.inner().len());
+        let storage = self.consume();
+        let chunk = db.storage_chunk;
+        db.run(move |conn| insert_child_storage(&conn, storage, chunk))
+            .await
     }
 }
 
 #[async_trait]
-impl<T> Insert for BatchStorage<T>
+impl<T> Insert for Event<T>
 where
     T: System,
 {
-    async fn insert(self, db: AsyncDiesel<PgConnection>) -> DbReturn {
-        use self::schema::blocks::dsl::{blocks, hash, time};
-        debug!("Inserting {} items via Batch Storage", self.inner().len());
-        let storage: Vec<Storage<T>> = self.consume();
-        db.run(move |conn| {
-            let len = storage.len();
-            for item in storage.into_iter() {
-                let date_time = item.get_timestamp()?;
-                /*
-                diesel::update(blocks.filter(hash.eq(item.hash().as_ref())))
-                    .set(time.eq(Some(&date_time)))
-                    .execute(&conn)?;
-                 */
+    async fn insert(self, db: &Database) -> DbReturn {
+        trace!("inserting events for: {}", self.hash());
+        let chunk = db.extrinsic_chunk;
+        db.run(move |conn| insert_events(&conn, vec![self], chunk))
+            .await
+    }
+}
+
+#[async_trait]
+impl<T> Insert for BatchEvent<T>
+where
+    T: System,
+{
+    async fn insert(self, db: &Database) -> DbReturn {
+        debug!("Inserting events of {} blocks", self.inner().len());
+        let events = self.consume();
+        let chunk = db.extrinsic_chunk;
+        db.run(move |conn| insert_events(&conn, events, chunk))
+            .await
+    }
+}
+
+/// insert the events of each block, skipping blocks that are not in the database
+/// events already archived for a block are left as they are, unless they were archived
+/// before their phase was recorded; those are replaced
+/// events that could not be decoded are kept encoded, in `undecoded_events`
+fn insert_events<T>(conn: &PgConnection, items: Vec<Event<T>>, chunk: usize) -> DbReturn
+where
+    T: System,
+{
+    let hashes = items
+        .iter()
+        .map(|e| e.hash().as_ref().to_vec())
+        .collect::<Vec<Vec<u8>>>();
+    let numbers: HashMap<Vec<u8>, i64> = blocks::table
+        .select((blocks::hash, blocks::block_num))
+        .filter(blocks::hash.eq_any(hashes))
+        .load::<(Vec<u8>, i64)>(conn)?
+        .into_iter()
+        .collect();
+
+    let (mut values, mut undecoded) = (Vec::new(), Vec::new());
+    for item in items.iter() {
+        let hash = item.hash().as_ref().to_vec();
+        let block_num = match numbers.get(&hash) {
+            Some(n) => *n,
+            None => {
+                debug!("Block {:?} not archived, skipping events", item.hash());
+                continue;
             }
-            info!("Done inserting storage!");
-            Ok(())
+        };
+        if let Some((data, error)) = item.raw() {
+            undecoded.push(InsertUndecodedEvents {
+                block_num,
+                hash: hash.clone(),
+                data: data.clone(),
+                error: error.clone(),
+            });
+        }
+        for e in item.events().iter() {
+            values.push(InsertEvent {
+                block_num,
+                hash: hash.clone(),
+                module: e.module.clone(),
+                event: e.event.clone(),
+                parameters: e.parameters.clone(),
+                event_index: e.event_index,
+                phase: e.phase.clone(),
+                extrinsic_index: e.extrinsic_index,
+            });
+        }
+    }
+
+    // events of a block are next to each other
+    let mut decoded = values
+        .iter()
+        .map(|e| e.hash.clone())
+        .collect::<Vec<Vec<u8>>>();
+    decoded.dedup();
+    conn.transaction::<_, ArchiveError, _>(|| {
+        diesel::delete(
+            events::table
+                .filter(events::hash.eq_any(decoded))
+                .filter(events::phase.eq(UNKNOWN_EVENT_PHASE)),
+        )
+        .execute(conn)?;
+        for chunks in values.as_slice().chunks(chunk) {
+            let _timer = metrics::time_insert("events");
+            diesel::insert_into(events::table)
+                .values(chunks)
+                .on_conflict((events::hash, events::event_index))
+                .do_nothing()
+                .execute(conn)?;
+        }
+        if !undecoded.is_empty() {
+            diesel::insert_into(undecoded_events::table)
+                .values(&undecoded)
+                .on_conflict(undecoded_events::hash)
+                .do_nothing()
+                .execute(conn)?;
+        }
+        Ok(())
+    })
+}
+
+/// insert child storage values, skipping blocks that are not in the database
+/// values already archived are left as they are
+fn insert_child_storage<T>(
+    conn: &PgConnection,
+    items: Vec<ChildStorage<T>>,
+    chunk: usize,
+) -> DbReturn
+where
+    T: System,
+{
+    let hashes = items
+        .iter()
+        .map(|s| s.hash().as_ref().to_vec())
+        .collect::<Vec<Vec<u8>>>();
+    let numbers: HashMap<Vec<u8>, i64> = blocks::table
+        .select((blocks::hash, blocks::block_num))
+        .filter(blocks::hash.eq_any(hashes))
+        .load::<(Vec<u8>, i64)>(conn)?
+        .into_iter()
+        .collect();
+
+    let values = items
+        .into_iter()
+        .filter_map(|s| {
+            let hash = s.hash().as_ref().to_vec();
+            let block_num = match numbers.get(&hash) {
+                Some(n) => *n,
+                None => {
+                    warn!("Block {:?} not archived, skipping child storage", s.hash());
+                    return None;
+                }
+            };
+            Some(InsertChildStorage {
+                block_num,
+                hash,
+                child_storage_key: s.child_storage_key().0.clone(),
+                key: s.key().0.clone(),
+                data: s.data().0.clone(),
+            })
         })
-        .await
+        .collect::<Vec<InsertChildStorage>>();
+
+    for chunks in values.as_slice().chunks(chunk) {
+        let _timer = metrics::time_insert("child_storage");
+        diesel::insert_into(child_storage::table)
+            .values(chunks)
+            .on_conflict((
+                child_storage::hash,
+                child_storage::child_storage_key,
+                child_storage::key,
+            ))
+            .do_nothing()
+            .execute(conn)?;
     }
+    Ok(())
 }
 
+/// Finalized blocks
+/// Any unfinalized block at the same height was on an abandoned fork;
+/// it is marked as not canonical and, along with its extrinsics, removed
+/// Blocks that fail an integrity check are quarantined instead
 #[async_trait]
 impl<T> Insert for Block<T>
 where
     T: System,
 {
-    async fn insert(self, db: AsyncDiesel<PgConnection>) -> DbReturn {
+    async fn insert(self, db: &Database) -> DbReturn {
+        use self::schema::blocks::dsl::{block_num, canonical, finalized, hash};
         let block = self.inner().block.clone();
-        info!("HASH: {:X?}", block.header.hash().as_ref());
+        let received =
+            Received::new::<T>(&block.header, &block.extrinsics, true, self.requested())?;
+        info!("HASH: {:X?}", received.hash());
         info!("Block Num: {:?}", block.header.number());
+        if let Err(fault) = received.check_body::<T>() {
+            let rejected = received.quarantine(&fault);
+            return db
+                .run(move |conn| quarantine_blocks(&conn, &[rejected]))
+                .await;
+        }
         let extrinsics = DbExtrinsic::decode::<T>(&block.extrinsics, &block.header)?;
+        let included = extrinsics::hashes::<T>(&block.extrinsics);
+        let number: u64 = (*block.header.number()).into();
         // TODO Optimize
-        db.run(move |conn| {
-            diesel::insert_into(blocks::table)
-                .values(InsertBlock {
-                    parent_hash: block.header.parent_hash().as_ref(),
-                    hash: block.header.hash().as_ref(),
-                    block_num: &((*block.header.number()).into() as i64),
-                    state_root: block.header.state_root().as_ref(),
-                    extrinsics_root: block.header.extrinsics_root().as_ref(),
-                    time: extrinsics.extra().time().as_ref(),
+        let inserted = db
+            .run(move |conn| {
+                conn.transaction::<_, ArchiveError, _>(|| {
+                    if let Some(fault) = link_fault(&conn, received.link())? {
+                        quarantine_blocks(&conn, &[received.quarantine(&fault)])?;
+                        return Ok(false);
+                    }
+                    let num = (*block.header.number()).into() as i64;
+                    let block_hash = received.hash();
+                    queries::abandon_siblings(num, block_hash).execute(&conn)?;
+                    let retracted = diesel::delete(
+                        blocks::table
+                            .filter(canonical.eq(false))
+                            .filter(block_num.le(num)),
+                    )
+                    .execute(&conn)?;
+                    if retracted > 0 {
+                        warn!("Removed {} blocks from abandoned forks", retracted);
+                        // extrinsics of removed blocks lost their link; relink them to the chain
+                        queries::link_archived_pending().execute(&conn)?;
+                    }
+
+                    // already archived while following the best chain
+                    let known = diesel::update(blocks::table.filter(hash.eq(block_hash)))
+                        .set((finalized.eq(true), canonical.eq(true)))
+                        .execute(&conn)?;
+                    if known > 0 {
+                        return Ok(true);
+                    }
+
+                    let timer = metrics::time_insert("blocks");
+                    diesel::insert_into(blocks::table)
+                        .values(InsertBlock {
+                            parent_hash: block.header.parent_hash().as_ref(),
+                            hash: block_hash,
+                            block_num: &num,
+                            state_root: block.header.state_root().as_ref(),
+                            extrinsics_root: block.header.extrinsics_root().as_ref(),
+                            time: extrinsics.extra().time().as_ref(),
+                        })
+                        .on_conflict(blocks::hash)
+                        .do_nothing()
+                        .execute(&conn)?;
+                    drop(timer);
+
+                    insert_extrinsics(&conn, extrinsics)?;
+                    link_pending(&conn, block_hash, included)?;
+                    Ok(true)
                 })
-                .on_conflict(blocks::hash)
-                .do_nothing()
-                .execute(&conn)?;
-
-            let (mut signed_ext, mut unsigned_ext) = (Vec::new(), Vec::new());
-            let len = extrinsics.0.len() + 1; // 1 for the block
-            for e in extrinsics.0.into_iter() {
-                match e {
-                    DbExtrinsic::Signed(e) => signed_ext.push(e),
-                    DbExtrinsic::NotSigned(e, _) => unsigned_ext.push(e),
-                }
-            }
+            })
+            .await?;
+        if inserted {
+            metrics::blocks_inserted(1, Some(number));
+        }
+        Ok(())
+    }
+}
+
+/// Blocks on the best chain, before they are finalized
+/// If the parent of the block is not the current canonical head, the chain has reorganized.
+/// Blocks on the old fork are marked as no longer canonical, and the blocks between
+/// the new block and the fork point become canonical
+#[async_trait]
+impl<T> Insert for BestBlock<T>
+where
+    T: System,
+{
+    async fn insert(self, db: &Database) -> DbReturn {
+        use self::schema::blocks::dsl::{block_num, canonical, finalized, hash};
 
-            diesel::insert_into(inherents::table)
-                .values(unsigned_ext)
-                .execute(&conn)?;
+        #[derive(QueryableByName, Debug)]
+        struct PathBlock {
+            #[sql_type = "Bytea"]
+            hash: Vec<u8>,
+            #[sql_type = "BigInt"]
+            block_num: i64,
+            #[sql_type = "Bool"]
+            canonical: bool,
+            #[sql_type = "BigInt"]
+            fork: i64,
+        }
 
-            diesel::insert_into(signed_extrinsics::table)
-                .values(signed_ext)
-                .execute(&conn)?;
+        #[derive(QueryableByName, Debug)]
+        struct Fork {
+            #[sql_type = "BigInt"]
+            fork: i64,
+        }
 
-            Ok(())
-        })
-        .await
+        let block = self.inner().block.clone();
+        debug!("Best Block: {:?}", block.header.number());
+        let received =
+            Received::new::<T>(&block.header, &block.extrinsics, false, self.requested())?;
+        if let Err(fault) = received.check_body::<T>() {
+            let rejected = received.quarantine(&fault);
+            return db
+                .run(move |conn| quarantine_blocks(&conn, &[rejected]))
+                .await;
+        }
+        let extrinsics = DbExtrinsic::decode::<T>(&block.extrinsics, &block.header)?;
+        let included = extrinsics::hashes::<T>(&block.extrinsics);
+        let number: u64 = (*block.header.number()).into();
+        let inserted = db
+            .run(move |conn| {
+                conn.transaction::<_, ArchiveError, _>(|| {
+                    let num = (*block.header.number()).into() as i64;
+                    let block_hash = received.hash().to_vec();
+                    let known: i64 = blocks::table
+                        .filter(hash.eq(&block_hash))
+                        .count()
+                        .get_result(&conn)?;
+                    if known > 0 {
+                        return Ok(false);
+                    }
+                    if let Some(fault) = link_fault(&conn, received.link())? {
+                        quarantine_blocks(&conn, &[received.quarantine(&fault)])?;
+                        return Ok(false);
+                    }
+
+                    let path: Vec<PathBlock> =
+                        queries::fork_path(block.header.parent_hash().as_ref()).load(&conn)?;
+                    // the highest block both the old and new canonical chain agree on
+                    let fork_point = match path.last() {
+                        Some(b) if b.canonical => b.block_num,
+                        Some(b) => b.block_num - 1,
+                        None => num - 1,
+                    };
+
+                    let retracted = diesel::update(
+                        blocks::table
+                            .filter(canonical.eq(true))
+                            .filter(finalized.eq(false))
+                            .filter(block_num.gt(fork_point)),
+                    )
+                    .set(canonical.eq(false))
+                    .execute(&conn)?;
+                    if retracted > 0 {
+                        warn!(
+                            "Reorg at block {}: {} blocks are no longer canonical",
+                            fork_point, retracted
+                        );
+                    }
+
+                    let restored = path
+                        .iter()
+                        .filter(|b| !b.canonical)
+                        .map(|b| b.hash.clone())
+                        .collect::<Vec<Vec<u8>>>();
+                    diesel::update(blocks::table.filter(hash.eq_any(restored)))
+                        .set(canonical.eq(true))
+                        .execute(&conn)?;
+
+                    // a block extending the canonical head stays on its parents fork
+                    let fork = match path.first() {
+                        Some(parent) if parent.canonical && retracted == 0 => parent.fork,
+                        None if retracted == 0 => 0,
+                        _ => {
+                            let fork: Fork = queries::next_fork().get_result(&conn)?;
+                            fork.fork
+                        }
+                    };
+
+                    let timer = metrics::time_insert("blocks");
+                    diesel::insert_into(blocks::table)
+                        .values(InsertBestBlockOwned {
+                            parent_hash: block.header.parent_hash().as_ref().to_vec(),
+                            hash: block_hash.clone(),
+                            block_num: num,
+                            state_root: block.header.state_root().as_ref().to_vec(),
+                            extrinsics_root: block.header.extrinsics_root().as_ref().to_vec(),
+                            time: extrinsics.extra().time(),
+                            canonical: true,
+                            finalized: false,
+                            fork,
+                        })
+                        .execute(&conn)?;
+                    drop(timer);
+
+                    insert_extrinsics(&conn, extrinsics)?;
+                    link_pending(&conn, &block_hash, included)?;
+                    Ok(true)
+                })
+            })
+            .await?;
+        if inserted {
+            metrics::blocks_inserted(1, Some(number));
+        }
+        Ok(())
     }
 }
 
 #[async_trait]
-impl<T> Insert for BatchBlock<T>
+impl<T> Insert for RuntimeVersionRange<T>
 where
     T: System,
 {
-    async fn insert(self, db: AsyncDiesel<PgConnection>) -> DbReturn {
+    async fn insert(self, db: &Database) -> DbReturn {
+        let version = runtime_version_row(&self);
+        db.run(move |conn| upsert_runtime_versions(&conn, &[version]))
+            .await
+    }
+}
+
+fn runtime_version_row<T: System>(version: &RuntimeVersionRange<T>) -> InsertRuntimeVersion {
+    InsertRuntimeVersion {
+        spec_version: version.spec_version() as i32,
+        spec_name: version.spec_name().to_string(),
+        from_block: version.from_block() as i64,
+        to_block: version.to_block() as i64,
+        from_hash: version.from_hash().as_ref().to_vec(),
+    }
+}
+
+/// record the blocks each runtime version was used for, widening the ranges already known
+fn upsert_runtime_versions(conn: &PgConnection, versions: &[InsertRuntimeVersion]) -> DbReturn {
+    let _timer = metrics::time_insert("runtime_versions");
+    for v in versions.iter() {
+        queries::upsert_runtime_version(
+            v.spec_version,
+            &v.spec_name,
+            v.from_block,
+            v.to_block,
+            &v.from_hash,
+        )
+        .execute(conn)?;
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl Insert for PendingExtrinsics {
+    /// extrinsics that are already archived keep the time they were first seen
+    /// extrinsics seen after the block that included them was archived are linked to it
+    async fn insert(self, db: &Database) -> DbReturn {
+        let pending = self.consume();
+        let chunk = db.extrinsic_chunk;
+        db.run(move |conn| {
+            for chunks in pending.as_slice().chunks(chunk) {
+                let _timer = metrics::time_insert("pending_extrinsics");
+                diesel::insert_into(pending_extrinsics::table)
+                    .values(chunks)
+                    .on_conflict(pending_extrinsics::hash)
+                    .do_nothing()
+                    .execute(&conn)?;
+            }
+            queries::link_archived_pending().execute(&conn)?;
+            Ok(())
+        })
+        .await
+    }
+}
+
+/// link extrinsics seen in the transaction pool to the block that included them
+fn link_pending(conn: &PgConnection, block_hash: &[u8], included: Vec<Vec<u8>>) -> DbReturn {
+    diesel::update(pending_extrinsics::table.filter(pending_extrinsics::hash.eq_any(included)))
+        .set(pending_extrinsics::included_in.eq(block_hash))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Blocks archived right before or after any of `links`
+fn neighbours(conn: &PgConnection, links: &[Link]) -> Result<Vec<Link>, ArchiveError> {
+    let numbers = links
+        .iter()
+        .flat_map(|l| vec![l.block_num - 1, l.block_num + 1])
+        .collect::<Vec<i64>>();
+    let rows = blocks::table
+        .filter(blocks::block_num.eq_any(numbers))
+        .select((
+            blocks::block_num,
+            blocks::hash,
+            blocks::parent_hash,
+            blocks::finalized,
+        ))
+        .load::<(i64, Vec<u8>, Vec<u8>, bool)>(conn)?;
+    Ok(rows
+        .into_iter()
+        .map(|(block_num, hash, parent_hash, finalized)| Link {
+            block_num,
+            hash,
+            parent_hash,
+            finalized,
+        })
+        .collect())
+}
+
+/// whether a single block links up with the blocks archived next to it
+fn link_fault(conn: &PgConnection, link: &Link) -> Result<Option<Fault>, ArchiveError> {
+    let links = std::slice::from_ref(link);
+    let archived = neighbours(conn, links)?;
+    Ok(integrity::check_links(links, &archived)
+        .pop()
+        .and_then(|f| f))
+}
+
+/// set aside blocks that failed an integrity check
+/// a block quarantined again for the same reason is only kept once
+fn quarantine_blocks(conn: &PgConnection, rejected: &[InsertQuarantine]) -> DbReturn {
+    if rejected.is_empty() {
+        return Ok(());
+    }
+    for block in rejected.iter() {
+        warn!(
+            "Quarantined block {} 0x{}: {}",
+            block.block_num,
+            HexDisplay::from(&block.hash),
+            block.reason
+        );
+    }
+    let _timer = metrics::time_insert("quarantine");
+    diesel::insert_into(quarantine::table)
+        .values(rejected)
+        .on_conflict_do_nothing()
+        .execute(conn)?;
+    metrics::blocks_quarantined(rejected.len());
+    Ok(())
+}
+
+/// insert the extrinsics of a single block
+fn insert_extrinsics(conn: &PgConnection, extrinsics: Extrinsics) -> DbReturn {
+    let (mut signed_ext, mut unsigned_ext) = (Vec::new(), Vec::new());
+    for e in extrinsics.0.into_iter() {
+        match e {
+            DbExtrinsic::Signed(e) => signed_ext.push(e),
+            DbExtrinsic::NotSigned(e, _) => unsigned_ext.push(e),
+        }
+    }
+
+    let timer = metrics::time_insert("inherents");
+    diesel::insert_into(inherents::table)
+        .values(unsigned_ext)
+        .execute(conn)?;
+    drop(timer);
+
+    let _timer = metrics::time_insert("signed_extrinsics");
+    diesel::insert_into(signed_extrinsics::table)
+        .values(signed_ext)
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Blocks and their extrinsics, decoded and ready to be committed
+#[derive(Debug)]
+pub struct DecodedBlocks {
+    blocks: Vec<InsertBlockOwned>,
+    signed: Vec<InsertTransactionOwned>,
+    unsigned: Vec<InsertInherentOwned>,
+    runtime_versions: Vec<InsertRuntimeVersion>,
+    /// (block hash, hashes of its extrinsics)
+    included: Vec<(Vec<u8>, Vec<Vec<u8>>)>,
+    /// blocks as received, to be checked against the blocks already archived
+    received: Vec<Received>,
+    /// blocks that failed an integrity check
+    quarantined: Vec<InsertQuarantine>,
+}
+
+impl DecodedBlocks {
+    /// Decode the extrinsics of every block
+    /// Blocks whose extrinsics do not match their header, or that are not the block they were
+    /// requested as, are quarantined, and not decoded
+    pub fn decode<T: System>(blocks: BatchBlock<T>) -> Result<Self, ArchiveError> {
         let mut extrinsics: Extrinsics = Extrinsics(Vec::new());
-        info!("Batch inserting {} blocks into DB", self.inner().len());
-        let blocks = self
+        let mut included = Vec::new();
+        let mut received = Vec::new();
+        let mut quarantined = Vec::new();
+        let blocks = blocks
             .inner()
             .iter()
-            .map(|block| {
-                let block = block.block.clone();
-                let mut block_ext: Extrinsics =
-                    DbExtrinsic::decode::<T>(&block.extrinsics, &block.header)?;
-                debug!("Block Ext: {:?}", block_ext);
-
-                let block = InsertBlockOwned {
-                    parent_hash: block.header.parent_hash().as_ref().to_vec(),
-                    hash: block.header.hash().as_ref().to_vec(),
-                    block_num: (*block.header.number()).into() as i64,
-                    state_root: block.header.state_root().as_ref().to_vec(),
-                    extrinsics_root: block.header.extrinsics_root().as_ref().to_vec(),
-                    time: block_ext.extra().time(),
+            .filter_map(|(requested, block)| {
+                let block = &block.block;
+                let checked = match Received::new::<T>(
+                    &block.header,
+                    &block.extrinsics,
+                    true,
+                    Some(requested),
+                ) {
+                    Ok(r) => r,
+                    Err(e) => return Some(Err(e)),
                 };
-                extrinsics.0.append(&mut block_ext.0);
-                Ok(block)
+                if let Err(fault) = checked.check_body::<T>() {
+                    quarantined.push(checked.quarantine(&fault));
+                    return None;
+                }
+                let block_hash = checked.hash().to_vec();
+                received.push(checked);
+                Some(Self::decode_block::<T>(
+                    block,
+                    block_hash,
+                    &mut extrinsics,
+                    &mut included,
+                ))
             })
-            // .filter_map(|b: Result<_, ArchiveError>| b.ok())
             .collect::<Result<Vec<InsertBlockOwned>, ArchiveError>>()?;
 
-        let (mut signed_ext, mut unsigned_ext) = (Vec::new(), Vec::new());
-
+        let (mut signed, mut unsigned) = (Vec::new(), Vec::new());
         for e in extrinsics.0.into_iter() {
             match e {
-                DbExtrinsic::Signed(v) => signed_ext.push(v),
-                DbExtrinsic::NotSigned(v, _) => unsigned_ext.push(v),
+                DbExtrinsic::Signed(v) => signed.push(v),
+                DbExtrinsic::NotSigned(v, _) => unsigned.push(v),
             }
         }
-
-        // batch insert everything we've formatted/collected into the database 10,000 items at a time
-        db.run(move |conn| {
-            let len = blocks.len() + unsigned_ext.len() + signed_ext.len();
-            for chunks in blocks.as_slice().chunks(10_000) {
-                info!("{} blocks to insert", chunks.len());
-                diesel::insert_into(blocks::table)
-                    .values(chunks)
-                    .on_conflict(blocks::hash)
-                    .do_nothing()
-                    .execute(&conn)?;
-            }
-            for chunks in unsigned_ext.as_slice().chunks(2_500) {
-                info!("{} unsigned extrinsics to insert", chunks.len());
-                diesel::insert_into(inherents::table)
-                    .values(chunks)
-                    .execute(&conn)?;
-            }
-            for chunks in signed_ext.as_slice().chunks(2_500) {
-                info!("{} signed extrinsics to insert", chunks.len());
-                diesel::insert_into(signed_extrinsics::table)
-                    .values(chunks)
-                    .execute(&conn)?;
-            }
-            info!("Done {} Inserting Blocks and Extrinsics", len);
-            Ok(())
+        Ok(Self {
+            blocks,
+            signed,
+            unsigned,
+            runtime_versions: Vec::new(),
+            included,
+            received,
+            quarantined,
         })
-        .await
+    }
+
+    fn decode_block<T: System>(
+        block: &BlockT<T::Header, T::Extrinsic>,
+        block_hash: Vec<u8>,
+        extrinsics: &mut Extrinsics,
+        included: &mut Vec<(Vec<u8>, Vec<Vec<u8>>)>,
+    ) -> Result<InsertBlockOwned, ArchiveError> {
+        let mut block_ext: Extrinsics = DbExtrinsic::decode::<T>(&block.extrinsics, &block.header)?;
+        debug!("Block Ext: {:?}", block_ext);
+        included.push((
+            block_hash.clone(),
+            extrinsics::hashes::<T>(&block.extrinsics),
+        ));
+
+        let block = InsertBlockOwned {
+            parent_hash: block.header.parent_hash().as_ref().to_vec(),
+            hash: block_hash,
+            block_num: (*block.header.number()).into() as i64,
+            state_root: block.header.state_root().as_ref().to_vec(),
+            extrinsics_root: block.header.extrinsics_root().as_ref().to_vec(),
+            time: block_ext.extra().time(),
+        };
+        extrinsics.0.append(&mut block_ext.0);
+        Ok(block)
+    }
+
+    /// Keep only the blocks at or below the finalized block `head`, which are inserted as final
+    /// blocks above it, with their extrinsics, are left for when they are finalized
+    pub fn finalized_to(mut self, head: u64) -> Self {
+        let above = self
+            .blocks
+            .iter()
+            .filter(|b| b.block_num as u64 > head)
+            .map(|b| b.hash.clone())
+            .collect::<HashSet<Vec<u8>>>();
+        if above.is_empty() {
+            return self;
+        }
+        warn!("Skipping {} blocks above the finalized head", above.len());
+        self.blocks.retain(|b| !above.contains(&b.hash));
+        self.signed.retain(|e| !above.contains(&e.hash));
+        self.unsigned.retain(|e| !above.contains(&e.hash));
+        self.included.retain(|(hash, _)| !above.contains(hash));
+        self.received.retain(|r| !above.contains(r.hash()));
+        self
+    }
+
+    /// also record which runtime these blocks were executed with,
+    /// in the same transaction as the blocks
+    pub fn with_runtime_versions<T: System>(mut self, versions: &[RuntimeVersionRange<T>]) -> Self {
+        self.runtime_versions = versions.iter().map(runtime_version_row).collect();
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// highest block number in this batch
+    pub fn max_block(&self) -> Option<u64> {
+        self.blocks.iter().map(|b| b.block_num as u64).max()
+    }
+}
+
+#[async_trait]
+impl<T> Insert for BatchBlock<T>
+where
+    T: System,
+{
+    async fn insert(self, db: &Database) -> DbReturn {
+        info!("Batch inserting {} blocks into DB", self.inner().len());
+        DecodedBlocks::decode(self)?.insert(db).await
+    }
+}
+
+#[async_trait]
+impl Insert for DecodedBlocks {
+    /// batch insert everything in chunks
+    /// all chunks are committed in one transaction, so a block is never archived without its extrinsics
+    /// blocks that do not link up with the archived blocks next to them are quarantined instead,
+    /// along with their extrinsics
+    /// unfinalized blocks at the same heights stop being canonical, and blocks on abandoned
+    /// forks below the highest block are removed
+    async fn insert(self, db: &Database) -> DbReturn {
+        let (block_chunk, extrinsic_chunk) = (db.block_chunk, db.extrinsic_chunk);
+        let DecodedBlocks {
+            mut blocks,
+            mut signed,
+            mut unsigned,
+            runtime_versions,
+            mut included,
+            received,
+            mut quarantined,
+        } = self;
+        let (inserted, highest) = db
+            .run(move |conn| {
+                conn.transaction::<_, ArchiveError, _>(|| {
+                    let links = received
+                        .iter()
+                        .map(|r| r.link().clone())
+                        .collect::<Vec<Link>>();
+                    let archived = neighbours(&conn, &links)?;
+                    let faults = integrity::check_links(&links, &archived);
+                    let mut rejected = HashSet::new();
+                    for (block, fault) in received.into_iter().zip(faults.into_iter()) {
+                        if let Some(fault) = fault {
+                            rejected.insert(block.hash().to_vec());
+                            quarantined.push(block.quarantine(&fault));
+                        }
+                    }
+                    quarantine_blocks(&conn, &quarantined)?;
+                    if !rejected.is_empty() {
+                        blocks.retain(|b| !rejected.contains(&b.hash));
+                        signed.retain(|e| !rejected.contains(&e.hash));
+                        unsigned.retain(|e| !rejected.contains(&e.hash));
+                        included.retain(|(hash, _)| !rejected.contains(hash));
+                    }
+                    let highest = blocks.iter().map(|b| b.block_num as u64).max();
+
+                    // unfinalized blocks at the same heights lose their place on the canonical
+                    // chain, as they do when a single finalized block is inserted
+                    for b in blocks.iter() {
+                        queries::abandon_siblings(b.block_num, &b.hash).execute(&conn)?;
+                    }
+                    if let Some(highest) = highest {
+                        let retracted = diesel::delete(
+                            blocks::table
+                                .filter(blocks::canonical.eq(false))
+                                .filter(blocks::block_num.le(highest as i64)),
+                        )
+                        .execute(&conn)?;
+                        if retracted > 0 {
+                            warn!("Removed {} blocks from abandoned forks", retracted);
+                            queries::link_archived_pending().execute(&conn)?;
+                        }
+                    }
+                    // already archived, with their extrinsics, while following the best chain
+                    let hashes = blocks.iter().map(|b| b.hash.clone()).collect::<Vec<_>>();
+                    let known: HashSet<Vec<u8>> =
+                        diesel::update(blocks::table.filter(blocks::hash.eq_any(hashes)))
+                            .set((blocks::finalized.eq(true), blocks::canonical.eq(true)))
+                            .returning(blocks::hash)
+                            .get_results::<Vec<u8>>(&conn)?
+                            .into_iter()
+                            .collect();
+                    if !known.is_empty() {
+                        blocks.retain(|b| !known.contains(&b.hash));
+                        signed.retain(|e| !known.contains(&e.hash));
+                        unsigned.retain(|e| !known.contains(&e.hash));
+                        included.retain(|(hash, _)| !known.contains(hash));
+                    }
+
+                    let len = blocks.len() + unsigned.len() + signed.len();
+                    let mut inserted = 0;
+                    for chunks in blocks.as_slice().chunks(block_chunk) {
+                        info!("{} blocks to insert", chunks.len());
+                        let _timer = metrics::time_insert("blocks");
+                        inserted += diesel::insert_into(blocks::table)
+                            .values(chunks)
+                            .on_conflict(blocks::hash)
+                            .do_nothing()
+                            .execute(&conn)?;
+                    }
+                    for chunks in unsigned.as_slice().chunks(extrinsic_chunk) {
+                        info!("{} unsigned extrinsics to insert", chunks.len());
+                        let _timer = metrics::time_insert("inherents");
+                        diesel::insert_into(inherents::table)
+                            .values(chunks)
+                            .execute(&conn)?;
+                    }
+                    for chunks in signed.as_slice().chunks(extrinsic_chunk) {
+                        info!("{} signed extrinsics to insert", chunks.len());
+                        let _timer = metrics::time_insert("signed_extrinsics");
+                        diesel::insert_into(signed_extrinsics::table)
+                            .values(chunks)
+                            .execute(&conn)?;
+                    }
+                    upsert_runtime_versions(&conn, &runtime_versions)?;
+                    for (block_hash, extrinsics) in included.into_iter() {
+                        link_pending(&conn, &block_hash, extrinsics)?;
+                    }
+                    info!("Done {} Inserting Blocks and Extrinsics", len);
+                    Ok((inserted, highest))
+                })
+            })
+            .await?;
+        metrics::blocks_inserted(inserted, highest);
+        Ok(())
     }
 }
 
@@ -25,7 +25,10 @@ use diesel::deserialize::{self, FromSql};
 use diesel::sql_types::Binary;
 use diesel::{AsChangeset, Queryable};
 
-use super::schema::{accounts, blocks, inherents, signed_extrinsics, storage};
+use super::schema::{
+    accounts, blocks, chain_info, child_storage, events, inherents, pending_extrinsics, quarantine,
+    runtime_versions, signed_extrinsics, storage, sync_state, undecoded_events,
+};
 
 // TODO: Make generic
 
@@ -56,6 +59,22 @@ pub struct InsertBlockOwned {
     pub time: Option<DateTime<Utc>>,
 }
 
+/// A block that is not finalized yet
+/// If the fork it is on is abandoned, the block is marked as not canonical and later removed
+#[derive(Insertable)]
+#[table_name = "blocks"]
+pub struct InsertBestBlockOwned {
+    pub parent_hash: Vec<u8>,
+    pub hash: Vec<u8>,
+    pub block_num: i64,
+    pub state_root: Vec<u8>,
+    pub extrinsics_root: Vec<u8>,
+    pub time: Option<DateTime<Utc>>,
+    pub canonical: bool,
+    pub finalized: bool,
+    pub fork: i64,
+}
+
 #[derive(Insertable)]
 #[table_name = "inherents"]
 pub struct InsertInherent<'a> {
@@ -104,7 +123,7 @@ pub struct InsertTransaction<'a> {
 #[derive(Insertable, Debug, Clone)]
 #[table_name = "signed_extrinsics"]
 pub struct InsertTransactionOwned {
-    // pub transaction_hash: Vec<u8>,
+    pub transaction_hash: Option<Vec<u8>>,
     pub block_num: i64,
     pub hash: Vec<u8>,
     // pub from_addr: Vec<u8>,
@@ -140,7 +159,8 @@ pub struct InsertStorage<'a> {
     pub hash: &'a [u8],
     pub module: &'a str,
     pub function: &'a str,
-    pub parameters: &'a Value,
+    pub parameters: Option<&'a Value>,
+    pub key: &'a [u8],
 }
 
 #[derive(Insertable)]
@@ -150,7 +170,115 @@ pub struct InsertStorageOwned {
     pub hash: Vec<u8>,
     pub module: String,
     pub function: String,
+    pub parameters: Option<Value>,
+    pub key: Vec<u8>,
+    pub data: Option<Vec<u8>>,
+}
+
+/// A storage value as it was archived, with the state root of its block
+#[derive(Queryable, PartialEq, Debug)]
+pub struct ArchivedStorage {
+    pub id: i32,
+    pub hash: Vec<u8>,
+    pub state_root: Vec<u8>,
+    pub key: Vec<u8>,
+    pub data: Option<Vec<u8>>,
+}
+
+/// An event deposited in `System.Events`, with its decoded arguments
+#[derive(Insertable, Debug, Clone)]
+#[table_name = "events"]
+pub struct InsertEvent {
+    pub block_num: i64,
+    pub hash: Vec<u8>,
+    pub module: String,
+    pub event: String,
     pub parameters: Value,
+    pub event_index: i32,
+    pub phase: String,
+    /// `None` unless the event was deposited while applying an extrinsic
+    pub extrinsic_index: Option<i32>,
+}
+
+/// A value in a child trie
+#[derive(Insertable)]
+#[table_name = "child_storage"]
+pub struct InsertChildStorage {
+    pub block_num: i64,
+    pub hash: Vec<u8>,
+    pub child_storage_key: Vec<u8>,
+    pub key: Vec<u8>,
+    pub data: Vec<u8>,
+}
+
+/// An extrinsic seen in the transaction pool
+#[derive(Insertable, Debug, Clone)]
+#[table_name = "pending_extrinsics"]
+pub struct InsertPendingExtrinsic {
+    pub hash: Vec<u8>,
+    pub data: Vec<u8>,
+    pub signed: Option<bool>,
+    pub module: Option<String>,
+    pub call: Option<String>,
+    pub parameters: Option<Value>,
+    pub first_seen: DateTime<Utc>,
+}
+
+/// A block that failed an integrity check
+#[derive(Insertable, Debug, Clone)]
+#[table_name = "quarantine"]
+pub struct InsertQuarantine {
+    pub hash: Vec<u8>,
+    pub block_num: i64,
+    pub reason: String,
+    pub header: Vec<u8>,
+    pub extrinsics: Vec<Vec<u8>>,
+    pub received_at: DateTime<Utc>,
+}
+
+/// Events of a block that could not be decoded
+#[derive(Insertable, Debug, Clone)]
+#[table_name = "undecoded_events"]
+pub struct InsertUndecodedEvents {
+    pub block_num: i64,
+    pub hash: Vec<u8>,
+    pub data: Vec<u8>,
+    pub error: String,
+}
+
+/// Identity of the chain a database archives
+#[derive(Insertable, AsChangeset, Queryable, PartialEq, Debug, Clone)]
+#[table_name = "chain_info"]
+pub struct ChainInfoRow {
+    pub genesis_hash: Vec<u8>,
+    pub chain: String,
+    pub properties: Value,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Range of blocks executed with one runtime
+#[derive(Insertable, Queryable, PartialEq, Debug, Clone)]
+#[table_name = "runtime_versions"]
+pub struct InsertRuntimeVersion {
+    pub spec_version: i32,
+    pub spec_name: String,
+    pub from_block: i64,
+    pub to_block: i64,
+    pub from_hash: Vec<u8>,
+}
+
+/// Progress of one phase of sync, for the range of blocks from `from_block` to `to_block`
+#[derive(Insertable, AsChangeset, Queryable, PartialEq, Debug)]
+#[table_name = "sync_state"]
+pub struct SyncState {
+    pub phase: String,
+    pub completed_to: i64,
+    pub target: i64,
+    pub rate: f64,
+    pub eta: Option<i64>,
+    pub updated_at: DateTime<Utc>,
+    pub from_block: i64,
+    pub to_block: Option<i64>,
 }
 
 type EncodedData = Vec<u8>;
@@ -21,6 +21,29 @@ table! {
         state_root -> Bytea,
         extrinsics_root -> Bytea,
         time -> Nullable<Timestamptz>,
+        canonical -> Bool,
+        finalized -> Bool,
+        fork -> Int8,
+    }
+}
+
+table! {
+    chain_info (genesis_hash) {
+        genesis_hash -> Bytea,
+        chain -> Varchar,
+        properties -> Jsonb,
+        updated_at -> Timestamptz,
+    }
+}
+
+table! {
+    child_storage (id) {
+        id -> Int4,
+        block_num -> Int8,
+        hash -> Bytea,
+        child_storage_key -> Bytea,
+        key -> Bytea,
+        data -> Bytea,
     }
 }
 
@@ -32,6 +55,9 @@ table! {
         module -> Varchar,
         event -> Varchar,
         parameters -> Jsonb,
+        event_index -> Int4,
+        phase -> Varchar,
+        extrinsic_index -> Nullable<Int4>,
     }
 }
 
@@ -48,6 +74,41 @@ table! {
     }
 }
 
+table! {
+    pending_extrinsics (hash) {
+        hash -> Bytea,
+        data -> Bytea,
+        signed -> Nullable<Bool>,
+        module -> Nullable<Varchar>,
+        call -> Nullable<Varchar>,
+        parameters -> Nullable<Jsonb>,
+        first_seen -> Timestamptz,
+        included_in -> Nullable<Bytea>,
+    }
+}
+
+table! {
+    quarantine (id) {
+        id -> Int4,
+        hash -> Bytea,
+        block_num -> Int8,
+        reason -> Text,
+        header -> Bytea,
+        extrinsics -> Array<Bytea>,
+        received_at -> Timestamptz,
+    }
+}
+
+table! {
+    runtime_versions (spec_version) {
+        spec_version -> Int4,
+        spec_name -> Varchar,
+        from_block -> Int8,
+        to_block -> Int8,
+        from_hash -> Bytea,
+    }
+}
+
 table! {
     signed_extrinsics (id) {
         id -> Int4,
@@ -58,6 +119,7 @@ table! {
         parameters -> Jsonb,
         tx_index -> Int4,
         transaction_version -> Int4,
+        transaction_hash -> Nullable<Bytea>,
     }
 }
 
@@ -68,21 +130,59 @@ table! {
         hash -> Bytea,
         module -> Varchar,
         function -> Varchar,
-        parameters -> Jsonb,
+        parameters -> Nullable<Jsonb>,
+        key -> Bytea,
+        data -> Nullable<Bytea>,
+        verified -> Nullable<Bool>,
+        verify_attempts -> Int4,
+    }
+}
+
+table! {
+    sync_state (id) {
+        phase -> Varchar,
+        completed_to -> Int8,
+        target -> Int8,
+        rate -> Float8,
+        eta -> Nullable<Int8>,
+        updated_at -> Timestamptz,
+        id -> Int4,
+        from_block -> Int8,
+        to_block -> Nullable<Int8>,
+    }
+}
+
+table! {
+    undecoded_events (id) {
+        id -> Int4,
+        block_num -> Int8,
+        hash -> Bytea,
+        data -> Bytea,
+        error -> Text,
     }
 }
 
 joinable!(accounts -> blocks (create_hash));
 joinable!(events -> blocks (hash));
 joinable!(inherents -> blocks (hash));
+joinable!(child_storage -> blocks (hash));
+joinable!(pending_extrinsics -> blocks (included_in));
 joinable!(signed_extrinsics -> blocks (hash));
 joinable!(storage -> blocks (hash));
+joinable!(undecoded_events -> blocks (hash));
 
 allow_tables_to_appear_in_same_query!(
     accounts,
     blocks,
+    chain_info,
+    child_storage,
     events,
     inherents,
+    pending_extrinsics,
+    quarantine,
+    runtime_versions,
     signed_extrinsics,
     storage,
+    sync_state,
+    undecoded_events,
 );
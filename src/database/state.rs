@@ -0,0 +1,122 @@
+// Copyright 2017-2019 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Storage values, with the module and name of their entry and their decoded value
+
+use async_trait::async_trait;
+use diesel::{pg::PgConnection, prelude::*};
+use log::*;
+
+use std::collections::HashMap;
+
+use super::{
+    models::InsertStorageOwned,
+    schema::{blocks, storage},
+    Database, DbReturn, Insert,
+};
+use crate::{
+    chain::ChainInfo,
+    decode, metrics,
+    types::{BatchStorage, Storage, System},
+};
+
+#[async_trait]
+impl<T> Insert for Storage<T>
+where
+    T: System,
+{
+    async fn insert(self, db: &Database) -> DbReturn {
+        trace!("inserting storage for: {}", self.hash());
+        let (chunk, chain) = (db.storage_chunk, db.chain.clone());
+        db.run(move |conn| insert_storage(&conn, &chain, vec![self], chunk))
+            .await
+    }
+}
+
+#[async_trait]
+impl<T> Insert for BatchStorage<T>
+where
+    T: System,
+{
+    async fn insert(self, db: &Database) -> DbReturn {
+        debug!("Inserting {} items via Batch Storage", self.inner().len());
+        let storage: Vec<Storage<T>> = self.consume();
+        let (chunk, chain) = (db.storage_chunk, db.chain.clone());
+        db.run(move |conn| {
+            insert_storage(&conn, &chain, storage, chunk)?;
+            info!("Done inserting storage!");
+            Ok(())
+        })
+        .await
+    }
+}
+
+/// insert storage values, skipping any whose block is not in the database
+fn insert_storage<T>(
+    conn: &PgConnection,
+    chain: &ChainInfo,
+    items: Vec<Storage<T>>,
+    chunk: usize,
+) -> DbReturn
+where
+    T: System,
+{
+    let hashes = items
+        .iter()
+        .map(|s| s.hash().as_ref().to_vec())
+        .collect::<Vec<Vec<u8>>>();
+    let numbers: HashMap<Vec<u8>, i64> = blocks::table
+        .select((blocks::hash, blocks::block_num))
+        .filter(blocks::hash.eq_any(hashes))
+        .load::<(Vec<u8>, i64)>(conn)?
+        .into_iter()
+        .collect();
+
+    let values = items
+        .into_iter()
+        .filter_map(|s| {
+            let hash = s.hash().as_ref().to_vec();
+            let block_num = match numbers.get(&hash) {
+                Some(n) => *n,
+                None => {
+                    warn!("Block {:?} not archived, skipping storage", s.hash());
+                    return None;
+                }
+            };
+            Some(InsertStorageOwned {
+                block_num,
+                hash,
+                module: s.metadata().module().to_string(),
+                function: s.metadata().name().to_string(),
+                parameters: s
+                    .data()
+                    .map(|d| decode::storage_value::<T>(chain, s.metadata(), &d.0)),
+                key: s.key().0.clone(),
+                data: s.data().map(|d| d.0.clone()),
+            })
+        })
+        .collect::<Vec<InsertStorageOwned>>();
+
+    for chunks in values.as_slice().chunks(chunk) {
+        let _timer = metrics::time_insert("storage");
+        diesel::insert_into(storage::table)
+            .values(chunks)
+            .on_conflict((storage::hash, storage::key))
+            .do_nothing()
+            .execute(conn)?;
+    }
+    Ok(())
+}
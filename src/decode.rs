@@ -0,0 +1,189 @@
+// Copyright 2017-2019 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Decoding SCALE values into JSON, by the name of their type in the metadata
+
+use codec::{Compact, Decode, Encode};
+use serde_json::{json, Value};
+use substrate_primitives::hexdisplay::HexDisplay;
+
+use crate::{
+    chain::ChainInfo,
+    error::Error,
+    metadata::{EventArg, StorageMetadata},
+    types::System,
+};
+
+/// Decode a value of type `ty`, advancing `input` past it
+/// Accounts and balances are formatted the way `chain` displays them
+pub(crate) fn decode_value<T: System>(
+    chain: &ChainInfo,
+    ty: &EventArg,
+    input: &mut &[u8],
+) -> Result<Value, Error> {
+    match ty {
+        EventArg::Vec(inner) if **inner == EventArg::Primitive("u8".to_string()) => {
+            let bytes: Vec<u8> = Decode::decode(input)?;
+            Ok(json!(hex(&bytes)))
+        }
+        EventArg::Vec(inner) => {
+            let len = <Compact<u32>>::decode(input)?.0;
+            let items = (0..len)
+                .map(|_| decode_value::<T>(chain, inner, input))
+                .collect::<Result<Vec<Value>, Error>>()?;
+            Ok(Value::Array(items))
+        }
+        EventArg::Tuple(types) => {
+            let items = types
+                .iter()
+                .map(|t| decode_value::<T>(chain, t, input))
+                .collect::<Result<Vec<Value>, Error>>()?;
+            Ok(Value::Array(items))
+        }
+        EventArg::Primitive(name) if name.starts_with("Option<") && name.ends_with('>') => {
+            let inner: EventArg = name[7..name.len() - 1].parse()?;
+            match u8::decode(input)? {
+                0 => Ok(Value::Null),
+                1 => decode_value::<T>(chain, &inner, input),
+                _ => Err(Error::UnexpectedType(format!("Invalid {}", name))),
+            }
+        }
+        EventArg::Primitive(name) => decode_primitive::<T>(chain, name, input),
+    }
+}
+
+/// Decode a value by the name of its type
+/// Types of the runtime are decoded with `T`; numbers too large for JSON are strings
+/// Accounts are SS58 addresses if `chain` sets a prefix, otherwise hex
+/// Balances are the raw amount, as a string; `ChainInfo::units` formats them for display
+fn decode_primitive<T: System>(
+    chain: &ChainInfo,
+    name: &str,
+    input: &mut &[u8],
+) -> Result<Value, Error> {
+    // `T::AccountId`, `<T as Trait>::Balance` and `BalanceOf<T>` are named by their last segment
+    let name = name.rsplit("::").next().unwrap_or(name);
+    let name = name.split('<').next().unwrap_or(name);
+    let value = match name {
+        "bool" => json!(bool::decode(input)?),
+        "u8" | "Percent" => json!(u8::decode(input)?),
+        "u16" => json!(u16::decode(input)?),
+        "u32" | "AccountIndex" | "AuthorityIndex" | "EraIndex" | "MemberCount" | "Perbill"
+        | "Permill" | "PropIndex" | "ProposalIndex" | "ReferendumIndex" | "SessionIndex" => {
+            json!(u32::decode(input)?)
+        }
+        "u64" | "Moment" => json!(u64::decode(input)?),
+        "u128" => json!(u128::decode(input)?.to_string()),
+        "Balance" | "BalanceOf" => json!(T::Balance::decode(input)?.to_string()),
+        "BlockNumber" => {
+            let number: u64 = T::BlockNumber::decode(input)?.into();
+            json!(number)
+        }
+        "Hash" => json!(hex(T::Hash::decode(input)?.as_ref())),
+        "AccountId" => {
+            let account = T::AccountId::decode(input)?.encode();
+            json!(chain.ss58(&account).unwrap_or_else(|| hex(&account)))
+        }
+        _ => return Err(Error::UnexpectedType(format!("Unknown type {}", name))),
+    };
+    Ok(value)
+}
+
+/// Decode a storage value by the value type of its entry, as `{"type": <type>, "value": <value>}`
+/// Values that can not be decoded, or that have bytes left over (as the values of linked maps do),
+/// are kept encoded, as `{"value": <hex>, "encoded": true}`
+pub(crate) fn storage_value<T: System>(
+    chain: &ChainInfo,
+    meta: &StorageMetadata,
+    data: &[u8],
+) -> Value {
+    let decoded = meta.value_type().map_err(Error::from).and_then(|ty| {
+        let mut input = data;
+        let value = decode_value::<T>(chain, &ty, &mut input)?;
+        if !input.is_empty() {
+            return Err(Error::UnexpectedType(format!(
+                "{} bytes left over decoding {}",
+                input.len(),
+                ty
+            )));
+        }
+        Ok(json!({ "type": ty.to_string(), "value": value }))
+    });
+    decoded.unwrap_or_else(|e| {
+        log::debug!(
+            "Could not decode {} {}: {:?}",
+            meta.module(),
+            meta.name(),
+            e
+        );
+        json!({ "value": hex(data), "encoded": true })
+    })
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    format!("0x{}", HexDisplay::from(&bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{chain_info, Runtime};
+
+    fn decode(ty: &str, data: &[u8]) -> Result<Value, Error> {
+        decode_value::<Runtime>(&chain_info(json!({})), &ty.parse().unwrap(), &mut &data[..])
+    }
+
+    #[test]
+    fn should_decode_runtime_types() {
+        let now = 1_576_000_000_000u64.encode();
+        assert_eq!(
+            decode("T::Moment", &now).unwrap(),
+            json!(1_576_000_000_000u64)
+        );
+        let number = 42u32.encode();
+        assert_eq!(decode("T::BlockNumber", &number).unwrap(), json!(42));
+        let accounts = vec![[1u8; 32], [2u8; 32]].encode();
+        assert_eq!(
+            decode("Vec<T::AccountId>", &accounts).unwrap(),
+            json!([hex(&[1u8; 32]), hex(&[2u8; 32])])
+        );
+    }
+
+    #[test]
+    fn should_format_accounts_and_keep_raw_balances() {
+        let chain = chain_info(json!({ "ss58Format": 42, "tokenDecimals": 12 }));
+        let decode = |ty: &str, data: &[u8]| {
+            decode_value::<Runtime>(&chain, &ty.parse().unwrap(), &mut &data[..]).unwrap()
+        };
+        let account = decode("T::AccountId", &[1u8; 32]);
+        assert_eq!(account, json!(chain.ss58(&[1u8; 32]).unwrap()));
+        let balance = 1_500_000_000_000u128.encode();
+        assert_eq!(decode("BalanceOf<T>", &balance), json!("1500000000000"));
+        assert_eq!(decode("u128", &balance), json!("1500000000000"));
+    }
+
+    #[test]
+    fn should_decode_options() {
+        assert_eq!(decode("Option<u32>", &[0]).unwrap(), Value::Null);
+        let some = Some(7u32).encode();
+        assert_eq!(decode("Option<u32>", &some).unwrap(), json!(7));
+    }
+
+    #[test]
+    fn should_not_decode_unknown_types() {
+        assert!(decode("Exposure<T::AccountId, BalanceOf<T>>", &[0]).is_err());
+    }
+}
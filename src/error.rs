@@ -16,7 +16,8 @@
 
 use codec::Error as CodecError;
 use failure::Fail;
-use futures::channel::mpsc::TrySendError;
+use futures::channel::mpsc::{SendError, TrySendError};
+use hyper::Error as HyperError;
 use jsonrpc_core_client::RpcError as JsonRpcError;
 use tokio::task::JoinError;
 // use jsonrpc_client_transports::RpcError as JsonRpcTransportError;
@@ -28,6 +29,7 @@ use std::env::VarError as EnvironmentError;
 use std::io::Error as IoError;
 use std::num::TryFromIntError;
 use url::ParseError;
+use websocket::result::WebSocketError;
 
 #[derive(Debug, Fail)]
 pub enum Error {
@@ -57,6 +59,8 @@ pub enum Error {
     IntConversion(#[fail(cause)] TryFromIntError),
     #[fail(display = "Serialization: {}", _0)]
     Serialize(#[fail(cause)] SerdeError),
+    #[fail(display = "Metrics server: {}", _0)]
+    Http(#[fail(cause)] HyperError),
 
     #[fail(display = "Call type unhandled, not committing to database")]
     UnhandledCallType,
@@ -70,6 +74,32 @@ pub enum Error {
     UnexpectedType(String),
     #[fail(display = "Metadata {}", _0)]
     Metadata(MetadataError),
+    #[fail(display = "Nodes are on different chains: {}", _0)]
+    GenesisMismatch(String),
+    #[fail(display = "Database archives a different chain: {}", _0)]
+    ChainMismatch(String),
+    #[fail(display = "Invalid read proof: {}", _0)]
+    InvalidProof(String),
+    #[fail(display = "Invalid configuration: {}", _0)]
+    InvalidConfig(String),
+    #[fail(display = "Transport: {}", _0)]
+    Transport(String),
+    #[fail(display = "Node answered with an error: {}", _0)]
+    RpcResponse(String),
+}
+
+impl Error {
+    /// whether the same request may succeed if it is tried again
+    /// The node answering with an error, or data that does not exist, is permanent;
+    /// timeouts and transport errors are not
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::Rpc(JsonRpcError::Timeout) | Error::Rpc(JsonRpcError::Other(_)) => true,
+            Error::Rpc(_) => false,
+            Error::Io(_) | Error::Transport(_) => true,
+            _ => false,
+        }
+    }
 }
 
 impl From<JoinError> for Error {
@@ -78,6 +108,18 @@ impl From<JoinError> for Error {
     }
 }
 
+impl From<HyperError> for Error {
+    fn from(err: HyperError) -> Error {
+        Error::Http(err)
+    }
+}
+
+impl From<WebSocketError> for Error {
+    fn from(err: WebSocketError) -> Error {
+        Error::Transport(err.to_string())
+    }
+}
+
 impl From<SerdeError> for Error {
     fn from(err: SerdeError) -> Error {
         Error::Serialize(err)
@@ -138,6 +180,12 @@ impl<T> From<TrySendError<T>> for Error {
     }
 }
 
+impl From<SendError> for Error {
+    fn from(err: SendError) -> Error {
+        Error::Send(err.to_string())
+    }
+}
+
 impl From<JsonRpcError> for Error {
     fn from(err: JsonRpcError) -> Error {
         Error::Rpc(err)
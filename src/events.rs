@@ -0,0 +1,192 @@
+// Copyright 2017-2019 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Decoding of the events a block deposited in `System.Events`
+
+use codec::{Compact, Decode};
+use serde_json::{json, Value};
+use substrate_primitives::storage::StorageData;
+
+use crate::{
+    chain::ChainInfo,
+    decode::decode_value,
+    error::Error,
+    metadata::{EventArg, Metadata},
+    types::System,
+};
+
+/// An event decoded from `System.Events`, ready to be committed
+#[derive(Debug, Clone, PartialEq)]
+pub struct DbEvent {
+    /// position of the event in `System.Events`
+    pub event_index: i32,
+    pub module: String,
+    pub event: String,
+    /// each argument, as `{"type": <type in the metadata>, "value": <decoded value>}`
+    pub parameters: Value,
+    /// `ApplyExtrinsic`, `Finalization` or `Initialization`
+    pub phase: String,
+    /// index of the extrinsic that deposited the event, in its block
+    pub extrinsic_index: Option<i32>,
+}
+
+impl DbEvent {
+    /// Decode the `Vec<EventRecord>` stored under `System.Events` for one block
+    /// Each record is the phase, the module and event index, the arguments, then the topics.
+    /// Module and event names, and the types of the arguments, are looked up in the metadata of
+    /// the runtime the block was executed with. The length of an event is only known from its
+    /// arguments, so an event the metadata does not know about, or an argument of a type that
+    /// can not be decoded, fails the whole block
+    /// Accounts and balances in the arguments are formatted the way `chain` displays them
+    pub fn decode<T>(
+        data: &StorageData,
+        metadata: &Metadata,
+        chain: &ChainInfo,
+    ) -> Result<Vec<DbEvent>, Error>
+    where
+        T: System,
+    {
+        let input = &mut data.0.as_slice();
+        let len = <Compact<u32>>::decode(input)?.0;
+        let mut events = Vec::with_capacity(len as usize);
+        for idx in 0..len {
+            let (phase, extrinsic_index) = decode_phase(input)?;
+            let (module_index, event_index) = (u8::decode(input)?, u8::decode(input)?);
+            let (module, event) = metadata.event(module_index, event_index).ok_or_else(|| {
+                Error::DataNotFound(format!(
+                    "Event {} of module {} in metadata",
+                    event_index, module_index
+                ))
+            })?;
+            let parameters = decode_args::<T>(chain, event.arguments(), input)?;
+            // topics are not archived
+            let _topics: Vec<T::Hash> = Decode::decode(input)?;
+            events.push(DbEvent {
+                event_index: idx as i32,
+                module,
+                event: event.name.clone(),
+                parameters,
+                phase: phase.to_string(),
+                extrinsic_index,
+            });
+        }
+        Ok(events)
+    }
+}
+
+/// Decode the phase of an event: the index of the extrinsic that deposited it, or whether it
+/// was deposited in block finalization or initialization
+fn decode_phase(input: &mut &[u8]) -> Result<(&'static str, Option<i32>), Error> {
+    match u8::decode(input)? {
+        0 => Ok(("ApplyExtrinsic", Some(u32::decode(input)? as i32))),
+        1 => Ok(("Finalization", None)),
+        2 => Ok(("Initialization", None)),
+        other => Err(Error::UnexpectedType(format!(
+            "Invalid event phase {}",
+            other
+        ))),
+    }
+}
+
+/// Decode the arguments of an event, advancing `input` past them
+fn decode_args<T: System>(
+    chain: &ChainInfo,
+    args: &[EventArg],
+    input: &mut &[u8],
+) -> Result<Value, Error> {
+    let decoded = args
+        .iter()
+        .map(|arg| {
+            let value = decode_value::<T>(chain, arg, input)?;
+            Ok(json!({ "type": arg.to_string(), "value": value }))
+        })
+        .collect::<Result<Vec<Value>, Error>>()?;
+    Ok(Value::Array(decoded))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::hex;
+    use crate::tests::{chain_info, Runtime};
+    use codec::Encode;
+
+    fn args(types: &[&str]) -> Vec<EventArg> {
+        types.iter().map(|t| t.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn should_decode_event_arguments() {
+        let mut encoded = [7u8; 32].to_vec();
+        encoded.extend(1_000_000_000_000u128.encode());
+        encoded.extend(vec![(1u32, true)].encode());
+        let input = &mut encoded.as_slice();
+        let decoded = decode_args::<Runtime>(
+            &chain_info(json!({})),
+            &args(&["AccountId", "BalanceOf<T>", "Vec<(u32, bool)>"]),
+            input,
+        )
+        .unwrap();
+        assert_eq!(
+            decoded,
+            json!([
+                { "type": "AccountId", "value": hex(&[7u8; 32]) },
+                { "type": "BalanceOf<T>", "value": "1000000000000" },
+                { "type": "Vec<(u32, bool)>", "value": [[1, true]] },
+            ])
+        );
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn should_not_decode_unknown_arguments() {
+        let mut encoded = 5u32.encode();
+        encoded.extend(vec![1u8, 2, 3]);
+        let input = &mut encoded.as_slice();
+        assert!(decode_args::<Runtime>(
+            &chain_info(json!({})),
+            &args(&["SessionIndex", "Kind", "u8"]),
+            input
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn should_decode_bytes_as_hex() {
+        let encoded = b"remark".to_vec().encode();
+        let decoded = decode_args::<Runtime>(
+            &chain_info(json!({})),
+            &args(&["Vec<u8>"]),
+            &mut encoded.as_slice(),
+        );
+        assert_eq!(
+            decoded.unwrap(),
+            json!([{ "type": "Vec<u8>", "value": hex(b"remark") }])
+        );
+    }
+
+    #[test]
+    fn should_decode_phases() {
+        let mut encoded = vec![0u8];
+        encoded.extend(3u32.encode());
+        encoded.extend(vec![1u8, 2]);
+        let input = &mut encoded.as_slice();
+        assert_eq!(decode_phase(input).unwrap(), ("ApplyExtrinsic", Some(3)));
+        assert_eq!(decode_phase(input).unwrap(), ("Finalization", None));
+        assert_eq!(decode_phase(input).unwrap(), ("Initialization", None));
+        assert!(decode_phase(&mut &[3u8][..]).is_err());
+    }
+}
@@ -15,8 +15,9 @@
 // along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
 
 use crate::{
-    database::models::{InsertInherentOwned, InsertTransactionOwned},
+    database::models::{InsertInherentOwned, InsertPendingExtrinsic, InsertTransactionOwned},
     error::Error,
+    metrics,
     types::{ExtractCall, Module, System, ToDatabaseExtrinsic},
     util,
 };
@@ -24,11 +25,12 @@ use chrono::{DateTime, TimeZone, Utc};
 use codec::{Decode, Error as CodecError, Input};
 use runtime_primitives::{
     generic::UncheckedExtrinsic,
-    traits::{Header, SignedExtension},
+    traits::{Hash, Header, SignedExtension},
 };
 use serde::Deserialize;
-use serde_json::json;
+use serde_json::{json, Value};
 use std::{fmt::Debug, iter::FromIterator};
+use substrate_primitives::Bytes;
 
 const LATEST_TRANSACTION_VERSION: u8 = 4;
 
@@ -166,11 +168,13 @@ pub enum RawExtrinsic {
 }
 
 impl RawExtrinsic {
+    /// `transaction_hash` is the hash of the encoded extrinsic, as the transaction pool hashes it
     pub fn database_format<H>(
         &self,
         index: i32,
         header: &H,
         number: i64,
+        transaction_hash: Option<Vec<u8>>,
     ) -> Result<DbExtrinsic, Error>
     where
         H: Header,
@@ -187,7 +191,7 @@ impl RawExtrinsic {
                     res?
                 };
                 Ok(DbExtrinsic::Signed(InsertTransactionOwned {
-                    // transaction_hash: Vec::new(),
+                    transaction_hash,
                     block_num: number,
                     hash: header.hash().as_ref().to_vec(),
                     // from_addr: Vec::new(), // TODO
@@ -256,6 +260,80 @@ impl RawExtrinsic {
             RawExtrinsic::NotSigned(v) => v.version,
         }
     }
+
+    /// (module, function, parameters) of the call
+    fn call(&self) -> Result<(String, String, Value), Error> {
+        let call = match self {
+            RawExtrinsic::Signed(v) => &v.call,
+            RawExtrinsic::NotSigned(v) => &v.call,
+        };
+        let (module, call) = call.extract_call();
+        let (fn_name, params) = call.function()?;
+        Ok((module.to_string(), fn_name, params))
+    }
+}
+
+/// The encoded extrinsic, as the node sends it over RPC
+pub fn encoded<T: System>(extrinsic: &T::Extrinsic) -> Result<Vec<u8>, Error> {
+    let bytes: Bytes = serde_json::from_value(serde_json::to_value(extrinsic)?)?;
+    Ok(bytes.0)
+}
+
+/// Hashes of the extrinsics of a block, in the same form as the transaction pool hashes them
+/// extrinsics that can not be encoded are left out
+pub fn hashes<T: System>(extrinsics: &[T::Extrinsic]) -> Vec<Vec<u8>> {
+    extrinsics.iter().filter_map(hash::<T>).collect()
+}
+
+/// Hash of an extrinsic, in the same form as the transaction pool hashes it
+/// `None` if the extrinsic can not be encoded
+fn hash<T: System>(extrinsic: &T::Extrinsic) -> Option<Vec<u8>> {
+    match encoded::<T>(extrinsic) {
+        Ok(data) => Some(T::Hashing::hash(&data).as_ref().to_vec()),
+        Err(e) => {
+            log::warn!("Could not encode extrinsic: {:?}", e);
+            None
+        }
+    }
+}
+
+/// An encoded extrinsic from the transaction pool, decoded as far as possible
+/// Extrinsics that do not decode are still kept, with only their data
+pub fn pending<T: System>(data: Vec<u8>, first_seen: DateTime<Utc>) -> InsertPendingExtrinsic {
+    let decoded = serde_json::to_value(Bytes(data.clone()))
+        .and_then(serde_json::from_value::<T::Extrinsic>)
+        .map_err(Error::from)
+        .and_then(|e| e.to_database());
+    let (signed, call) = match decoded {
+        Ok(ext) => {
+            let signed = match ext {
+                RawExtrinsic::Signed(_) => true,
+                RawExtrinsic::NotSigned(_) => false,
+            };
+            let call = ext.call().map_err(|e| {
+                log::debug!("Call not found for pending extrinsic: {:?}", e);
+                e
+            });
+            (Some(signed), call.ok())
+        }
+        Err(e) => {
+            log::warn!("Could not decode pending extrinsic: {:?}", e);
+            (None, None)
+        }
+    };
+    let (module, call, parameters) = match call {
+        Some((module, call, params)) => (Some(module), Some(call), Some(params)),
+        None => (None, None, None),
+    };
+    InsertPendingExtrinsic {
+        hash: T::Hashing::hash(&data).as_ref().to_vec(),
+        data,
+        signed,
+        module,
+        call,
+        parameters,
+        first_seen,
+    }
 }
 
 #[derive(Debug)]
@@ -338,7 +416,7 @@ impl DbExtrinsic {
     where
         T: System,
     {
-        extrinsics
+        let extrinsics = extrinsics
             .iter()
             // enumerate is used here to preserve order/index of extrinsics
             .enumerate()
@@ -346,23 +424,29 @@ impl DbExtrinsic {
                 log::debug!("Decoding Extrinsic in block: {:?}", header.number());
                 let decoded: RawExtrinsic = x.to_database()?;
                 log::trace!("{}", util::log_extrinsics(&decoded));
-                Ok((idx, decoded))
+                Ok((idx, decoded, hash::<T>(x)))
             })
-            .collect::<Vec<Result<(usize, RawExtrinsic), Error>>>()
+            .collect::<Vec<Result<(usize, RawExtrinsic, Option<Vec<u8>>), Error>>>()
             .into_iter()
             // we don't want to skip over _all_ extrinsics if decoding one extrinsic does not work
-            .filter_map(|x: Result<(usize, RawExtrinsic), _>| match x {
+            .filter_map(|x| match x {
                 Ok(v) => {
+                    metrics::extrinsic_decoded(true);
                     let number = (*header.number()).into() as i64;
                     let index: i32 = v.0 as i32;
-                    Some(v.1.database_format(index, header, number))
+                    Some(v.1.database_format(index, header, number, v.2))
                 }
                 Err(e) => {
+                    metrics::extrinsic_decoded(false);
                     log::error!("{:?}", e);
                     None
                 }
             })
-            .collect::<Result<Extrinsics, Error>>()
+            .collect::<Result<Extrinsics, Error>>();
+        if extrinsics.is_ok() {
+            metrics::blocks_decoded(1);
+        }
+        extrinsics
     }
 }
 
@@ -0,0 +1,316 @@
+// Copyright 2017-2019 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Checking blocks from the node before they are archived
+
+use chrono::Utc;
+use codec::Encode;
+use runtime_primitives::traits::{Hash, Header};
+use substrate_primitives::hexdisplay::HexDisplay;
+
+use std::{collections::HashMap, fmt};
+
+use crate::{
+    database::models::InsertQuarantine, error::Error as ArchiveError, extrinsics, types::System,
+};
+
+/// Why a block is quarantined instead of archived
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Fault {
+    /// the recomputed hash is not the hash the block was requested or announced by
+    RequestedHash {
+        requested: Vec<u8>,
+        computed: Vec<u8>,
+    },
+    /// the extrinsics do not hash to the extrinsics root of the header
+    ExtrinsicsRoot { header: Vec<u8>, computed: Vec<u8> },
+    /// the parent hash is not the hash of the finalized block before it
+    Parent {
+        parent_hash: Vec<u8>,
+        archived: Vec<u8>,
+    },
+    /// the recomputed hash is not the parent hash of the finalized block after it
+    Hash {
+        hash: Vec<u8>,
+        child_parent: Vec<u8>,
+    },
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Fault::RequestedHash {
+                requested,
+                computed,
+            } => write!(
+                f,
+                "requested as 0x{}, header hashes to 0x{}",
+                HexDisplay::from(requested),
+                HexDisplay::from(computed)
+            ),
+            Fault::ExtrinsicsRoot { header, computed } => write!(
+                f,
+                "extrinsics root 0x{} of the header, computed 0x{}",
+                HexDisplay::from(header),
+                HexDisplay::from(computed)
+            ),
+            Fault::Parent {
+                parent_hash,
+                archived,
+            } => write!(
+                f,
+                "parent hash 0x{}, previous block is 0x{}",
+                HexDisplay::from(parent_hash),
+                HexDisplay::from(archived)
+            ),
+            Fault::Hash { hash, child_parent } => write!(
+                f,
+                "hash 0x{}, next block has parent 0x{}",
+                HexDisplay::from(hash),
+                HexDisplay::from(child_parent)
+            ),
+        }
+    }
+}
+
+/// Where a block sits in the chain
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Link {
+    pub(crate) block_num: i64,
+    pub(crate) hash: Vec<u8>,
+    pub(crate) parent_hash: Vec<u8>,
+    pub(crate) finalized: bool,
+}
+
+/// A block as the node returned it, kept until it passes every check
+#[derive(Debug, Clone)]
+pub(crate) struct Received {
+    link: Link,
+    /// hash the block was requested or announced by, if any
+    requested: Option<Vec<u8>>,
+    extrinsics_root: Vec<u8>,
+    header: Vec<u8>,
+    extrinsics: Vec<Vec<u8>>,
+}
+
+impl Received {
+    /// Recompute the hash of the header with the hashing of the runtime
+    /// `requested` is the hash the block was requested or announced by, if any
+    /// Errors if an extrinsic can not be encoded
+    pub(crate) fn new<T: System>(
+        header: &T::Header,
+        extrinsics: &[T::Extrinsic],
+        finalized: bool,
+        requested: Option<&T::Hash>,
+    ) -> Result<Self, ArchiveError> {
+        let extrinsics = extrinsics
+            .iter()
+            .map(extrinsics::encoded::<T>)
+            .collect::<Result<Vec<Vec<u8>>, ArchiveError>>()?;
+        Ok(Self {
+            link: Link {
+                block_num: (*header.number()).into() as i64,
+                hash: T::Hashing::hash_of(header).as_ref().to_vec(),
+                parent_hash: header.parent_hash().as_ref().to_vec(),
+                finalized,
+            },
+            requested: requested.map(|h| h.as_ref().to_vec()),
+            extrinsics_root: header.extrinsics_root().as_ref().to_vec(),
+            header: header.encode(),
+            extrinsics,
+        })
+    }
+
+    pub(crate) fn link(&self) -> &Link {
+        &self.link
+    }
+
+    /// hash of the header, as recomputed
+    pub(crate) fn hash(&self) -> &[u8] {
+        &self.link.hash
+    }
+
+    /// Check the block on its own: the recomputed hash against the hash it was requested by,
+    /// and the extrinsics against the extrinsics root of the header
+    pub(crate) fn check_body<T: System>(&self) -> Result<(), Fault> {
+        if let Some(requested) = &self.requested {
+            if *requested != self.link.hash {
+                return Err(Fault::RequestedHash {
+                    requested: requested.clone(),
+                    computed: self.link.hash.clone(),
+                });
+            }
+        }
+        let computed = T::Hashing::ordered_trie_root(self.extrinsics.clone());
+        if computed.as_ref() != self.extrinsics_root.as_slice() {
+            return Err(Fault::ExtrinsicsRoot {
+                header: self.extrinsics_root.clone(),
+                computed: computed.as_ref().to_vec(),
+            });
+        }
+        Ok(())
+    }
+
+    pub(crate) fn quarantine(self, fault: &Fault) -> InsertQuarantine {
+        InsertQuarantine {
+            hash: self.link.hash,
+            block_num: self.link.block_num,
+            reason: fault.to_string(),
+            header: self.header,
+            extrinsics: self.extrinsics,
+            received_at: Utc::now(),
+        }
+    }
+}
+
+/// Check each of `blocks` against the finalized blocks next to it,
+/// whether they are `archived` or in `blocks` themselves
+/// The parent hash of a block must be the hash of the finalized block before it, and a finalized
+/// block must be the parent of the finalized block after it. Either side of a broken link fails
+pub(crate) fn check_links(blocks: &[Link], archived: &[Link]) -> Vec<Option<Fault>> {
+    let mut finalized: HashMap<i64, Vec<&Link>> = HashMap::new();
+    for link in archived.iter().chain(blocks.iter()).filter(|l| l.finalized) {
+        finalized.entry(link.block_num).or_default().push(link);
+    }
+    let at = |number: i64| finalized.get(&number).map(Vec::as_slice).unwrap_or(&[]);
+
+    blocks
+        .iter()
+        .map(|block| {
+            if let Some(parent) = at(block.block_num - 1)
+                .iter()
+                .find(|p| p.hash != block.parent_hash)
+            {
+                return Some(Fault::Parent {
+                    parent_hash: block.parent_hash.clone(),
+                    archived: parent.hash.clone(),
+                });
+            }
+            if !block.finalized {
+                return None;
+            }
+            at(block.block_num + 1)
+                .iter()
+                .find(|c| c.parent_hash != block.hash)
+                .map(|child| Fault::Hash {
+                    hash: block.hash.clone(),
+                    child_parent: child.parent_hash.clone(),
+                })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::Runtime;
+
+    fn link(block_num: i64, hash: u8, parent_hash: u8) -> Link {
+        Link {
+            block_num,
+            hash: vec![hash; 32],
+            parent_hash: vec![parent_hash; 32],
+            finalized: true,
+        }
+    }
+
+    #[test]
+    fn should_accept_blocks_that_extend_the_archive() {
+        let archived = vec![link(0, 0xa0, 0), link(1, 0xa1, 0xa0)];
+        let blocks = vec![link(2, 0xa2, 0xa1), link(3, 0xa3, 0xa2)];
+        assert_eq!(check_links(&blocks, &archived), vec![None, None]);
+    }
+
+    #[test]
+    fn should_reject_blocks_with_another_parent() {
+        let archived = vec![link(1, 0xa1, 0xa0)];
+        let blocks = vec![link(2, 0xa2, 0xff)];
+        assert_eq!(
+            check_links(&blocks, &archived),
+            vec![Some(Fault::Parent {
+                parent_hash: vec![0xff; 32],
+                archived: vec![0xa1; 32],
+            })]
+        );
+    }
+
+    #[test]
+    fn should_reject_blocks_that_are_not_the_parent_of_the_next() {
+        let archived = vec![link(3, 0xa3, 0xa2)];
+        let blocks = vec![link(2, 0xff, 0xa1)];
+        assert_eq!(
+            check_links(&blocks, &archived),
+            vec![Some(Fault::Hash {
+                hash: vec![0xff; 32],
+                child_parent: vec![0xa2; 32],
+            })]
+        );
+    }
+
+    #[test]
+    fn should_not_check_forks_against_the_next_block() {
+        let archived = vec![link(3, 0xa3, 0xa2)];
+        let mut fork = link(2, 0xf2, 0xa1);
+        fork.finalized = false;
+        assert_eq!(check_links(&[fork], &archived), vec![None]);
+    }
+
+    #[test]
+    fn should_check_the_extrinsics_root() {
+        let empty = Received {
+            link: link(0, 0xa0, 0),
+            requested: None,
+            extrinsics_root: <Runtime as System>::Hashing::ordered_trie_root(Vec::new())
+                .as_ref()
+                .to_vec(),
+            header: Vec::new(),
+            extrinsics: Vec::new(),
+        };
+        assert!(empty.check_body::<Runtime>().is_ok());
+
+        let tampered = Received {
+            extrinsics: vec![vec![4, 1]],
+            ..empty
+        };
+        assert!(tampered.check_body::<Runtime>().is_err());
+    }
+
+    #[test]
+    fn should_check_the_requested_hash() {
+        let block = Received {
+            link: link(0, 0xa0, 0),
+            requested: Some(vec![0xa0; 32]),
+            extrinsics_root: <Runtime as System>::Hashing::ordered_trie_root(Vec::new())
+                .as_ref()
+                .to_vec(),
+            header: Vec::new(),
+            extrinsics: Vec::new(),
+        };
+        assert!(block.check_body::<Runtime>().is_ok());
+
+        let substituted = Received {
+            requested: Some(vec![0xff; 32]),
+            ..block
+        };
+        assert_eq!(
+            substituted.check_body::<Runtime>(),
+            Err(Fault::RequestedHash {
+                requested: vec![0xff; 32],
+                computed: vec![0xa0; 32],
+            })
+        );
+    }
+}
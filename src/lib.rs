@@ -19,21 +19,32 @@
 #[macro_use]
 extern crate diesel;
 mod archive;
+mod chain;
+mod config;
 mod database;
+mod decode;
 mod error;
+mod events;
 mod extrinsics;
 mod frame_ext;
+mod integrity;
 mod metadata;
+mod metrics;
+mod progress;
 mod queries;
 #[cfg(test)]
 mod tests;
 mod types;
 mod util;
+mod verify;
 
-pub use archive::Archive;
+pub use archive::{Archive, ShutdownHandle};
+pub use chain::ChainInfo;
+pub use config::ArchiveConfig;
 pub use error::Error;
 pub use extrinsics::{OldExtrinsic, RawExtrinsic};
 pub use frame_ext::{FrameExt, NotHandled};
+pub use progress::{Phase, PhaseProgress, Progress};
 pub use types::{ExtractCall, Module, System, ToDatabaseExtrinsic};
 
 pub mod rpc;
@@ -24,13 +24,22 @@ use log::*;
 use runtime_metadata::RuntimeMetadataPrefixed;
 use substrate_primitives::storage::StorageKey;
 
-use std::{convert::TryFrom, fmt};
+use std::{collections::HashMap, convert::TryFrom, fmt};
 
-pub use self::subxt_metadata::{Error, Metadata as SubxtMetadata};
+pub use self::subxt_metadata::{
+    Error, EventArg, Metadata as SubxtMetadata, ModuleEventMetadata, StorageMetadata,
+};
 use crate::error::Error as ArchiveError;
 
+/// length of the prefix every key of a storage map starts with
+const MAP_PREFIX_LEN: usize = 32;
+
 pub struct Metadata {
     inner: SubxtMetadata,
+    /// plain storage entries, by their key
+    entries: HashMap<Vec<u8>, StorageMetadata>,
+    /// storage maps, by the prefix of their keys
+    maps: HashMap<Vec<u8>, StorageMetadata>,
 }
 
 impl fmt::Display for Metadata {
@@ -45,33 +54,73 @@ impl Metadata {
     }
 
     pub fn from_subxt(meta: SubxtMetadata) -> Metadata {
-        Metadata { inner: meta }
-    }
-
-    /// get storage keys for all possible values of storage for one block
-    pub fn keys(&self, keys: Vec<StorageKey>) -> Vec<StorageKey> {
-        let other_keys = Vec::new();
-        for module in self.inner.modules() {
+        let (mut entries, mut maps) = (HashMap::new(), HashMap::new());
+        for module in meta.modules() {
             trace!("MODULE: {:?}", module.name());
-            for (key, map) in module.storage_keys() {
-                trace!("STORAGE: {:?}", key);
-                trace!("STORAGE MAP {:?}", map);
-                trace!("Module: {:?}", module);
-                // trace!("Combined: {}", format!("{} {}", module.name(), key.as_str()));
-                /*
-                other_keys.push(
-                    twox_128(format!("{} {}", module.name(), call.as_str()).as_bytes()).to_vec()
-                )
-                */
+            for (name, entry) in module.storage_keys() {
+                trace!("STORAGE: {:?}", name);
+                if let Some(key) = entry.plain_key() {
+                    entries.insert(key.0, entry.clone());
+                } else if let Some(prefix) = entry.map_prefix() {
+                    maps.insert(prefix.0, entry.clone());
+                }
             }
         }
-        other_keys
-        /*
-                keys.into_iter().filter_map(|k| {
-                    other_keys.iter().find(|&&other| other == &&k.0)
-                        .map(|k| StorageKey(k.to_vec()))
-                }).collect::<Vec<StorageKey>>()
-        */
+        Metadata {
+            inner: meta,
+            entries,
+            maps,
+        }
+    }
+
+    /// get storage keys for all possible values of storage for one block
+    /// only plain storage values are known; the keys of maps cannot be derived from metadata,
+    /// they are enumerated at each block by the prefixes of `map_prefixes`
+    pub fn keys(&self) -> Vec<StorageKey> {
+        self.entries
+            .keys()
+            .map(|k| StorageKey(k.clone()))
+            .collect::<Vec<StorageKey>>()
+    }
+
+    /// the prefix the keys of each storage map start with
+    pub fn map_prefixes(&self) -> Vec<StorageKey> {
+        self.maps
+            .keys()
+            .map(|k| StorageKey(k.clone()))
+            .collect::<Vec<StorageKey>>()
+    }
+
+    /// key of `System.Events`, where each block stores the events it deposited
+    pub fn events_key(&self) -> Option<StorageKey> {
+        self.inner
+            .module("System")
+            .ok()?
+            .storage("Events")
+            .ok()?
+            .plain_key()
+    }
+
+    /// module name and metadata of an event, by the index of its module in the outer event enum
+    /// and its index within the module
+    pub fn event(
+        &self,
+        module_index: u8,
+        event_index: u8,
+    ) -> Option<(String, &ModuleEventMetadata)> {
+        let module = self.inner.module_name(module_index).ok()?;
+        let event = self.inner.module(&module).ok()?.event(event_index).ok()?;
+        Some((module, event))
+    }
+
+    /// metadata of the storage entry at `key`, if it is a known plain storage value
+    /// or an entry of a known map
+    pub fn entry(&self, key: &StorageKey) -> Option<&StorageMetadata> {
+        self.entries.get(&key.0).or_else(|| {
+            key.0
+                .get(..MAP_PREFIX_LEN)
+                .and_then(|prefix| self.maps.get(prefix))
+        })
     }
 }
 
@@ -80,7 +129,7 @@ impl TryFrom<RuntimeMetadataPrefixed> for Metadata {
 
     fn try_from(metadata: RuntimeMetadataPrefixed) -> Result<Self, Self::Error> {
         let metadata = SubxtMetadata::try_from(metadata).map_err(|e| ArchiveError::from(e))?;
-        Ok(Self { inner: metadata })
+        Ok(Self::from_subxt(metadata))
     }
 }
 
@@ -145,4 +194,43 @@ mod tests {
         meta.keys(vec![some_key]).unwrap()
     }
     */
+
+    fn map_and_value() -> Metadata {
+        use runtime_metadata::{DecodeDifferent, StorageEntryType, StorageHasher};
+        Metadata::from_subxt(SubxtMetadata::with_storage(vec![
+            (
+                "Balances FreeBalance",
+                StorageEntryType::Map {
+                    hasher: StorageHasher::Blake2_256,
+                    key: DecodeDifferent::Decoded("T::AccountId".to_string()),
+                    value: DecodeDifferent::Decoded("T::Balance".to_string()),
+                    is_linked: false,
+                },
+            ),
+            (
+                "Timestamp Now",
+                StorageEntryType::Plain(DecodeDifferent::Decoded("T::Moment".to_string())),
+            ),
+        ]))
+    }
+
+    #[test]
+    fn should_prefix_map_keys_with_module_and_entry() {
+        let metadata = map_and_value();
+        let mut prefix = substrate_primitives::twox_128(b"Balances").to_vec();
+        prefix.extend_from_slice(&substrate_primitives::twox_128(b"FreeBalance"));
+        assert_eq!(metadata.map_prefixes(), vec![StorageKey(prefix.clone())]);
+        assert_eq!(
+            metadata.keys(),
+            vec![StorageKey(
+                substrate_primitives::twox_128(b"Timestamp Now").to_vec()
+            )]
+        );
+
+        let mut key = prefix;
+        key.extend_from_slice(&[1; 32]);
+        let entry = metadata.entry(&StorageKey(key)).unwrap();
+        assert_eq!((entry.module(), entry.name()), ("Balances", "FreeBalance"));
+        assert!(metadata.entry(&StorageKey(vec![1; 48])).is_none());
+    }
 }
@@ -24,12 +24,7 @@ use runtime_metadata::{
     DecodeDifferent, RuntimeMetadata, RuntimeMetadataPrefixed, StorageEntryModifier,
     StorageEntryType, StorageHasher, META_RESERVED,
 };
-use std::{
-    collections::{HashMap, HashSet},
-    convert::TryFrom,
-    marker::PhantomData,
-    str::FromStr,
-};
+use std::{collections::HashMap, convert::TryFrom, fmt, marker::PhantomData, str::FromStr};
 use substrate_primitives::storage::StorageKey;
 
 #[derive(Clone)]
@@ -109,6 +104,36 @@ impl Metadata {
     }
 }
 
+#[cfg(test)]
+impl Metadata {
+    /// metadata of a runtime with only the storage `entries`, each named "Module Entry"
+    pub(crate) fn with_storage(entries: Vec<(&str, StorageEntryType)>) -> Self {
+        let mut modules = HashMap::new();
+        for (prefix, ty) in entries.into_iter() {
+            let entry = StorageMetadata {
+                prefix: prefix.to_string(),
+                modifier: StorageEntryModifier::Default,
+                ty,
+                default: Vec::new(),
+            };
+            let module = modules
+                .entry(entry.module().to_string())
+                .or_insert_with(|| ModuleMetadata {
+                    index: 0,
+                    name: entry.module().to_string(),
+                    storage: HashMap::new(),
+                    calls: HashMap::new(),
+                    events: HashMap::new(),
+                });
+            module.storage.insert(entry.name().to_string(), entry);
+        }
+        Metadata {
+            modules,
+            modules_by_event_index: HashMap::new(),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct ModuleMetadata {
     index: u8,
@@ -174,6 +199,51 @@ pub struct StorageMetadata {
 }
 
 impl StorageMetadata {
+    /// name of the module this storage entry belongs to
+    pub fn module(&self) -> &str {
+        self.prefix.splitn(2, ' ').next().unwrap_or("")
+    }
+
+    /// name of the storage entry, without the module
+    pub fn name(&self) -> &str {
+        self.prefix.splitn(2, ' ').nth(1).unwrap_or("")
+    }
+
+    /// type of the values stored under this entry
+    pub fn value_type(&self) -> Result<EventArg, Error> {
+        let ty = match &self.ty {
+            StorageEntryType::Plain(value) => value,
+            StorageEntryType::Map { value, .. } => value,
+            StorageEntryType::DoubleMap { value, .. } => value,
+        };
+        convert(ty.clone())?.parse()
+    }
+
+    /// The key of a plain storage value
+    /// `None` if this entry is a map, whose keys cannot be known from metadata alone
+    pub fn plain_key(&self) -> Option<StorageKey> {
+        match &self.ty {
+            StorageEntryType::Plain(_) => Some(StorageKey(
+                substrate_primitives::twox_128(self.prefix.as_bytes()).to_vec(),
+            )),
+            _ => None,
+        }
+    }
+
+    /// The prefix the keys of every entry of a map start with: `twox_128` of the module name,
+    /// then `twox_128` of the entry name, as runtimes with prefixed storage lay out map keys
+    /// `None` if this entry is a plain storage value
+    pub fn map_prefix(&self) -> Option<StorageKey> {
+        match &self.ty {
+            StorageEntryType::Plain(_) => None,
+            _ => {
+                let mut prefix = substrate_primitives::twox_128(self.module().as_bytes()).to_vec();
+                prefix.extend_from_slice(&substrate_primitives::twox_128(self.name().as_bytes()));
+                Some(StorageKey(prefix))
+            }
+        }
+    }
+
     pub fn get_map<K: Encode, V: Decode + Clone>(&self) -> Result<StorageMap<K, V>, MetadataError> {
         match &self.ty {
             StorageEntryType::Map { hasher, .. } => {
@@ -223,12 +293,13 @@ impl<K: Encode, V: Decode + Clone> StorageMap<K, V> {
 #[derive(Clone, Debug)]
 pub struct ModuleEventMetadata {
     pub name: String,
-    arguments: HashSet<EventArg>,
+    /// in the order they are encoded
+    arguments: Vec<EventArg>,
 }
 
 impl ModuleEventMetadata {
-    pub fn arguments(&self) -> Vec<EventArg> {
-        self.arguments.iter().cloned().collect()
+    pub fn arguments(&self) -> &[EventArg] {
+        &self.arguments
     }
 }
 
@@ -277,6 +348,19 @@ impl FromStr for EventArg {
     }
 }
 
+impl fmt::Display for EventArg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EventArg::Primitive(p) => write!(f, "{}", p),
+            EventArg::Vec(arg) => write!(f, "Vec<{}>", arg),
+            EventArg::Tuple(args) => {
+                let args = args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+                write!(f, "({})", args.join(", "))
+            }
+        }
+    }
+}
+
 impl EventArg {
     /// Returns all primitive types for this EventArg
     pub fn primitives(&self) -> Vec<String> {
@@ -383,10 +467,10 @@ fn convert_module(
 
 fn convert_event(event: runtime_metadata::EventMetadata) -> Result<ModuleEventMetadata, Error> {
     let name = convert(event.name)?;
-    let mut arguments = HashSet::new();
+    let mut arguments = Vec::new();
     for arg in convert(event.arguments)? {
         let arg = arg.parse::<EventArg>()?;
-        arguments.insert(arg);
+        arguments.push(arg);
     }
     Ok(ModuleEventMetadata { name, arguments })
 }
@@ -0,0 +1,175 @@
+// Copyright 2017-2019 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Prometheus metrics of the archive, served over HTTP at `/metrics`
+
+use futures::Future;
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server, StatusCode,
+};
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge, Encoder, HistogramTimer,
+    HistogramVec, IntCounterVec, IntGauge, TextEncoder,
+};
+
+use std::net::SocketAddr;
+
+use crate::error::Error as ArchiveError;
+
+lazy_static! {
+    static ref BLOCKS: IntCounterVec = register_int_counter_vec!(
+        "archive_blocks_total",
+        "Blocks that went through each stage of the archive",
+        &["stage"]
+    )
+    .expect("Metric is only registered once; qed");
+    static ref EXTRINSICS: IntCounterVec = register_int_counter_vec!(
+        "archive_extrinsics_decoded_total",
+        "Extrinsics decoded, by whether decoding succeeded",
+        &["result"]
+    )
+    .expect("Metric is only registered once; qed");
+    static ref STORAGE_VERIFIED: IntCounterVec = register_int_counter_vec!(
+        "archive_storage_verified_total",
+        "Archived storage values checked against a read proof, by whether they matched",
+        &["result"]
+    )
+    .expect("Metric is only registered once; qed");
+    static ref RPC_LATENCY: HistogramVec = register_histogram_vec!(
+        "archive_rpc_duration_seconds",
+        "Latency of requests to the node, by RPC method",
+        &["method"]
+    )
+    .expect("Metric is only registered once; qed");
+    static ref DB_LATENCY: HistogramVec = register_histogram_vec!(
+        "archive_db_insert_duration_seconds",
+        "Latency of inserts into the database, by table",
+        &["table"]
+    )
+    .expect("Metric is only registered once; qed");
+    static ref CHANNEL_DEPTH: IntGauge = register_int_gauge!(
+        "archive_channel_depth",
+        "Items received from the node that are waiting to be inserted"
+    )
+    .expect("Metric is only registered once; qed");
+    static ref CHAIN_HEAD: IntGauge = register_int_gauge!(
+        "archive_chain_head",
+        "Latest finalized block number reported by the node"
+    )
+    .expect("Metric is only registered once; qed");
+    static ref ARCHIVED_HEAD: IntGauge = register_int_gauge!(
+        "archive_highest_block",
+        "Highest block number committed to the database"
+    )
+    .expect("Metric is only registered once; qed");
+}
+
+pub(crate) fn blocks_fetched(count: usize) {
+    BLOCKS.with_label_values(&["fetched"]).inc_by(count as i64);
+}
+
+pub(crate) fn blocks_decoded(count: usize) {
+    BLOCKS.with_label_values(&["decoded"]).inc_by(count as i64);
+}
+
+/// `highest` is the largest block number among the inserted blocks
+pub(crate) fn blocks_inserted(count: usize, highest: Option<u64>) {
+    BLOCKS.with_label_values(&["inserted"]).inc_by(count as i64);
+    if let Some(highest) = highest {
+        if highest as i64 > ARCHIVED_HEAD.get() {
+            ARCHIVED_HEAD.set(highest as i64);
+        }
+    }
+}
+
+pub(crate) fn blocks_quarantined(count: usize) {
+    BLOCKS
+        .with_label_values(&["quarantined"])
+        .inc_by(count as i64);
+}
+
+pub(crate) fn extrinsic_decoded(ok: bool) {
+    let result = if ok { "ok" } else { "failed" };
+    EXTRINSICS.with_label_values(&[result]).inc();
+}
+
+pub(crate) fn storage_verified(verified: usize, mismatched: usize) {
+    STORAGE_VERIFIED
+        .with_label_values(&["ok"])
+        .inc_by(verified as i64);
+    STORAGE_VERIFIED
+        .with_label_values(&["mismatch"])
+        .inc_by(mismatched as i64);
+}
+
+/// `number` must be a finalized block; best blocks may still be reorged out
+pub(crate) fn chain_head(number: u64) {
+    if number as i64 > CHAIN_HEAD.get() {
+        CHAIN_HEAD.set(number as i64);
+    }
+}
+
+pub(crate) fn channel_push() {
+    CHANNEL_DEPTH.inc();
+}
+
+pub(crate) fn channel_pop() {
+    CHANNEL_DEPTH.dec();
+}
+
+/// Time an RPC request
+pub(crate) async fn time_rpc<F: Future>(method: &'static str, request: F) -> F::Output {
+    let _timer = RPC_LATENCY.with_label_values(&[method]).start_timer();
+    request.await
+}
+
+/// Time an insert into `table`; the latency is recorded when the timer is dropped
+pub(crate) fn time_insert(table: &str) -> HistogramTimer {
+    DB_LATENCY.with_label_values(&[table]).start_timer()
+}
+
+/// Serve every registered metric at `http://<addr>/metrics`
+pub(crate) async fn serve(addr: SocketAddr) -> Result<(), ArchiveError> {
+    let service = make_service_fn(|_| async { Ok::<_, hyper::Error>(service_fn(respond)) });
+    log::info!("Serving metrics on http://{}/metrics", addr);
+    Server::bind(&addr).serve(service).await?;
+    Ok(())
+}
+
+async fn respond(req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    if req.uri().path() != "/metrics" {
+        let mut not_found = Response::new(Body::empty());
+        *not_found.status_mut() = StatusCode::NOT_FOUND;
+        return Ok(not_found);
+    }
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        log::error!("{:?}", e);
+    }
+    let mut response = Response::new(Body::from(buffer));
+    response.headers_mut().insert(
+        CONTENT_TYPE,
+        encoder
+            .format_type()
+            .parse()
+            .expect("Prometheus content type is a valid header; qed"),
+    );
+    Ok(response)
+}
@@ -0,0 +1,172 @@
+// Copyright 2017-2019 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Progress of the historical sync
+//! The same numbers are persisted in the `sync_state` table, so sync can resume after a restart
+
+use chrono::{DateTime, Utc};
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+/// The parts of the chain that are archived separately
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, derive_more::Display)]
+pub enum Phase {
+    Blocks,
+    Extrinsics,
+    Storage,
+    Events,
+    ChildStorage,
+}
+
+impl Phase {
+    pub fn all() -> [Phase; 5] {
+        [
+            Phase::Blocks,
+            Phase::Extrinsics,
+            Phase::Storage,
+            Phase::Events,
+            Phase::ChildStorage,
+        ]
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Phase::Blocks => "blocks",
+            Phase::Extrinsics => "extrinsics",
+            Phase::Storage => "storage",
+            Phase::Events => "events",
+            Phase::ChildStorage => "child_storage",
+        }
+    }
+
+    pub fn from_name(phase: &str) -> Option<Phase> {
+        match phase {
+            "blocks" => Some(Phase::Blocks),
+            "extrinsics" => Some(Phase::Extrinsics),
+            "storage" => Some(Phase::Storage),
+            "events" => Some(Phase::Events),
+            "child_storage" => Some(Phase::ChildStorage),
+            _ => None,
+        }
+    }
+}
+
+/// How far along one phase of sync is
+#[derive(Debug, PartialEq, Clone)]
+pub struct PhaseProgress {
+    pub phase: Phase,
+    /// every block up to and including this one has been archived for this phase
+    pub completed_to: u64,
+    /// the latest finalized block when this phase was last checked
+    pub target: u64,
+    /// blocks completed per second since the previous update
+    pub rate: f64,
+    /// estimated time until `target` is reached, if any progress is being made
+    pub eta: Option<Duration>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl PhaseProgress {
+    pub fn new(phase: Phase) -> Self {
+        Self {
+            phase,
+            completed_to: 0,
+            target: 0,
+            rate: 0.0,
+            eta: None,
+            updated_at: Utc::now(),
+        }
+    }
+
+    /// progress after completing every block up to `completed_to`
+    pub(crate) fn advance(&self, completed_to: u64, target: u64) -> Self {
+        let now = Utc::now();
+        let completed_to = std::cmp::max(self.completed_to, completed_to);
+        let elapsed = (now - self.updated_at).num_milliseconds() as f64 / 1000.0;
+        let rate = if elapsed > 0.0 {
+            (completed_to - self.completed_to) as f64 / elapsed
+        } else {
+            self.rate
+        };
+        let remaining = target.saturating_sub(completed_to);
+        let eta = if remaining == 0 {
+            Some(Duration::from_secs(0))
+        } else if rate > 0.0 {
+            Some(Duration::from_secs((remaining as f64 / rate) as u64))
+        } else {
+            None
+        };
+        Self {
+            phase: self.phase,
+            completed_to,
+            target,
+            rate,
+            eta,
+            updated_at: now,
+        }
+    }
+}
+
+/// Shared view of sync progress, readable while the Archive is running
+#[derive(Debug, Clone, Default)]
+pub struct Progress {
+    inner: Arc<RwLock<HashMap<Phase, PhaseProgress>>>,
+}
+
+impl Progress {
+    pub(crate) fn new(phases: Vec<PhaseProgress>) -> Self {
+        let progress = Progress::default();
+        for p in phases.into_iter() {
+            progress.set(p);
+        }
+        progress
+    }
+
+    /// progress of one phase
+    pub fn phase(&self, phase: Phase) -> PhaseProgress {
+        self.inner
+            .read()
+            .expect("Lock is never poisoned; qed")
+            .get(&phase)
+            .cloned()
+            .unwrap_or_else(|| PhaseProgress::new(phase))
+    }
+
+    /// progress of one phase, if a checkpoint was ever saved for it
+    pub(crate) fn saved(&self, phase: Phase) -> Option<PhaseProgress> {
+        self.inner
+            .read()
+            .expect("Lock is never poisoned; qed")
+            .get(&phase)
+            .cloned()
+    }
+
+    /// progress of every phase
+    pub fn all(&self) -> Vec<PhaseProgress> {
+        Phase::all().iter().map(|p| self.phase(*p)).collect()
+    }
+
+    pub(crate) fn set(&self, progress: PhaseProgress) {
+        self.inner
+            .write()
+            .expect("Lock is never poisoned; qed")
+            .insert(progress.phase, progress);
+    }
+}
@@ -15,29 +15,159 @@
 // along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
 
 //! Common Sql queries on Archive Database abstracted into rust functions
-pub(crate) fn missing_blocks(latest: Option<u64>) -> diesel::query_builder::SqlQuery {
+use diesel::{
+    pg::Pg,
+    query_builder::{BoxedSqlQuery, SqlQuery},
+    sql_types::{Array, BigInt, Bytea, Integer, Text},
+};
+
+type BoxedQuery<'a> = BoxedSqlQuery<'a, Pg, SqlQuery>;
+
+/// Block numbers from `from` to `latest` that have no finalized, canonical block in the database
+/// If `latest` is `None`, up to the largest block in the database
+pub(crate) fn missing_blocks(from: u64, latest: Option<u64>) -> diesel::query_builder::SqlQuery {
     let query = if let Some(latest) = latest {
         let q = format!(
             "
 SELECT generate_series
-FROM generate_series('0'::bigint, '{}'::bigint)
+FROM generate_series('{}'::bigint, '{}'::bigint)
 WHERE
-NOT EXISTS(SELECT id FROM blocks WHERE block_num = generate_series)",
-            latest
+NOT EXISTS(SELECT id FROM blocks WHERE block_num = generate_series AND finalized AND canonical)",
+            from, latest
         );
         q
     } else {
         // take largest block from the db
-        "SELECT generate_series
-FROM (SELECT 0 as a, max(block_num) as z FROM blocks) x, generate_series(a, z)
+        format!(
+            "SELECT generate_series
+FROM (SELECT '{}'::bigint as a, max(block_num) as z FROM blocks) x, generate_series(a, z)
 WHERE
-NOT EXISTS(SELECT id FROM blocks WHERE block_num = generate_series)"
-            .to_string()
+NOT EXISTS(SELECT id FROM blocks WHERE block_num = generate_series AND finalized AND canonical)",
+            from
+        )
     };
 
     diesel::sql_query(&query)
 }
 
+/// The highest block number up to `to` such that every block from `from` up to it has its
+/// finalized, canonical block in the database
+pub(crate) fn contiguous_to(from: u64, to: u64) -> diesel::query_builder::SqlQuery {
+    let query = format!(
+        "
+SELECT COALESCE(MIN(generate_series) - 1, '{to}'::bigint) AS block_num
+FROM generate_series('{from}'::bigint, '{to}'::bigint)
+WHERE
+NOT EXISTS(SELECT id FROM blocks WHERE block_num = generate_series AND finalized AND canonical)",
+        from = from,
+        to = to
+    );
+    diesel::sql_query(&query)
+}
+
+/// Walk back from `parent` through blocks that are not canonical,
+/// stopping at the first canonical block (the fork point)
+/// Rows are ordered from `parent` to the fork point
+pub(crate) fn fork_path(parent: &[u8]) -> BoxedQuery<'_> {
+    diesel::sql_query(
+        "
+WITH RECURSIVE path AS (
+    SELECT hash, parent_hash, block_num, canonical, fork FROM blocks WHERE hash = $1
+  UNION ALL
+    SELECT b.hash, b.parent_hash, b.block_num, b.canonical, b.fork
+    FROM blocks b JOIN path p ON b.hash = p.parent_hash
+    WHERE NOT p.canonical
+)
+SELECT hash, block_num, canonical, fork FROM path ORDER BY block_num DESC",
+    )
+    .into_boxed()
+    .bind::<Bytea, _>(parent)
+}
+
+/// Mark every unfinalized block at `block_num` other than `hash`, and all of their descendants,
+/// as no longer canonical
+pub(crate) fn abandon_siblings(block_num: i64, hash: &[u8]) -> BoxedQuery<'_> {
+    diesel::sql_query(
+        "
+WITH RECURSIVE abandoned AS (
+    SELECT hash FROM blocks WHERE block_num = $1 AND hash <> $2 AND NOT finalized
+  UNION ALL
+    SELECT b.hash FROM blocks b JOIN abandoned a ON b.parent_hash = a.hash
+)
+UPDATE blocks SET canonical = false WHERE hash IN (SELECT hash FROM abandoned)",
+    )
+    .into_boxed()
+    .bind::<BigInt, _>(block_num)
+    .bind::<Bytea, _>(hash)
+}
+
+/// Link extrinsics seen in the transaction pool that are not linked to a block yet to the
+/// canonical block that included them, if it is archived
+pub(crate) fn link_archived_pending() -> SqlQuery {
+    diesel::sql_query(
+        "
+UPDATE pending_extrinsics p SET included_in = b.hash
+FROM signed_extrinsics s JOIN blocks b ON b.hash = s.hash
+WHERE p.included_in IS NULL AND s.transaction_hash = p.hash AND b.canonical",
+    )
+}
+
+/// Get a new id for a fork
+pub(crate) fn next_fork() -> SqlQuery {
+    diesel::sql_query("SELECT nextval('fork_seq') AS fork")
+}
+
+/// Storage values of `keys` that have not been archived for finalized, canonical blocks
+/// Only blocks from `from` up to `to` are checked
+pub(crate) fn missing_storage(
+    keys: Vec<Vec<u8>>,
+    from: i64,
+    to: i64,
+    limit: i64,
+) -> BoxedQuery<'static> {
+    diesel::sql_query(
+        "
+SELECT b.block_num, b.hash, k.key
+FROM blocks b CROSS JOIN unnest($1) AS k(key)
+WHERE b.canonical AND b.finalized AND b.block_num >= $2 AND b.block_num <= $3
+AND NOT EXISTS(SELECT 1 FROM storage s WHERE s.hash = b.hash AND s.key = k.key)
+ORDER BY b.block_num ASC
+LIMIT $4",
+    )
+    .into_boxed()
+    .bind::<Array<Bytea>, _>(keys)
+    .bind::<BigInt, _>(from)
+    .bind::<BigInt, _>(to)
+    .bind::<BigInt, _>(limit)
+}
+
+/// Record that blocks `from_block` to `to_block` were executed with `spec_version`
+/// If the version is already known, its range is widened to cover these blocks
+pub(crate) fn upsert_runtime_version(
+    spec_version: i32,
+    spec_name: &str,
+    from_block: i64,
+    to_block: i64,
+    from_hash: &[u8],
+) -> BoxedQuery<'static> {
+    diesel::sql_query(
+        "
+INSERT INTO runtime_versions (spec_version, spec_name, from_block, to_block, from_hash)
+VALUES ($1, $2, $3, $4, $5)
+ON CONFLICT (spec_version) DO UPDATE SET
+  from_hash = CASE WHEN EXCLUDED.from_block < runtime_versions.from_block
+    THEN EXCLUDED.from_hash ELSE runtime_versions.from_hash END,
+  from_block = LEAST(runtime_versions.from_block, EXCLUDED.from_block),
+  to_block = GREATEST(runtime_versions.to_block, EXCLUDED.to_block)",
+    )
+    .into_boxed()
+    .bind::<Integer, _>(spec_version)
+    .bind::<Text, _>(spec_name.to_string())
+    .bind::<BigInt, _>(from_block)
+    .bind::<BigInt, _>(to_block)
+    .bind::<Bytea, _>(from_hash.to_vec())
+}
+
 // Get the latest block in the database
 // this might not be up-to-date right as the node starts,
 // but will soon start collecting the latest heads
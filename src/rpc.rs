@@ -14,72 +14,407 @@
 // You should have received a copy of the GNU General Public License
 // along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
 
+mod batch;
+mod endpoint;
+#[cfg(test)]
+pub(crate) mod mock;
+mod record;
+mod replay;
+mod retry;
 mod substrate_rpc;
-use self::substrate_rpc::SubstrateRpc;
+use self::endpoint::{check_genesis, Endpoint};
+pub(crate) use self::record::Recorder;
+pub(crate) use self::replay::ReplayNode;
+pub(crate) use self::substrate_rpc::RequestOptions;
+use self::substrate_rpc::{SubstrateRpc, CHILD_STORAGE_PREFIX};
 
+use chrono::Utc;
 use futures::{
-    channel::mpsc::UnboundedSender,
-    future::{self, FutureExt, TryFutureExt},
+    channel::mpsc::Sender,
+    future::{self, FutureExt},
+    sink::SinkExt,
     stream::StreamExt,
 };
-use log::{debug, error, trace, warn};
-use runtime_primitives::traits::Header as HeaderTrait;
-use substrate_primitives::{storage::StorageKey, twox_128};
-// use substrate_rpc_api::system::Properties;
+use log::{debug, error, info, trace, warn};
+use runtime_primitives::traits::{Hash as HashT, Header as HeaderTrait};
+use substrate_primitives::{
+    storage::{StorageData, StorageKey},
+    twox_128,
+};
 use substrate_rpc_primitives::{list::ListOrValue, number::NumberOrHex};
+use tokio::time;
 
+use std::collections::{HashMap, HashSet};
 use std::marker::PhantomData;
-use std::sync::Arc;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, RwLock,
+};
+use std::time::Duration;
 
 use crate::{
+    chain::ChainInfo,
     error::Error as ArchiveError,
+    events::DbEvent,
+    extrinsics,
     metadata::Metadata,
-    types::{BatchBlock, Block, Data, Header, Storage, SubstrateBlock, System},
+    metrics,
+    types::{
+        BatchBlock, BestBlock, Block, ChildStorage, Data, Event, Header, PendingExtrinsics,
+        RuntimeVersionRange, Storage, SubstrateBlock, System,
+    },
 };
 
-/// Communicate with Substrate node via RPC
+/// What a batch of requests returned: everything that was fetched,
+/// and each item that still failed after retrying, with its error
+#[derive(Debug)]
+pub struct Batch<K, V> {
+    pub fetched: Vec<V>,
+    pub failed: Vec<(K, ArchiveError)>,
+}
+
+impl<K: std::fmt::Debug, V> Batch<K, V> {
+    /// log every failed item, returning what was fetched
+    pub fn log_failed(self) -> Vec<V> {
+        for (item, e) in self.failed.iter() {
+            error!("Failed to fetch {:?}: {:?}", item, e);
+        }
+        self.fetched
+    }
+}
+
+fn not_found(number: u64) -> ArchiveError {
+    ArchiveError::DataNotFound(format!("Block {}", number))
+}
+
+/// key of `System.Events` in this runtime
+fn events_key(metadata: &Metadata) -> Result<StorageKey, ArchiveError> {
+    metadata
+        .events_key()
+        .ok_or_else(|| ArchiveError::DataNotFound("System.Events in metadata".to_string()))
+}
+
+/// root of every child trie at block `hash`, by the top-level key it is stored under
+async fn child_roots<T: System>(
+    client: &SubstrateRpc<T>,
+    hash: T::Hash,
+) -> Result<HashMap<StorageKey, StorageData>, ArchiveError> {
+    let prefix = StorageKey(CHILD_STORAGE_PREFIX.to_vec());
+    let children = client.storage_keys(prefix, Some(hash)).await?;
+    let roots =
+        future::try_join_all(children.iter().map(|c| client.storage(c.clone(), hash))).await?;
+    Ok(children
+        .into_iter()
+        .zip(roots.into_iter())
+        .filter_map(|(child, root)| Some((child, root?)))
+        .collect())
+}
+
+/// first wait before reconnecting once every node is down
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+/// longest wait between attempts to reconnect
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Communicate with Substrate nodes via RPC
+/// Subscriptions use the first healthy node, in the order they were configured;
+/// historical block requests are spread over every healthy node
 pub struct Rpc<T: System> {
     _marker: PhantomData<T>,
-    url: url::Url,
+    endpoints: Vec<Endpoint<T>>,
+    /// every node must be on the chain with this genesis hash
+    genesis: T::Hash,
+    /// endpoint the next balanced request starts looking from
+    next: AtomicUsize,
     keys: Vec<StorageKey>,
-    metadata: Metadata,
-    // properties: Properties,
+    /// spec version and metadata of the latest runtime
+    latest: RwLock<(u32, Arc<Metadata>)>,
+    /// metadata of every runtime seen so far, by spec version
+    runtimes: RwLock<HashMap<u32, Arc<Metadata>>>,
+    /// name and properties of the chain
+    chain: ChainInfo,
 }
 
 impl<T> Rpc<T>
 where
     T: System,
 {
-    pub fn metadata(&self) -> &Metadata {
-        &self.metadata
+    /// metadata of the latest runtime
+    pub fn metadata(&self) -> Arc<Metadata> {
+        self.latest
+            .read()
+            .expect("Lock is never poisoned; qed")
+            .1
+            .clone()
+    }
+
+    /// spec version of the latest runtime
+    pub fn spec_version(&self) -> u32 {
+        self.latest.read().expect("Lock is never poisoned; qed").0
+    }
+
+    /// metadata of a runtime that has been seen on the chain
+    pub fn metadata_at_version(&self, spec_version: u32) -> Option<Arc<Metadata>> {
+        self.runtimes
+            .read()
+            .expect("Lock is never poisoned; qed")
+            .get(&spec_version)
+            .cloned()
     }
 
     pub fn keys(&self) -> &Vec<StorageKey> {
         &self.keys
     }
-    /*
-        pub fn properties(&self) -> &Properties {
-            &self.properties
-        }
-    */
+
+    /// genesis hash, name and properties of the chain
+    pub fn chain_info(&self) -> &ChainInfo {
+        &self.chain
+    }
 }
 
 impl<T> Rpc<T>
 where
     T: System,
 {
-    /// subscribes to new heads but sends blocks and timestamps instead of headers
+    /// subscribes to finalized heads but sends blocks and timestamps instead of headers
+    /// If the connection drops, the subscription is re-established once the node is back,
+    /// and any finalized blocks that were missed in between are fetched
     pub async fn subscribe_blocks(
         self: Arc<Self>,
-        sender: UnboundedSender<Data<T>>,
+        mut sender: Sender<Data<T>>,
     ) -> Result<(), ArchiveError> {
-        let client = Arc::new(self.client().await?);
-        let mut stream = client.subscribe_finalized_heads().await?;
-        while let Some(head) = stream.next().await {
-            log::info!("Got Head: {:?}", head);
-            self.clone()
-                .block(Some(head?.hash()), sender.clone())
+        let mut last = None;
+        loop {
+            let client = self.client().await?;
+            let mut stream = match client.subscribe_finalized_heads().await {
+                Ok(s) => s,
+                Err(e) => {
+                    warn!("Could not subscribe to finalized heads: {:?}", e);
+                    time::delay_for(INITIAL_BACKOFF).await;
+                    continue;
+                }
+            };
+            while let Some(head) = stream.next().await {
+                log::info!("Got Head: {:?}", head);
+                let head = match head {
+                    Ok(h) => h,
+                    Err(e) => {
+                        warn!("{:?}", e);
+                        break;
+                    }
+                };
+                let number: u64 = (*head.number()).into();
+                metrics::chain_head(number);
+                self.fill_gap(last, number, &mut sender, false).await?;
+                last = Some(number);
+                self.clone()
+                    .block(Some(head.hash()), sender.clone())
+                    .await?;
+                for version in self.runtime_versions(vec![(number, head.hash())]).await? {
+                    Self::send(&mut sender, Data::RuntimeVersion(version)).await?;
+                }
+            }
+            warn!("Finalized heads subscription ended, resubscribing");
+        }
+    }
+
+    /// subscribes to runtime upgrades, refreshing the metadata each time the spec version changes
+    /// Like `subscribe_blocks`, resubscribes if the connection drops
+    pub async fn subscribe_runtime_versions(self: Arc<Self>) -> Result<(), ArchiveError> {
+        loop {
+            let client = self.client().await?;
+            let mut stream = match client.subscribe_runtime_version().await {
+                Ok(s) => s,
+                Err(e) => {
+                    warn!("Could not subscribe to runtime versions: {:?}", e);
+                    time::delay_for(INITIAL_BACKOFF).await;
+                    continue;
+                }
+            };
+            while let Some(version) = stream.next().await {
+                let version = match version {
+                    Ok(v) => v,
+                    Err(e) => {
+                        warn!("{:?}", e);
+                        break;
+                    }
+                };
+                if version.spec_version != self.spec_version() {
+                    info!(
+                        "Runtime upgraded to {} version {}, refreshing metadata",
+                        version.spec_name, version.spec_version
+                    );
+                    self.refresh_metadata(None).await?;
+                }
+            }
+            warn!("Runtime version subscription ended, resubscribing");
+        }
+    }
+
+    /// subscribes to changes of `System.Events`, sending the decoded events of each block
+    /// Notifications are for new best blocks; events of blocks that are not archived yet
+    /// are skipped on insert, and archived once the historical sync crawls them
+    /// Only websocket nodes can be subscribed to; while connected over HTTP, events are
+    /// only crawled
+    pub async fn subscribe_events(
+        self: Arc<Self>,
+        mut sender: Sender<Data<T>>,
+    ) -> Result<(), ArchiveError> {
+        loop {
+            let client = self.client().await?;
+            if !client.can_subscribe() {
+                debug!("Connected over HTTP, not subscribing to events");
+                time::delay_for(MAX_BACKOFF).await;
+                continue;
+            }
+            let key = events_key(&self.metadata())?;
+            let mut stream = match client.subscribe_storage(vec![key.clone()]).await {
+                Ok(s) => s,
+                Err(e) => {
+                    warn!("Could not subscribe to events: {:?}", e);
+                    time::delay_for(INITIAL_BACKOFF).await;
+                    continue;
+                }
+            };
+            while let Some(change_set) = stream.next().await {
+                let change_set = match change_set {
+                    Ok(c) => c,
+                    Err(e) => {
+                        warn!("{:?}", e);
+                        break;
+                    }
+                };
+                let metadata = self.metadata();
+                for (_, data) in change_set.changes.iter().filter(|(k, _)| *k == key) {
+                    let events = match data {
+                        Some(data) => DbEvent::decode::<T>(data, &metadata, &self.chain),
+                        None => Ok(Vec::new()),
+                    };
+                    match events {
+                        Ok(events) => {
+                            let events = Event::new(change_set.block, events);
+                            Self::send(&mut sender, Data::Event(events)).await?;
+                        }
+                        Err(e) => warn!("Could not decode events of {}: {:?}", change_set.block, e),
+                    }
+                }
+            }
+            warn!("Events subscription ended, resubscribing");
+        }
+    }
+
+    /// checks the transaction pool every `interval`, sending every extrinsic that was not in
+    /// the pool at the last check
+    /// The time an extrinsic is first sent is kept as the time it was first seen
+    pub async fn poll_pending_extrinsics(
+        self: Arc<Self>,
+        mut sender: Sender<Data<T>>,
+        interval: Duration,
+    ) -> Result<(), ArchiveError> {
+        let mut seen = HashSet::new();
+        loop {
+            time::delay_for(interval).await;
+            let client = self.client().await?;
+            let pending = match client.pending_extrinsics().await {
+                Ok(p) => p,
+                Err(e) => {
+                    warn!("Could not fetch pending extrinsics: {:?}", e);
+                    continue;
+                }
+            };
+            let now = Utc::now();
+            let mut pool = HashSet::new();
+            let mut new = Vec::new();
+            for data in pending.into_iter() {
+                let hash = T::Hashing::hash(&data.0);
+                if !seen.contains(&hash) {
+                    new.push(extrinsics::pending::<T>(data.0, now));
+                }
+                pool.insert(hash);
+            }
+            seen = pool;
+            if !new.is_empty() {
+                debug!("{} new extrinsics in the transaction pool", new.len());
+                Self::send(
+                    &mut sender,
+                    Data::PendingExtrinsics(PendingExtrinsics::new(new)),
+                )
                 .await?;
+            }
+        }
+    }
+
+    /// subscribes to new best heads, sending the block for each head
+    /// these blocks are not finalized, and may be on a fork that is later abandoned
+    /// Like `subscribe_blocks`, resubscribes and fills the gap if the connection drops
+    pub async fn subscribe_best_blocks(
+        self: Arc<Self>,
+        mut sender: Sender<Data<T>>,
+    ) -> Result<(), ArchiveError> {
+        let mut last = None;
+        loop {
+            let client = self.client().await?;
+            let mut stream = match client.subscribe_new_heads().await {
+                Ok(s) => s,
+                Err(e) => {
+                    warn!("Could not subscribe to new heads: {:?}", e);
+                    time::delay_for(INITIAL_BACKOFF).await;
+                    continue;
+                }
+            };
+            while let Some(head) = stream.next().await {
+                let head = match head {
+                    Ok(h) => h,
+                    Err(e) => {
+                        warn!("{:?}", e);
+                        break;
+                    }
+                };
+                log::debug!("Got Best Head: {:?}", head);
+                let number: u64 = (*head.number()).into();
+                self.fill_gap(last, number, &mut sender, true).await?;
+                last = Some(number);
+                let hash = head.hash();
+                match client.block(ListOrValue::Value(Some(hash))).await? {
+                    ListOrValue::Value(Some(b)) => {
+                        metrics::blocks_fetched(1);
+                        let block = BestBlock::new(b, Some(hash));
+                        Self::send(&mut sender, Data::BestBlock(block)).await?;
+                    }
+                    _ => warn!("No Block Exists!"),
+                }
+            }
+            warn!("New heads subscription ended, resubscribing");
+        }
+    }
+
+    /// fetch the blocks between the last head seen and a new head
+    /// a gap means heads were missed while the subscription was down
+    async fn fill_gap(
+        &self,
+        last: Option<u64>,
+        head: u64,
+        sender: &mut Sender<Data<T>>,
+        best: bool,
+    ) -> Result<(), ArchiveError> {
+        let last = match last {
+            Some(last) if head > last + 1 => last,
+            _ => return Ok(()),
+        };
+        warn!("Missed blocks {} to {}, fetching them", last + 1, head - 1);
+        let numbers = (last + 1..head).collect::<Vec<u64>>();
+        let blocks = self.batch_block_from_number(numbers).await?.log_failed();
+        if !best {
+            for version in self.block_runtime_versions(&blocks).await? {
+                Self::send(sender, Data::RuntimeVersion(version)).await?;
+            }
+        }
+        for (hash, block) in blocks.into_iter() {
+            let data = if best {
+                Data::BestBlock(BestBlock::new(block, Some(hash)))
+            } else {
+                Data::Block(Block::new(block, Some(hash)))
+            };
+            Self::send(sender, data).await?;
         }
         Ok(())
     }
@@ -89,58 +424,131 @@ impl<T> Rpc<T>
 where
     T: System,
 {
-    pub(crate) async fn new(url: url::Url) -> Result<Self, ArchiveError> {
-        let client: SubstrateRpc<T> = SubstrateRpc::connect(&url).await?;
+    /// Connect to every node in `urls`
+    /// Nodes that are down are connected to once they come up. Every node that is up
+    /// must agree on the genesis hash
+    pub(crate) async fn new(
+        urls: Vec<url::Url>,
+        options: RequestOptions,
+    ) -> Result<Self, ArchiveError> {
+        let mut endpoints = Vec::new();
+        let mut genesis = None;
+        let mut first = None;
+        for url in urls.into_iter() {
+            let client = match SubstrateRpc::<T>::connect(&url, options.clone()).await {
+                Ok(c) => c,
+                Err(e) => {
+                    warn!("Could not connect to {}: {:?}", url, e);
+                    endpoints.push(Endpoint::new(url, None, options.clone()));
+                    continue;
+                }
+            };
+            match genesis {
+                Some(genesis) => check_genesis(&url, &client, genesis).await?,
+                None => genesis = Some(client.genesis_hash().await?),
+            }
+            let client = Arc::new(client);
+            first = first.or_else(|| Some(client.clone()));
+            endpoints.push(Endpoint::new(url, Some(client), options.clone()));
+        }
+        let (genesis, client) = match (genesis, first) {
+            (Some(genesis), Some(client)) => (genesis, client),
+            _ => {
+                return Err(ArchiveError::DataNotFound(
+                    "A reachable RPC endpoint".to_string(),
+                ))
+            }
+        };
 
-        let (keys, metadata) = futures::join! {
+        let (keys, version, metadata, chain, properties) = futures::join! {
             client.storage_keys(StorageKey(Vec::new()), None),
+            client.runtime_version(None),
             client.metadata(None),
+            client.chain_name(),
+            client.properties(),
         };
+        let (spec_version, metadata) = (version?.spec_version, Arc::new(metadata?));
+        let mut runtimes = HashMap::new();
+        runtimes.insert(spec_version, metadata.clone());
 
         Ok(Self {
-            url,
+            endpoints,
+            genesis,
+            next: AtomicUsize::new(0),
             keys: keys?,
-            metadata: metadata?,
+            latest: RwLock::new((spec_version, metadata)),
+            runtimes: RwLock::new(runtimes),
+            chain: ChainInfo::new(genesis.as_ref().to_vec(), chain?, properties?),
             _marker: PhantomData,
         })
     }
 
-    /// get the latest block
-    pub(crate) async fn latest_block(&self) -> Result<Option<SubstrateBlock<T>>, ArchiveError> {
-        let client = self.client().await?;
-        let block = client.block(ListOrValue::Value(None)).await?;
-        match block {
-            ListOrValue::Value(v) => Ok(v),
-            ListOrValue::List(_) => {
-                return Err(ArchiveError::UnexpectedType(
-                    "Expected Value, Got List".to_string(),
-                ))
+    /// genesis hash of the chain every node is on
+    pub fn genesis_hash(&self) -> T::Hash {
+        self.genesis
+    }
+
+    /// urls of every node
+    pub fn urls(&self) -> Vec<&url::Url> {
+        self.endpoints.iter().map(|e| e.url()).collect()
+    }
+
+    /// The connection to the first healthy node, shared by every request and subscription
+    /// If every node is down, waits for one to come back
+    pub async fn client(&self) -> Result<Arc<SubstrateRpc<T>>, ArchiveError> {
+        self.client_from(0).await
+    }
+
+    /// A connection for a one-off request, taking turns between the healthy nodes
+    pub(crate) async fn balanced_client(&self) -> Result<Arc<SubstrateRpc<T>>, ArchiveError> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % self.endpoints.len();
+        self.client_from(start).await
+    }
+
+    /// first healthy node, starting at endpoint `start`
+    async fn client_from(&self, start: usize) -> Result<Arc<SubstrateRpc<T>>, ArchiveError> {
+        let mut backoff = INITIAL_BACKOFF;
+        let len = self.endpoints.len();
+        loop {
+            for endpoint in (0..len).map(|i| &self.endpoints[(start + i) % len]) {
+                if let Some(client) = endpoint.client(self.genesis).await {
+                    return Ok(client);
+                }
             }
+            warn!("Every RPC endpoint is down, retrying in {:?}", backoff);
+            time::delay_for(backoff).await;
+            backoff = std::cmp::min(backoff * 2, MAX_BACKOFF);
         }
     }
 
+    /// header of the latest finalized block
+    /// also reported as the chain head metric
+    pub(crate) async fn finalized_head(&self) -> Result<T::Header, ArchiveError> {
+        let client = self.client().await?;
+        let hash = client.finalized_head().await?;
+        let head = client.header(Some(hash)).await?.ok_or_else(|| {
+            ArchiveError::DataNotFound(format!("Header of finalized block {:?}", hash))
+        })?;
+        metrics::chain_head((*head.number()).into());
+        Ok(head)
+    }
+
     /// get just the latest header
     pub(crate) async fn latest_head(&self) -> Result<Option<T::Header>, ArchiveError> {
         let client = self.client().await?;
         client.header(None).await
     }
 
-    pub async fn client(&self) -> Result<SubstrateRpc<T>, ArchiveError> {
-        SubstrateRpc::connect(&self.url).await
-    }
-
     /// send all new headers back to main thread
     pub async fn subscribe_new_heads(
         &self,
-        sender: UnboundedSender<Data<T>>,
+        mut sender: Sender<Data<T>>,
     ) -> Result<(), ArchiveError> {
         let client = self.client().await?;
         let mut stream = client.subscribe_new_heads().await?;
 
         while let Some(head) = stream.next().await {
-            sender
-                .unbounded_send(Data::Header(Header::new(head?)))
-                .map_err(|e| ArchiveError::from(e))?;
+            Self::send(&mut sender, Data::Header(Header::new(head?))).await?;
         }
         Ok(())
     }
@@ -148,35 +556,17 @@ where
     /// send all finalized headers back to main thread
     pub async fn subscribe_finalized_heads(
         &self,
-        sender: UnboundedSender<Data<T>>,
+        mut sender: Sender<Data<T>>,
     ) -> Result<(), ArchiveError> {
         let client = self.client().await?;
         let mut stream = client.subscribe_finalized_heads().await?;
 
         while let Some(head) = stream.next().await {
-            sender
-                .unbounded_send(Data::FinalizedHead(Header::new(head?)))
-                .map_err(|e| ArchiveError::from(e))?;
+            Self::send(&mut sender, Data::FinalizedHead(Header::new(head?))).await?;
         }
         Ok(())
     }
 
-    /*
-    /// send all substrate events back to us
-    pub fn subscribe_events(&self, sender: UnboundedSender<Data<T>>) -> impl Future<Item = (), Error = ArchiveError>
-    {
-        self.chain.subscribe_events()
-            .map_err(|e| ArchiveError::from(e))
-            .and_then(|stream| {
-                stream.map_err(|e| e.into()).for_each(move |storage_change| {
-                    sender
-                        .unbounded_send(Data::Event(storage_change))
-                        .map_err(|e| ArchiveError::from(e))
-                })
-            })
-    }
-     */
-
     /*
         pub fn refresh_metadata(&mut self, hash: Option<T::Hash>) -> impl Future<Item = (), Error = ArchiveError> {
             SubstrateRpc::connect(&self.url)
@@ -190,139 +580,456 @@ where
         }
     */
 
-    pub async fn refresh_metadata(&mut self, hash: Option<T::Hash>) -> Result<(), ArchiveError> {
+    /// Fetch the runtime version and metadata at `hash`, or at the best block if `None`
+    /// The metadata becomes the latest if its spec version is newer than the latest
+    pub async fn refresh_metadata(&self, hash: Option<T::Hash>) -> Result<(), ArchiveError> {
         let client = self.client().await?;
-        let meta = client.metadata(hash).await?;
-        self.metadata = meta;
+        let (version, metadata) =
+            futures::join!(client.runtime_version(hash), client.metadata(hash));
+        let (spec_version, metadata) = (version?.spec_version, Arc::new(metadata?));
+        self.runtimes
+            .write()
+            .expect("Lock is never poisoned; qed")
+            .insert(spec_version, metadata.clone());
+        let mut latest = self.latest.write().expect("Lock is never poisoned; qed");
+        if hash.is_none() || spec_version > latest.0 {
+            *latest = (spec_version, metadata);
+        }
         Ok(())
     }
 
+    /// Which runtime each of these blocks, paired with the hash they were requested by,
+    /// was executed with
+    pub async fn block_runtime_versions(
+        &self,
+        blocks: &[(T::Hash, SubstrateBlock<T>)],
+    ) -> Result<Vec<RuntimeVersionRange<T>>, ArchiveError> {
+        let blocks = blocks
+            .iter()
+            .map(|(hash, b)| ((*b.block.header.number()).into(), *hash))
+            .collect::<Vec<(u64, T::Hash)>>();
+        self.runtime_versions(blocks).await
+    }
+
+    /// Which runtime each of these (number, hash) blocks was executed with, as ranges of blocks
+    /// Only the first and last block are asked for, unless the spec version changes between them;
+    /// then the blocks are bisected to find the block the upgrade was enacted in
+    /// Metadata of any runtime that was not seen before is fetched at the first block using it
+    pub async fn runtime_versions(
+        &self,
+        mut blocks: Vec<(u64, T::Hash)>,
+    ) -> Result<Vec<RuntimeVersionRange<T>>, ArchiveError> {
+        if blocks.is_empty() {
+            return Ok(Vec::new());
+        }
+        blocks.sort_by_key(|(number, _)| *number);
+        let client = self.balanced_client().await?;
+        let mut versions = HashMap::new();
+        // (first index, last index) of blocks known to share a spec version
+        let mut spans = Vec::new();
+        let mut todo = vec![(0, blocks.len() - 1)];
+        while let Some((lo, hi)) = todo.pop() {
+            for i in [lo, hi].iter() {
+                if !versions.contains_key(i) {
+                    let version = client.runtime_version(Some(blocks[*i].1)).await?;
+                    versions.insert(*i, version);
+                }
+            }
+            if versions[&lo].spec_version == versions[&hi].spec_version {
+                spans.push((lo, hi));
+            } else if hi - lo == 1 {
+                spans.push((lo, lo));
+                spans.push((hi, hi));
+            } else {
+                let mid = lo + (hi - lo) / 2;
+                todo.push((mid, hi));
+                todo.push((lo, mid));
+            }
+        }
+
+        let mut ranges: Vec<RuntimeVersionRange<T>> = Vec::new();
+        for (lo, hi) in spans.into_iter() {
+            let version = &versions[&lo];
+            match ranges.last_mut() {
+                Some(r) if r.spec_version() == version.spec_version => r.extend(blocks[hi].0),
+                _ => {
+                    if self.metadata_at_version(version.spec_version).is_none() {
+                        info!(
+                            "Runtime {} version {} first seen at block {}, fetching metadata",
+                            version.spec_name, version.spec_version, blocks[lo].0
+                        );
+                        let metadata = client.metadata(Some(blocks[lo].1)).await?;
+                        self.runtimes
+                            .write()
+                            .expect("Lock is never poisoned; qed")
+                            .insert(version.spec_version, Arc::new(metadata));
+                    }
+                    ranges.push(RuntimeVersionRange::new(
+                        version.spec_name.to_string(),
+                        version.spec_version,
+                        blocks[lo],
+                        blocks[hi].0,
+                    ))
+                }
+            }
+        }
+        Ok(ranges)
+    }
+
+    /// metadata of runtime `spec_version`, fetched at block `hash` if it was not seen before
+    pub async fn metadata_of(
+        &self,
+        spec_version: u32,
+        hash: T::Hash,
+    ) -> Result<Arc<Metadata>, ArchiveError> {
+        if let Some(metadata) = self.metadata_at_version(spec_version) {
+            return Ok(metadata);
+        }
+        let client = self.balanced_client().await?;
+        let metadata = Arc::new(client.metadata(Some(hash)).await?);
+        self.runtimes
+            .write()
+            .expect("Lock is never poisoned; qed")
+            .insert(spec_version, metadata.clone());
+        Ok(metadata)
+    }
+
     // TODO: make "Key" and "from" vectors
     // TODO: Merge 'from' and 'key' via a macro_derive on StorageKeyType, to auto-generate storage keys
     /// Get a storage item
     /// must provide the key, hash of the block to get storage from, as well as the key type
     pub async fn storage(
         &self,
-        sender: UnboundedSender<Data<T>>,
+        mut sender: Sender<Data<T>>,
         key: StorageKey,
         hash: T::Hash,
     ) -> Result<(), ArchiveError> {
+        let meta = self
+            .metadata()
+            .entry(&key)
+            .cloned()
+            .ok_or_else(|| ArchiveError::DataNotFound(format!("Metadata for {:?}", key)))?;
         let client = self.client().await?;
-        let storage = client.storage(key, hash).await?;
+        let storage = client.storage(key.clone(), hash).await?;
         debug!("STORAGE: {:?}", storage);
-        if let Some(s) = storage {
-            trace!("Sending timestamp for {}", hash);
-            sender.unbounded_send(Data::Storage(Storage::new(s, hash)))?;
-            Ok(())
-        } else {
+        if storage.is_none() {
             warn!("Storage Item does not exist!");
-            Ok(())
         }
+        trace!("Sending storage for {}", hash);
+        Self::send(
+            &mut sender,
+            Data::Storage(Storage::new(storage, hash, key, meta)),
+        )
+        .await
+    }
+
+    /// Fetch and decode `System.Events` of each (number, hash) block
+    /// Events are decoded with the metadata of the runtime each block was executed with;
+    /// events that can not be decoded are returned encoded, so the crawl does not stall on them
+    /// blocks that fail to fetch are returned with their error, instead of failing the whole batch
+    pub async fn batch_events(
+        &self,
+        blocks: Vec<(u64, T::Hash)>,
+    ) -> Result<Batch<u64, Event<T>>, ArchiveError> {
+        let versions = self.runtime_versions(blocks.clone()).await?;
+        let client = self.balanced_client().await?;
+        let futures = blocks.into_iter().map(|(number, hash)| {
+            let metadata = versions
+                .iter()
+                .find(|v| v.from_block() <= number && number <= v.to_block())
+                .and_then(|v| self.metadata_at_version(v.spec_version()))
+                .unwrap_or_else(|| self.metadata());
+            let client = client.clone();
+            async move {
+                let fetch = async {
+                    let data = client.storage(events_key(&metadata)?, hash).await?;
+                    let events = match data {
+                        Some(data) => match DbEvent::decode::<T>(&data, &metadata, &self.chain) {
+                            Ok(events) => Event::new(hash, events),
+                            Err(e) => {
+                                warn!("Could not decode events of {:?}: {:?}", hash, e);
+                                Event::undecoded(hash, data.0, e.to_string())
+                            }
+                        },
+                        None => Event::new(hash, Vec::new()),
+                    };
+                    Ok::<_, ArchiveError>(events)
+                };
+                fetch.await.map_err(|e| (number, e))
+            }
+        });
+
+        let (mut fetched, mut failed) = (Vec::new(), Vec::new());
+        for events in future::join_all(futures).await.into_iter() {
+            match events {
+                Ok(e) => fetched.push(e),
+                Err(f) => failed.push(f),
+            }
+        }
+        Ok(Batch { fetched, failed })
+    }
+
+    /// Fetch every value of every child trie that changed at each block
+    /// child tries are found under their top-level keys, which start with `:child_storage:`,
+    /// and the value under that key is the root of the trie. A trie is only crawled again at a
+    /// block if its root changed since the block before; the first block of the batch, and any
+    /// block whose previous block is not in the batch, has every trie crawled
+    /// Blocks that fail are returned with their error, instead of failing the whole batch
+    pub async fn batch_child_storage(
+        &self,
+        mut blocks: Vec<(u64, T::Hash)>,
+    ) -> Result<Batch<u64, ChildStorage<T>>, ArchiveError> {
+        blocks.sort_by_key(|(number, _)| *number);
+        let client = self.balanced_client().await?;
+        let roots =
+            future::join_all(blocks.iter().map(|(_, hash)| child_roots(&client, *hash))).await;
+
+        let (mut futures, mut failed) = (Vec::new(), Vec::new());
+        let mut previous: Option<(u64, HashMap<StorageKey, StorageData>)> = None;
+        for ((number, hash), roots) in blocks.into_iter().zip(roots.into_iter()) {
+            let roots = match roots {
+                Ok(roots) => roots,
+                Err(e) => {
+                    failed.push((number, e));
+                    previous = None;
+                    continue;
+                }
+            };
+            let changed = roots
+                .iter()
+                .filter(|(child, root)| match &previous {
+                    Some((prev, prev_roots)) if prev + 1 == number => {
+                        prev_roots.get(*child) != Some(*root)
+                    }
+                    _ => true,
+                })
+                .map(|(child, _)| child.clone())
+                .collect::<Vec<StorageKey>>();
+            previous = Some((number, roots));
+            let client = client.clone();
+            futures.push(async move {
+                let fetch = async {
+                    let mut values = Vec::new();
+                    for child in changed.into_iter() {
+                        let keys = client
+                            .child_storage_keys(&child, &StorageKey(Vec::new()), hash)
+                            .await?;
+                        let data = future::try_join_all(
+                            keys.iter().map(|k| client.child_storage(&child, k, hash)),
+                        )
+                        .await?;
+                        for (key, data) in keys.into_iter().zip(data.into_iter()) {
+                            if let Some(data) = data {
+                                values.push(ChildStorage::new(hash, child.clone(), key, data));
+                            }
+                        }
+                    }
+                    Ok::<_, ArchiveError>(values)
+                };
+                fetch.await.map_err(|e| (number, e))
+            });
+        }
+
+        let mut fetched = Vec::new();
+        for storage in future::join_all(futures).await.into_iter() {
+            match storage {
+                Ok(s) => fetched.extend(s),
+                Err(f) => failed.push(f),
+            }
+        }
+        Ok(Batch { fetched, failed })
+    }
+
+    /// Fetch a read proof of the keys at each block
+    /// Blocks that fail are returned with their error, instead of failing the whole batch
+    pub async fn batch_read_proofs(
+        &self,
+        blocks: Vec<(T::Hash, Vec<StorageKey>)>,
+    ) -> Result<Batch<T::Hash, (T::Hash, Vec<Vec<u8>>)>, ArchiveError> {
+        let client = self.balanced_client().await?;
+        let futures = blocks.into_iter().map(|(hash, keys)| {
+            let client = client.clone();
+            async move {
+                match client.read_proof(keys, hash).await {
+                    Ok(p) => Ok((hash, p.proof.into_iter().map(|node| node.0).collect())),
+                    Err(e) => Err((hash, e)),
+                }
+            }
+        });
+
+        let (mut fetched, mut failed) = (Vec::new(), Vec::new());
+        for proof in future::join_all(futures).await.into_iter() {
+            match proof {
+                Ok(p) => fetched.push(p),
+                Err(f) => failed.push(f),
+            }
+        }
+        Ok(Batch { fetched, failed })
+    }
+
+    /// Keys of every entry of the storage maps under `prefixes` at block `hash`
+    /// The keys of a map can not be derived from metadata, so the node is asked for them
+    pub async fn map_keys(
+        &self,
+        prefixes: Vec<StorageKey>,
+        hash: T::Hash,
+    ) -> Result<Vec<StorageKey>, ArchiveError> {
+        let client = self.balanced_client().await?;
+        let futures = prefixes
+            .into_iter()
+            .map(|prefix| client.storage_keys(prefix, Some(hash)));
+        let keys = future::try_join_all(futures).await?;
+        Ok(keys.into_iter().flatten().collect())
     }
 
+    /// Fetch the storage at each key/hash pair
+    /// Every block must have been executed with the runtime of `metadata`, which the values
+    /// are decoded with
+    /// storage that does not exist at a block is returned with no data
+    /// pairs that fail are returned with their error, instead of failing the whole batch
     pub async fn batch_storage(
         &self,
         keys: Vec<StorageKey>,
         hashes: Vec<T::Hash>,
-    ) -> Result<Vec<Storage<T>>, ArchiveError> {
+        metadata: &Metadata,
+    ) -> Result<Batch<(StorageKey, T::Hash), Storage<T>>, ArchiveError> {
         assert!(hashes.len() == keys.len()); // TODO remove assertion, make into ensure!
                                              // TODO: too many clones
         let client = self.client().await?;
         let mut futures = Vec::new();
-        for (idx, hash) in hashes.into_iter().enumerate() {
-            let key = keys[idx].clone();
-            futures.push(client.storage(key.clone(), hash).map(move |data| {
-                if let Some(d) = data? {
-                    Ok(Storage::new(d, hash))
-                } else {
-                    let err = format!("Storage item {:?} does not exist!", key);
-                    Err(ArchiveError::DataNotFound(err))
+        let mut failed = Vec::new();
+        for (key, hash) in keys.into_iter().zip(hashes.into_iter()) {
+            let meta = match metadata.entry(&key) {
+                Some(m) => m.clone(),
+                None => {
+                    let e = ArchiveError::DataNotFound(format!("Metadata for {:?}", key));
+                    failed.push(((key, hash), e));
+                    continue;
+                }
+            };
+            let client = client.clone();
+            futures.push(async move {
+                match client.storage(key.clone(), hash).await {
+                    Ok(data) => Ok(Storage::new(data, hash, key, meta)),
+                    Err(e) => Err(((key, hash), e)),
                 }
-            }));
+            });
         }
 
-        let data = future::join_all(futures).await;
-        Ok(data
-            .into_iter()
-            .filter_map(|d| {
-                if d.is_err() {
-                    error!("{:?}", d);
-                }
-                d.ok()
-            })
-            .collect::<Vec<Storage<T>>>())
+        let mut fetched = Vec::new();
+        for storage in future::join_all(futures).await.into_iter() {
+            match storage {
+                Ok(s) => fetched.push(s),
+                Err(f) => failed.push(f),
+            }
+        }
+        Ok(Batch { fetched, failed })
     }
 
     /// Fetch a block by hash from Substrate RPC
     pub async fn block(
         &self,
         hash: Option<T::Hash>,
-        sender: UnboundedSender<Data<T>>,
+        sender: Sender<Data<T>>,
     ) -> Result<(), ArchiveError> {
         let client = self.client().await?;
-        let block = client.block(ListOrValue::Value(hash)).await?;
-        Self::send_block(block, sender)
+        Self::send_block(&client, ListOrValue::Value(hash), sender).await
     }
 
     pub async fn block_from_number(
         &self,
         number: NumberOrHex<T::BlockNumber>,
-        sender: UnboundedSender<Data<T>>,
+        sender: Sender<Data<T>>,
     ) -> Result<(), ArchiveError> {
         let client = self.client().await?;
 
         let num = Some(ListOrValue::Value(number));
-        let block = client.block(client.hash(num).await?).await?;
-        Self::send_block(block, sender)
+        let hash = client.hash(num).await?;
+        Self::send_block(&client, hash, sender).await
     }
 
+    /// Fetch the blocks with these numbers, from whichever node's turn it is
+    /// blocks that fail, or that the node does not know about, are returned with their error
+    /// each block is returned with the hash it was requested by
     pub async fn batch_block_from_number(
         &self,
-        numbers: Vec<NumberOrHex<T::BlockNumber>>,
-    ) -> Result<Vec<SubstrateBlock<T>>, ArchiveError> {
-        let client = Arc::new(self.client().await?);
-
-        let numbers = Some(ListOrValue::List(numbers));
-        let blocks = client.block_from_number(numbers).await?;
-        let blocks = match blocks {
-            ListOrValue::Value(_) => {
-                return Err(ArchiveError::UnexpectedType(
-                    "Expected List, got Value".to_string(),
-                ))
+        numbers: Vec<u64>,
+    ) -> Result<Batch<u64, (T::Hash, SubstrateBlock<T>)>, ArchiveError> {
+        // spread historical requests over every node
+        let client = self.balanced_client().await?;
+
+        let hashes = client.batch_hashes(&numbers).await?;
+
+        let mut failed = Vec::new();
+        let mut found = Vec::new();
+        for (number, hash) in numbers.into_iter().zip(hashes.into_iter()) {
+            match hash {
+                Ok(Some(hash)) => found.push((number, hash)),
+                Ok(None) => failed.push((number, not_found(number))),
+                Err(e) => failed.push((number, e)),
             }
-            ListOrValue::List(v) => v,
-        };
+        }
+        let (numbers, hashes): (Vec<u64>, Vec<T::Hash>) = found.into_iter().unzip();
 
-        Ok(blocks
+        let mut fetched = Vec::new();
+        let blocks = client.batch_blocks(hashes.clone()).await?;
+        for ((number, hash), block) in numbers
             .into_iter()
-            .filter_map(|b| b)
-            .collect::<Vec<SubstrateBlock<T>>>())
+            .zip(hashes.into_iter())
+            .zip(blocks.into_iter())
+        {
+            match block {
+                Ok(Some(b)) => fetched.push((hash, b)),
+                Ok(None) => failed.push((number, not_found(number))),
+                Err(e) => failed.push((number, e)),
+            }
+        }
+        metrics::blocks_fetched(fetched.len());
+        Ok(Batch { fetched, failed })
     }
 
-    fn send_block(
-        block: ListOrValue<Option<SubstrateBlock<T>>>,
-        sender: UnboundedSender<Data<T>>,
+    /// send data to be inserted, keeping count of the items waiting in the channel
+    async fn send(sender: &mut Sender<Data<T>>, data: Data<T>) -> Result<(), ArchiveError> {
+        metrics::channel_push();
+        sender.send(data).await.map_err(|e| {
+            metrics::channel_pop();
+            e.into()
+        })
+    }
+
+    /// fetch blocks by hash and send them, with the hash each was requested by
+    async fn send_block(
+        client: &SubstrateRpc<T>,
+        hash: ListOrValue<Option<T::Hash>>,
+        mut sender: Sender<Data<T>>,
     ) -> Result<(), ArchiveError> {
-        match block {
-            ListOrValue::Value(v) => {
-                if let Some(b) = v {
-                    sender
-                        .unbounded_send(Data::Block(Block::new(b)))
-                        .map_err(Into::into)
+        match hash {
+            ListOrValue::Value(hash) => {
+                if let ListOrValue::Value(Some(b)) = client.block(ListOrValue::Value(hash)).await? {
+                    metrics::blocks_fetched(1);
+                    Self::send(&mut sender, Data::Block(Block::new(b, hash))).await
                 } else {
                     warn!("No Block Exists!");
                     Ok(())
                 }
             }
-            ListOrValue::List(v) => {
+            ListOrValue::List(hashes) => {
+                let blocks = match client.block(ListOrValue::List(hashes.clone())).await? {
+                    ListOrValue::List(blocks) => blocks,
+                    ListOrValue::Value(_) => {
+                        return Err(ArchiveError::UnexpectedType(
+                            "Expected List, got Value".to_string(),
+                        ))
+                    }
+                };
                 // throws out any none's
-                let blocks = v
+                let blocks = hashes
                     .into_iter()
-                    .filter_map(|b| b)
-                    .collect::<Vec<SubstrateBlock<T>>>();
-                sender
-                    .unbounded_send(Data::BatchBlock(BatchBlock::new(blocks)))
-                    .map_err(Into::into)
+                    .zip(blocks.into_iter())
+                    .filter_map(|(hash, b)| Some((hash?, b?)))
+                    .collect::<Vec<(T::Hash, SubstrateBlock<T>)>>();
+                metrics::blocks_fetched(blocks.len());
+                Self::send(&mut sender, Data::BatchBlock(BatchBlock::new(blocks))).await
             }
         }
     }
@@ -342,7 +1049,84 @@ where
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use crate::tests::Runtime;
+    use mock::{Fixture, MockNode};
+    use tokio::runtime::Runtime as TokioRuntime;
+
+    fn connect(node: &MockNode) -> (TokioRuntime, Rpc<Runtime>) {
+        let mut runtime = TokioRuntime::new().unwrap();
+        let rpc = runtime
+            .block_on(Rpc::<Runtime>::new(
+                vec![node.url().clone()],
+                RequestOptions::default(),
+            ))
+            .unwrap();
+        (runtime, rpc)
+    }
 
     #[test]
-    fn can_query_blocks() {}
+    fn should_read_chain_at_startup() {
+        let node = MockNode::start(Fixture::load("node"));
+        let (_rt, rpc) = connect(&node);
+        assert_eq!(rpc.spec_version(), 200);
+        assert_eq!(rpc.chain_info().chain(), "Development");
+        assert_eq!(rpc.chain_info().ss58_prefix(), Some(42));
+        assert_eq!(rpc.chain_info().token_decimals(), Some(12));
+        assert_eq!(rpc.genesis_hash().as_ref(), &[0xa0; 32][..]);
+    }
+
+    #[test]
+    fn should_refuse_nodes_of_another_chain() {
+        let node = MockNode::start(Fixture::load("node"));
+        let other = MockNode::start(Fixture::load("node").respond(
+            "chain_getBlockHash",
+            Some(serde_json::json!([0])),
+            serde_json::json!(format!("0x{}", "ff".repeat(32))),
+        ));
+        let mut runtime = TokioRuntime::new().unwrap();
+        let rpc = runtime.block_on(Rpc::<Runtime>::new(
+            vec![node.url().clone(), other.url().clone()],
+            RequestOptions::default(),
+        ));
+        match rpc {
+            Err(ArchiveError::GenesisMismatch(_)) => (),
+            other => panic!("expected a genesis mismatch, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn should_batch_blocks_from_number() {
+        let node = MockNode::start(Fixture::load("node"));
+        let (mut rt, rpc) = connect(&node);
+        let batch = rt
+            .block_on(rpc.batch_block_from_number(vec![0, 1, 2]))
+            .unwrap();
+        assert!(batch.failed.is_empty());
+        let mut numbers = batch
+            .fetched
+            .iter()
+            .map(|(_, b)| (*b.block.header.number()).into())
+            .collect::<Vec<u64>>();
+        numbers.sort();
+        assert_eq!(numbers, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn should_enumerate_map_keys_by_prefix() {
+        let hash = format!("0x{}", "a1".repeat(32));
+        let node = MockNode::start(Fixture::load("node").respond(
+            "state_getKeys",
+            Some(serde_json::json!(["0x0102", hash])),
+            serde_json::json!(["0x010203", "0x010204"]),
+        ));
+        let (mut rt, rpc) = connect(&node);
+        let hash = <Runtime as System>::Hash::from_slice(&[0xa1; 32]);
+        let prefixes = vec![StorageKey(vec![1, 2]), StorageKey(vec![9])];
+        let keys = rt.block_on(rpc.map_keys(prefixes, hash)).unwrap();
+        assert_eq!(
+            keys,
+            vec![StorageKey(vec![1, 2, 3]), StorageKey(vec![1, 2, 4])]
+        );
+    }
 }
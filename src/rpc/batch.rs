@@ -0,0 +1,233 @@
+// Copyright 2017-2019 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! JSON-RPC batch requests over websockets
+//! Many calls of one method go out in a single websocket message, and come back in one
+
+use futures::compat::Future01CompatExt;
+use futures01::{Sink as Sink01, Stream as Stream01};
+use jsonrpc_core_client::RpcError;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use tokio::time;
+use websocket::{
+    r#async::Client, stream::r#async::Stream as AsyncStream, ClientBuilder, OwnedMessage,
+};
+
+use std::{sync::Mutex, time::Duration};
+
+use super::retry;
+use crate::{error::Error as ArchiveError, metrics};
+
+/// how long the node has to answer a batch
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+type WsClient = Client<Box<dyn AsyncStream + Send>>;
+
+/// one response in a batch
+#[derive(Debug, Deserialize)]
+struct Response {
+    id: usize,
+    #[serde(default)]
+    result: Value,
+    error: Option<Value>,
+}
+
+/// Websocket connections to one node, that batches are sent over
+/// Each batch in flight has a connection of its own, so batches do not wait on each other.
+/// A connection is kept for the next batch once its reply is in, up to `max_idle` of them;
+/// it is dropped when a batch fails or times out, so no late reply is mistaken for the next one
+pub(crate) struct Connection {
+    url: url::Url,
+    /// connections with no batch in flight
+    /// only locked to take or return a connection
+    idle: Mutex<Vec<WsClient>>,
+    max_idle: usize,
+}
+
+impl Connection {
+    pub(crate) fn new(url: url::Url, max_idle: usize) -> Self {
+        Self {
+            url,
+            idle: Mutex::new(Vec::new()),
+            max_idle,
+        }
+    }
+
+    /// send one message and wait for the reply
+    async fn send(&self, message: String) -> Result<String, ArchiveError> {
+        let idle = self.idle.lock().expect("Lock is never poisoned; qed").pop();
+        let ws = match idle {
+            Some(ws) => ws,
+            None => {
+                let (ws, _) = ClientBuilder::from_url(&self.url)
+                    .async_connect(None)
+                    .compat()
+                    .await?;
+                ws
+            }
+        };
+        let (reply, ws) = time::timeout(TIMEOUT, exchange(ws, message))
+            .await
+            .map_err(|_| ArchiveError::Rpc(RpcError::Timeout))??;
+        let mut idle = self.idle.lock().expect("Lock is never poisoned; qed");
+        if idle.len() < self.max_idle {
+            idle.push(ws);
+        }
+        Ok(reply)
+    }
+}
+
+/// Call `method` once for each of `params`, sending at most `batch_size` calls per message
+/// Results are in the same order as `params`. A call that the node answers with an error
+/// fails on its own; a batch that can not be sent, or whose reply can not be read,
+/// fails every call in it, but not the other batches
+pub(crate) async fn call<R>(
+    connection: &Connection,
+    method: &'static str,
+    params: Vec<Value>,
+    batch_size: usize,
+    max_attempts: usize,
+) -> Vec<Result<R, ArchiveError>>
+where
+    R: DeserializeOwned,
+{
+    let mut results = Vec::with_capacity(params.len());
+    for chunk in params.chunks(batch_size) {
+        let message = frame(method, chunk);
+        let response = retry::retry(method, max_attempts, || {
+            metrics::time_rpc(method, connection.send(message.clone()))
+        })
+        .await;
+        match response.and_then(|r| parse(&r, chunk.len())) {
+            Ok(r) => results.extend(r),
+            Err(e) => {
+                log::warn!("Batch of {} {} calls failed: {:?}", chunk.len(), method, e);
+                results.extend(chunk.iter().map(|_| {
+                    Err(ArchiveError::Transport(format!(
+                        "Batch of {} failed: {}",
+                        method, e
+                    )))
+                }));
+            }
+        }
+    }
+    results
+}
+
+/// a batch of calls, with each call's id its position in the batch
+fn frame(method: &str, params: &[Value]) -> String {
+    let calls = params
+        .iter()
+        .enumerate()
+        .map(|(id, p)| json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": p }))
+        .collect::<Vec<Value>>();
+    Value::Array(calls).to_string()
+}
+
+/// send `message` and wait for the reply, handing the connection back with it
+async fn exchange(ws: WsClient, message: String) -> Result<(String, WsClient), ArchiveError> {
+    let mut ws = ws.send(OwnedMessage::Text(message)).compat().await?;
+    loop {
+        let (message, rest) = ws.into_future().compat().await.map_err(|(e, _)| e)?;
+        ws = rest;
+        match message {
+            Some(OwnedMessage::Text(text)) => return Ok((text, ws)),
+            Some(OwnedMessage::Ping(p)) => ws = ws.send(OwnedMessage::Pong(p)).compat().await?,
+            Some(OwnedMessage::Close(_)) | None => {
+                return Err(ArchiveError::Transport(
+                    "Node closed the connection during a batch".to_string(),
+                ))
+            }
+            Some(_) => continue,
+        }
+    }
+}
+
+/// results of a batch of `len` calls, in order of id
+fn parse<R: DeserializeOwned>(
+    response: &str,
+    len: usize,
+) -> Result<Vec<Result<R, ArchiveError>>, ArchiveError> {
+    let responses: Vec<Response> = serde_json::from_str(response)?;
+    let mut results = (0..len)
+        .map(|id| {
+            Err(ArchiveError::RpcResponse(format!(
+                "No response to call {} in batch",
+                id
+            )))
+        })
+        .collect::<Vec<Result<R, ArchiveError>>>();
+    for response in responses.into_iter() {
+        if response.id >= len {
+            continue;
+        }
+        results[response.id] = match response.error {
+            Some(e) => Err(ArchiveError::RpcResponse(e.to_string())),
+            None => serde_json::from_value(response.result).map_err(Into::into),
+        };
+    }
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_frame_calls() {
+        let message = frame("chain_getBlockHash", &[json!(["0x1"]), json!(["0x2"])]);
+        let calls: Vec<Value> = serde_json::from_str(&message).unwrap();
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[1]["id"], json!(1));
+        assert_eq!(calls[1]["method"], json!("chain_getBlockHash"));
+        assert_eq!(calls[1]["params"], json!(["0x2"]));
+    }
+
+    #[test]
+    fn should_parse_out_of_order_responses() {
+        let response = r#"[
+            {"jsonrpc": "2.0", "id": 1, "result": 20},
+            {"jsonrpc": "2.0", "id": 0, "result": 10},
+            {"jsonrpc": "2.0", "id": 2, "error": {"code": -32000, "message": "Unknown block"}}
+        ]"#;
+        let results = parse::<u64>(response, 4).unwrap();
+        assert_eq!(results[0].as_ref().unwrap(), &10);
+        assert_eq!(results[1].as_ref().unwrap(), &20);
+        assert!(results[2].is_err());
+        // no response at all
+        assert!(results[3].is_err());
+    }
+
+    #[test]
+    fn should_parse_null_results() {
+        let response = r#"[{"jsonrpc": "2.0", "id": 0, "result": null}]"#;
+        let results = parse::<Option<u64>>(response, 1).unwrap();
+        assert_eq!(results[0].as_ref().unwrap(), &None);
+    }
+
+    #[test]
+    fn should_fail_each_call_of_a_batch_that_can_not_be_sent() {
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        // nothing listens on port 1
+        let connection = Connection::new(url::Url::parse("ws://127.0.0.1:1").unwrap(), 1);
+        let params = vec![json!(["0x1"]), json!(["0x2"]), json!(["0x3"])];
+        let results: Vec<Result<u64, ArchiveError>> =
+            rt.block_on(call(&connection, "chain_getBlockHash", params, 2, 1));
+        assert_eq!(results.len(), 3);
+        assert!(results.iter().all(Result::is_err));
+    }
+}
@@ -0,0 +1,156 @@
+// Copyright 2017-2019 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! One node the archive can talk to, and its connection
+
+use log::warn;
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use super::substrate_rpc::{RequestOptions, SubstrateRpc};
+use crate::{error::Error as ArchiveError, types::System};
+
+/// first wait before connecting to a node again, after it could not be reached
+const INITIAL_RETRY_AFTER: Duration = Duration::from_secs(1);
+/// longest wait before connecting to a node again
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+pub(crate) struct Endpoint<T: System> {
+    url: url::Url,
+    /// `None` while the node is unreachable
+    /// only locked to read or swap the client, never while connecting
+    client: Mutex<Option<Arc<SubstrateRpc<T>>>>,
+    /// while the node is down: when to try it again, and how long the last wait was
+    retry_at: Mutex<Option<(Instant, Duration)>>,
+    /// set while a caller is connecting to the node again
+    connecting: AtomicBool,
+    options: RequestOptions,
+}
+
+impl<T> Endpoint<T>
+where
+    T: System,
+{
+    pub(crate) fn new(
+        url: url::Url,
+        client: Option<Arc<SubstrateRpc<T>>>,
+        options: RequestOptions,
+    ) -> Self {
+        Self {
+            url,
+            client: Mutex::new(client),
+            retry_at: Mutex::new(None),
+            connecting: AtomicBool::new(false),
+            options,
+        }
+    }
+
+    pub(crate) fn url(&self) -> &url::Url {
+        &self.url
+    }
+
+    /// The connection to this node
+    /// If it was dropped, tries to connect once; returns `None` if the node is down,
+    /// or if it is on a different chain than `genesis`
+    /// A node that could not be reached is skipped until its retry-after passes, which doubles
+    /// each time it is still down. Only one caller connects at a time; the others get `None`
+    /// meanwhile, so they can use another node instead of waiting
+    pub(crate) async fn client(&self, genesis: T::Hash) -> Option<Arc<SubstrateRpc<T>>> {
+        let lost = match &*self.client.lock().expect("Lock is never poisoned; qed") {
+            Some(c) if !c.is_closed() => return Some(c.clone()),
+            Some(_) => true,
+            None => false,
+        };
+        if self.connecting.swap(true, Ordering::SeqCst) {
+            return None;
+        }
+        let _connecting = Connecting(&self.connecting);
+        if lost {
+            warn!("Lost connection to {}, reconnecting", self.url);
+        }
+        let last_wait = {
+            let retry_at = self.retry_at.lock().expect("Lock is never poisoned; qed");
+            match *retry_at {
+                Some((at, _)) if Instant::now() < at => return None,
+                Some((_, wait)) => Some(wait),
+                None => None,
+            }
+        };
+        let client = match connect_verified(&self.url, genesis, self.options.clone()).await {
+            Ok(c) => {
+                log::info!("Connected to {}", self.url);
+                *self.retry_at.lock().expect("Lock is never poisoned; qed") = None;
+                Some(Arc::new(c))
+            }
+            Err(e) => {
+                let wait = last_wait
+                    .map(|wait| std::cmp::min(wait * 2, MAX_RETRY_AFTER))
+                    .unwrap_or(INITIAL_RETRY_AFTER);
+                warn!(
+                    "Could not connect to {}, retrying in {:?}: {:?}",
+                    self.url, wait, e
+                );
+                *self.retry_at.lock().expect("Lock is never poisoned; qed") =
+                    Some((Instant::now() + wait, wait));
+                None
+            }
+        };
+        *self.client.lock().expect("Lock is never poisoned; qed") = client.clone();
+        client
+    }
+}
+
+/// Clears the connecting flag of an endpoint once its connection attempt finishes or is dropped
+struct Connecting<'a>(&'a AtomicBool);
+
+impl Drop for Connecting<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Connect to `url`, only if the node has the expected genesis hash
+async fn connect_verified<T: System>(
+    url: &url::Url,
+    genesis: T::Hash,
+    options: RequestOptions,
+) -> Result<SubstrateRpc<T>, ArchiveError> {
+    let client = SubstrateRpc::<T>::connect(url, options).await?;
+    check_genesis(url, &client, genesis).await?;
+    Ok(client)
+}
+
+/// Error if the node at `url` is on a different chain than `genesis`
+pub(crate) async fn check_genesis<T: System>(
+    url: &url::Url,
+    client: &SubstrateRpc<T>,
+    genesis: T::Hash,
+) -> Result<(), ArchiveError> {
+    let node_genesis = client.genesis_hash().await?;
+    if node_genesis != genesis {
+        return Err(ArchiveError::GenesisMismatch(format!(
+            "{} has genesis {:?}, expected {:?}",
+            url, node_genesis, genesis
+        )));
+    }
+    Ok(())
+}
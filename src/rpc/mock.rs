@@ -0,0 +1,235 @@
+// Copyright 2017-2019 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! A websocket JSON-RPC server that answers like a Substrate node
+//! Runs in-process, so `Rpc`, `Sync` and `Archive` can be tested without a live node.
+//! Answers and subscription notifications are read from fixture files in `fixtures/`
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use super::replay::{normalize, serve, Answers, SUBSCRIPTIONS};
+
+/// Canned answers of a node
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Fixture {
+    /// answers to calls
+    /// answers for the exact params are sent in order, one per call, repeating the last;
+    /// otherwise the first answer without params is sent
+    #[serde(default)]
+    responses: Vec<Canned>,
+    /// notifications sent right after subscribing, by subscription method
+    #[serde(default)]
+    subscriptions: HashMap<String, Vec<Value>>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Canned {
+    method: String,
+    /// answers any params if `None`
+    #[serde(default)]
+    params: Option<Value>,
+    #[serde(default)]
+    result: Value,
+    /// the JSON-RPC error to answer with instead of `result`
+    #[serde(default)]
+    error: Option<Value>,
+}
+
+impl Fixture {
+    /// read `fixtures/<name>.json`
+    pub fn load(name: &str) -> Self {
+        let path: std::path::PathBuf = [
+            env!("CARGO_MANIFEST_DIR"),
+            "fixtures",
+            &format!("{}.json", name),
+        ]
+        .iter()
+        .collect();
+        let file = std::fs::read_to_string(&path).expect("Fixture exists");
+        serde_json::from_str(&file).expect("Fixture is valid")
+    }
+
+    /// answer `method` with `result`, instead of the answers in the fixture for the same params
+    pub fn respond(mut self, method: &str, params: Option<Value>, result: Value) -> Self {
+        let params = params.map(normalize);
+        self.responses
+            .retain(|c| !(c.method == method && c.params.clone().map(normalize) == params));
+        let canned = Canned {
+            method: method.to_string(),
+            params,
+            result,
+            error: None,
+        };
+        self.responses.insert(0, canned);
+        self
+    }
+
+    /// the answer to the `nth` call of `method` with `params`: its result, or its error
+    /// `None` if the fixture does not know the method. Known methods with params the
+    /// fixture has no answer for return `null`, like a node asked for a block it does not have
+    fn answer(&self, method: &str, params: &Value, nth: usize) -> Option<Result<Value, Value>> {
+        let params = normalize(params.clone());
+        let known = self
+            .responses
+            .iter()
+            .filter(|c| c.method == method)
+            .collect::<Vec<&Canned>>();
+        if known.is_empty() {
+            return None;
+        }
+        let exact = known
+            .iter()
+            .filter(|c| c.params.clone().map(normalize) == Some(params.clone()))
+            .collect::<Vec<_>>();
+        let canned = match exact.len() {
+            0 => known.iter().find(|c| c.params.is_none()),
+            n => exact.get(std::cmp::min(nth, n - 1)).copied(),
+        };
+        Some(match canned {
+            Some(c) => match c.error {
+                Some(ref e) => Err(e.clone()),
+                None => Ok(c.result.clone()),
+            },
+            None => Ok(Value::Null),
+        })
+    }
+}
+
+/// What every connection to a mock node shares
+struct State {
+    fixture: Fixture,
+    /// next subscription id
+    ids: AtomicU64,
+    /// times each (method, params) was called
+    calls: Mutex<HashMap<String, usize>>,
+}
+
+/// A running mock node
+/// The server thread lives as long as the process
+pub struct MockNode {
+    url: url::Url,
+}
+
+impl MockNode {
+    /// serve `fixture` on a free local port
+    pub fn start(fixture: Fixture) -> Self {
+        let url = serve(State {
+            fixture,
+            ids: AtomicU64::new(1),
+            calls: Mutex::new(HashMap::new()),
+        })
+        .expect("Mock node can serve on a local port");
+        Self { url }
+    }
+
+    pub fn url(&self) -> &url::Url {
+        &self.url
+    }
+}
+
+impl Answers for State {
+    fn call(&self, call: &Value) -> (Value, Vec<Value>) {
+        let id = call["id"].clone();
+        let method = call["method"].as_str().unwrap_or_default();
+        let params = &call["params"];
+
+        if let Some((_, notify)) = SUBSCRIPTIONS.iter().find(|(m, _)| *m == method) {
+            let subscription = self.ids.fetch_add(1, Ordering::SeqCst);
+            let notifications = self
+                .fixture
+                .subscriptions
+                .get(method)
+                .cloned()
+                .unwrap_or_default()
+                .into_iter()
+                .map(|result| {
+                    json!({
+                        "jsonrpc": "2.0",
+                        "method": notify,
+                        "params": { "subscription": subscription, "result": result }
+                    })
+                })
+                .collect();
+            let reply = json!({ "jsonrpc": "2.0", "id": id, "result": subscription });
+            return (reply, notifications);
+        }
+        if method.contains("_unsubscribe") {
+            return (
+                json!({ "jsonrpc": "2.0", "id": id, "result": true }),
+                Vec::new(),
+            );
+        }
+        let nth = {
+            let mut calls = self.calls.lock().expect("Lock is never poisoned; qed");
+            let count = calls
+                .entry(format!("{}{}", method, normalize(params.clone())))
+                .or_insert(0);
+            *count += 1;
+            *count - 1
+        };
+        let reply = match self.fixture.answer(method, params, nth) {
+            Some(Ok(result)) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Some(Err(error)) => json!({ "jsonrpc": "2.0", "id": id, "error": error }),
+            None => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": -32601, "message": "Method not found" }
+            }),
+        };
+        (reply, Vec::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_match_params_like_a_node() {
+        let fixture = Fixture::load("node");
+        assert_eq!(
+            fixture.answer("chain_getBlockHash", &json!([1]), 0),
+            fixture.answer("chain_getBlockHash", &json!(["0x1"]), 0)
+        );
+        assert_eq!(
+            fixture.answer("chain_getHeader", &json!([null]), 0),
+            fixture.answer("chain_getHeader", &json!([]), 0)
+        );
+        assert_eq!(
+            fixture.answer("chain_getBlockHash", &json!([100]), 0),
+            Some(Ok(Value::Null))
+        );
+        assert_eq!(fixture.answer("system_unknown", &json!([]), 0), None);
+    }
+
+    #[test]
+    fn should_prefer_added_responses() {
+        let fixture = Fixture::load("node").respond("system_chain", None, json!("Local"));
+        assert_eq!(
+            fixture.answer("system_chain", &json!([]), 0),
+            Some(Ok(json!("Local")))
+        );
+    }
+}
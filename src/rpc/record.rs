@@ -0,0 +1,79 @@
+// Copyright 2017-2019 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Recording of RPC traffic, so that the archive can be run against it again without a node
+//! Every call with its answer, and every subscription notification, is written as one line of JSON.
+//! `ArchiveConfig::replay` serves a recording back through a replay node, see `replay`
+
+use log::warn;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+    sync::Mutex,
+};
+
+use crate::error::Error as ArchiveError;
+
+/// Writes RPC traffic to a file, shared by every connection
+#[derive(Debug)]
+pub(crate) struct Recorder {
+    file: Mutex<File>,
+}
+
+impl Recorder {
+    /// record into `path`, appending if it already has a recording
+    pub(crate) fn open(path: &Path) -> Result<Self, ArchiveError> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// a call to `method`, and what the node answered
+    pub(crate) fn call<R: Serialize>(
+        &self,
+        method: &str,
+        params: &Value,
+        result: &Result<R, ArchiveError>,
+    ) {
+        let line = match result {
+            Ok(r) => json!({ "method": method, "params": params, "result": r }),
+            Err(e) => json!({
+                "method": method,
+                "params": params,
+                "error": { "code": -32000, "message": e.to_string() }
+            }),
+        };
+        self.write(line);
+    }
+
+    /// a notification of the subscription made with `method`
+    pub(crate) fn notification<R: Serialize>(&self, method: &str, result: &R) {
+        self.write(json!({ "subscription": method, "result": result }));
+    }
+
+    /// a failed write is logged, rather than failing the request it records
+    fn write(&self, line: Value) {
+        let mut file = self.file.lock().expect("Lock is never poisoned; qed");
+        if let Err(e) = writeln!(file, "{}", line) {
+            warn!("Could not record RPC traffic: {:?}", e);
+        }
+    }
+}
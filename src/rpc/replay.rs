@@ -0,0 +1,352 @@
+// Copyright 2017-2019 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! A local websocket server that serves a recording made with `ArchiveConfig::record`,
+//! to run the archive against what a node answered, without the node
+//! Calls are answered in the order they were recorded. Notifications are sent once, in the
+//! order they were recorded, to the first subscription of their method; a resubscription
+//! gets no notifications, as a node does not send a block twice
+//! Where notifications fell between calls is not recorded: they are all sent right after the
+//! subscription, so a replay answers the same, but is not a reproduction of the same timing
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+use websocket::{sync::Server, OwnedMessage};
+
+use std::{
+    collections::{HashMap, VecDeque},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+use crate::error::Error as ArchiveError;
+
+/// subscription methods, and the method their notifications are sent with
+pub(super) const SUBSCRIPTIONS: &[(&str, &str)] = &[
+    ("chain_subscribeFinalizedHeads", "chain_finalizedHead"),
+    ("chain_subscribeNewHeads", "chain_newHead"),
+    ("state_subscribeRuntimeVersion", "state_runtimeVersion"),
+    ("state_subscribeStorage", "state_storage"),
+];
+
+/// Answers JSON-RPC calls of a websocket server
+pub(super) trait Answers: Send + Sync + 'static {
+    /// the reply to one call, and any notifications to send after it
+    fn call(&self, call: &Value) -> (Value, Vec<Value>);
+}
+
+/// Serve `answers` on a free local port
+/// The server thread lives as long as the process
+pub(super) fn serve<A: Answers>(answers: A) -> Result<url::Url, ArchiveError> {
+    let server = Server::bind("127.0.0.1:0")?;
+    let addr = server.local_addr()?;
+    let answers = Arc::new(answers);
+    thread::spawn(move || {
+        for request in server.filter_map(Result::ok) {
+            let answers = answers.clone();
+            thread::spawn(move || {
+                if let Ok(client) = request.accept() {
+                    connection(client, &*answers);
+                }
+            });
+        }
+    });
+    Ok(url::Url::parse(&format!("ws://{}", addr))?)
+}
+
+/// answer one connection until it closes
+fn connection<A: Answers>(mut client: websocket::sync::Client<std::net::TcpStream>, answers: &A) {
+    while let Ok(message) = client.recv_message() {
+        let text = match message {
+            OwnedMessage::Text(text) => text,
+            OwnedMessage::Ping(p) => {
+                let _ = client.send_message(&OwnedMessage::Pong(p));
+                continue;
+            }
+            OwnedMessage::Close(_) => return,
+            _ => continue,
+        };
+        let (reply, notifications) = match serde_json::from_str::<Value>(&text) {
+            Ok(Value::Array(calls)) => {
+                let replies = calls.iter().map(|c| answers.call(c).0).collect();
+                (Value::Array(replies), Vec::new())
+            }
+            Ok(c) => answers.call(&c),
+            Err(e) => (
+                json!({ "jsonrpc": "2.0", "id": null, "error": { "code": -32700, "message": e.to_string() } }),
+                Vec::new(),
+            ),
+        };
+        let messages = std::iter::once(reply).chain(notifications.into_iter());
+        for message in messages {
+            if client
+                .send_message(&OwnedMessage::Text(message.to_string()))
+                .is_err()
+            {
+                return;
+            }
+        }
+    }
+}
+
+/// params compared the way a node reads them: trailing `null`s are optional params
+/// that were left out, and numbers may be sent as hex strings
+pub(super) fn normalize(params: Value) -> Value {
+    let mut params = match params {
+        Value::Array(p) => p,
+        Value::Null => Vec::new(),
+        other => vec![other],
+    };
+    while params.last() == Some(&Value::Null) {
+        params.pop();
+    }
+    params
+        .into_iter()
+        .map(|p| match p.as_u64() {
+            Some(n) => json!(format!("0x{:x}", n)),
+            None => p,
+        })
+        .collect()
+}
+
+/// One line of a recording
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Recorded {
+    Notification {
+        subscription: String,
+        result: Value,
+    },
+    Call {
+        method: String,
+        #[serde(default)]
+        params: Value,
+        #[serde(default)]
+        result: Value,
+        #[serde(default)]
+        error: Option<Value>,
+    },
+}
+
+/// A recording, answering calls the way the node did
+struct Recording {
+    /// answers by method, then by normalized params, in recorded order
+    responses: HashMap<String, HashMap<String, Vec<Result<Value, Value>>>>,
+    /// notifications not yet sent, by subscription method
+    notifications: Mutex<HashMap<String, VecDeque<Value>>>,
+    /// times each (method, params) was called
+    calls: Mutex<HashMap<String, usize>>,
+    /// next subscription id
+    ids: AtomicU64,
+}
+
+impl Recording {
+    fn read(path: &Path) -> Result<Self, ArchiveError> {
+        let mut responses = HashMap::new();
+        let mut notifications = HashMap::new();
+        for line in std::fs::read_to_string(path)?.lines() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str(line)? {
+                Recorded::Notification {
+                    subscription,
+                    result,
+                } => notifications
+                    .entry(subscription)
+                    .or_insert_with(VecDeque::new)
+                    .push_back(result),
+                Recorded::Call {
+                    method,
+                    params,
+                    result,
+                    error,
+                } => responses
+                    .entry(method)
+                    .or_insert_with(HashMap::new)
+                    .entry(normalize(params).to_string())
+                    .or_insert_with(Vec::new)
+                    .push(error.map(Err).unwrap_or(Ok(result))),
+            }
+        }
+        Ok(Self {
+            responses,
+            notifications: Mutex::new(notifications),
+            calls: Mutex::new(HashMap::new()),
+            ids: AtomicU64::new(1),
+        })
+    }
+
+    /// the answer to the `nth` call of `method` with `params`: its result, or its error
+    /// `None` if the node was never asked `method`. Params it was never asked with are
+    /// answered with `null`, like a node asked for a block it does not have
+    fn answer(&self, method: &str, params: &str, nth: usize) -> Option<Result<Value, Value>> {
+        let answers = self.responses.get(method)?;
+        Some(match answers.get(params) {
+            Some(a) => a[std::cmp::min(nth, a.len() - 1)].clone(),
+            None => Ok(Value::Null),
+        })
+    }
+
+    /// the notifications of `method` not sent yet, leaving none for the next subscription
+    fn notifications(&self, method: &str) -> Vec<Value> {
+        let mut notifications = self
+            .notifications
+            .lock()
+            .expect("Lock is never poisoned; qed");
+        notifications
+            .remove(method)
+            .map(Vec::from)
+            .unwrap_or_default()
+    }
+}
+
+impl Answers for Recording {
+    fn call(&self, call: &Value) -> (Value, Vec<Value>) {
+        let id = call["id"].clone();
+        let method = call["method"].as_str().unwrap_or_default();
+        let params = normalize(call["params"].clone()).to_string();
+
+        if let Some((_, notify)) = SUBSCRIPTIONS.iter().find(|(m, _)| *m == method) {
+            let subscription = self.ids.fetch_add(1, Ordering::SeqCst);
+            let notifications = self
+                .notifications(method)
+                .into_iter()
+                .map(|result| {
+                    json!({
+                        "jsonrpc": "2.0",
+                        "method": notify,
+                        "params": { "subscription": subscription, "result": result }
+                    })
+                })
+                .collect();
+            let reply = json!({ "jsonrpc": "2.0", "id": id, "result": subscription });
+            return (reply, notifications);
+        }
+        if method.contains("_unsubscribe") {
+            return (
+                json!({ "jsonrpc": "2.0", "id": id, "result": true }),
+                Vec::new(),
+            );
+        }
+        let nth = {
+            let mut calls = self.calls.lock().expect("Lock is never poisoned; qed");
+            let count = calls.entry(format!("{}{}", method, params)).or_insert(0);
+            *count += 1;
+            *count - 1
+        };
+        let reply = match self.answer(method, &params, nth) {
+            Some(Ok(result)) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Some(Err(error)) => json!({ "jsonrpc": "2.0", "id": id, "error": error }),
+            None => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": -32601, "message": "Method not found" }
+            }),
+        };
+        (reply, Vec::new())
+    }
+}
+
+/// A running server replaying a recording
+pub(crate) struct ReplayNode {
+    url: url::Url,
+}
+
+impl ReplayNode {
+    /// serve the recording at `path` on a free local port
+    pub(crate) fn start(path: &Path) -> Result<Self, ArchiveError> {
+        let url = serve(Recording::read(path)?)?;
+        Ok(Self { url })
+    }
+
+    pub(crate) fn url(&self) -> &url::Url {
+        &self.url
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::record::Recorder;
+
+    fn record(name: &str, record: impl FnOnce(&Recorder)) -> Recording {
+        let path =
+            std::env::temp_dir().join(format!("archive-recording-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        record(&Recorder::open(&path).unwrap());
+        let recording = Recording::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        recording
+    }
+
+    #[test]
+    fn should_replay_a_recording_in_order() {
+        let params = json!([null]);
+        let recording = record("calls", |recorder| {
+            recorder.call("chain_getHeader", &params, &Ok::<_, ArchiveError>(json!(1)));
+            recorder.call("chain_getHeader", &params, &Ok::<_, ArchiveError>(json!(2)));
+            recorder.call::<Value>(
+                "state_getStorage",
+                &json!(["0x00"]),
+                &Err(ArchiveError::RpcResponse("Unknown block".to_string())),
+            );
+        });
+        let params = normalize(params).to_string();
+        assert_eq!(
+            recording.answer("chain_getHeader", &params, 0),
+            Some(Ok(json!(1)))
+        );
+        assert_eq!(
+            recording.answer("chain_getHeader", &params, 1),
+            Some(Ok(json!(2)))
+        );
+        assert_eq!(
+            recording.answer("chain_getHeader", &params, 5),
+            Some(Ok(json!(2)))
+        );
+        assert!(recording
+            .answer("state_getStorage", &json!(["0x00"]).to_string(), 0)
+            .unwrap()
+            .is_err());
+        assert_eq!(
+            recording.answer("chain_getHeader", &json!(["0x01"]).to_string(), 0),
+            Some(Ok(Value::Null))
+        );
+        assert_eq!(recording.answer("system_unknown", "[]", 0), None);
+    }
+
+    #[test]
+    fn should_send_notifications_once() {
+        let recording = record("notifications", |recorder| {
+            recorder.notification("chain_subscribeFinalizedHeads", &json!(3));
+            recorder.notification("chain_subscribeFinalizedHeads", &json!(4));
+        });
+        let subscribe = json!({ "id": 1, "method": "chain_subscribeFinalizedHeads" });
+        let (_, notifications) = recording.call(&subscribe);
+        let results = notifications
+            .iter()
+            .map(|n| n["params"]["result"].clone())
+            .collect::<Vec<Value>>();
+        assert_eq!(results, vec![json!(3), json!(4)]);
+        let (_, resubscribed) = recording.call(&subscribe);
+        assert!(resubscribed.is_empty());
+    }
+}
@@ -0,0 +1,130 @@
+// Copyright 2017-2019 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Retrying RPC requests that failed for reasons that may go away
+
+use futures::Future;
+use log::warn;
+use rand::Rng;
+use tokio::time;
+
+use std::time::Duration;
+
+use crate::error::Error as ArchiveError;
+
+/// wait before the second attempt
+const INITIAL_BACKOFF: Duration = Duration::from_millis(250);
+/// longest wait between two attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Run `request` until it succeeds, fails with an error that is not retryable,
+/// or has been attempted `max_attempts` times
+/// The wait between attempts doubles each time, with jitter so that many failed
+/// requests do not all retry at once
+pub(crate) async fn retry<F, Fut, R>(
+    method: &str,
+    max_attempts: usize,
+    mut request: F,
+) -> Result<R, ArchiveError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<R, ArchiveError>>,
+{
+    let mut attempt = 1;
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        match request().await {
+            Ok(r) => return Ok(r),
+            Err(e) if e.is_retryable() && attempt < max_attempts => {
+                let wait = jitter(backoff);
+                warn!(
+                    "{} failed (attempt {} of {}), retrying in {:?}: {:?}",
+                    method, attempt, max_attempts, wait, e
+                );
+                time::delay_for(wait).await;
+                backoff = std::cmp::min(backoff * 2, MAX_BACKOFF);
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// somewhere between half of `backoff` and all of it
+fn jitter(backoff: Duration) -> Duration {
+    let half = backoff / 2;
+    let millis = half.as_millis() as u64;
+    half + Duration::from_millis(rand::thread_rng().gen_range(0, millis + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonrpc_core_client::RpcError;
+    use std::cell::Cell;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn should_retry_transient_errors() {
+        let mut rt = Runtime::new().unwrap();
+        let attempts = Cell::new(0);
+        let res = rt.block_on(retry("test", 5, || {
+            attempts.set(attempts.get() + 1);
+            let attempt = attempts.get();
+            async move {
+                if attempt < 3 {
+                    Err(ArchiveError::Rpc(RpcError::Timeout))
+                } else {
+                    Ok(attempt)
+                }
+            }
+        }));
+        assert_eq!(res.unwrap(), 3);
+    }
+
+    #[test]
+    fn should_not_retry_permanent_errors() {
+        let mut rt = Runtime::new().unwrap();
+        let attempts = Cell::new(0);
+        let res: Result<(), _> = rt.block_on(retry("test", 5, || {
+            attempts.set(attempts.get() + 1);
+            async { Err(ArchiveError::DataNotFound("block".to_string())) }
+        }));
+        assert!(res.is_err());
+        assert_eq!(attempts.get(), 1);
+    }
+
+    #[test]
+    fn should_give_up_after_max_attempts() {
+        let mut rt = Runtime::new().unwrap();
+        let attempts = Cell::new(0);
+        let res: Result<(), _> = rt.block_on(retry("test", 2, || {
+            attempts.set(attempts.get() + 1);
+            async { Err(ArchiveError::Rpc(RpcError::Timeout)) }
+        }));
+        assert!(res.is_err());
+        assert_eq!(attempts.get(), 2);
+    }
+
+    #[test]
+    fn jitter_stays_within_backoff() {
+        let backoff = Duration::from_millis(1000);
+        for _ in 0..100 {
+            let wait = jitter(backoff);
+            assert!(wait >= backoff / 2 && wait <= backoff);
+        }
+    }
+}
@@ -18,12 +18,23 @@
 use codec::Decode;
 use futures::{
     compat::{Future01CompatExt, Stream01CompatExt},
-    future::{self, TryFutureExt},
-    stream::{Stream, TryStreamExt},
+    future::{self, FutureExt, TryFutureExt},
+    stream::{self, BoxStream, StreamExt, TryStreamExt},
+};
+use futures01::Future as Future01;
+use jsonrpc_core_client::{
+    transports::{http, ws},
+    RpcChannel, RpcError, TypedClient,
 };
-use jsonrpc_core_client::{transports::ws, RpcChannel};
 use runtime_metadata::RuntimeMetadataPrefixed;
-use substrate_primitives::storage::{StorageData, StorageKey};
+use runtime_primitives::traits::Header as HeaderTrait;
+use runtime_version::RuntimeVersion;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use substrate_primitives::{
+    storage::{StorageChangeSet, StorageData, StorageKey},
+    Bytes, U256,
+};
 use substrate_rpc_api::{
     author::AuthorClient,
     chain::ChainClient,
@@ -32,14 +43,118 @@ use substrate_rpc_api::{
 };
 use substrate_rpc_primitives::{list::ListOrValue, number::NumberOrHex};
 
-use std::convert::TryInto;
+use tokio::time;
 
+use std::{
+    convert::TryInto,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use super::{batch, record::Recorder, retry};
 use crate::{
     error::Error as ArchiveError,
     metadata::Metadata,
+    metrics,
     types::{SubstrateBlock, System},
 };
 
+/// how often heads are polled for when connected over HTTP
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Headers as they are announced by the node
+pub(crate) type HeadStream<T> = BoxStream<'static, Result<<T as System>::Header, ArchiveError>>;
+
+/// Runtime versions as they are announced by the node
+pub(crate) type VersionStream = BoxStream<'static, Result<RuntimeVersion, ArchiveError>>;
+
+/// Changes to storage as they are announced by the node
+pub(crate) type ChangeStream<T> =
+    BoxStream<'static, Result<StorageChangeSet<<T as System>::Hash>, ArchiveError>>;
+
+/// How requests to a node are made
+#[derive(Debug, Clone)]
+pub(crate) struct RequestOptions {
+    /// times a request is attempted before giving up
+    /// only timeouts and transport errors are retried
+    pub max_attempts: usize,
+    /// calls sent in one JSON-RPC batch message
+    pub batch_size: usize,
+    /// websocket connections kept open to a node for batches, one per batch in flight
+    pub batch_connections: usize,
+    /// where every request and response is written, if recording
+    pub recorder: Option<Arc<Recorder>>,
+}
+
+impl Default for RequestOptions {
+    fn default() -> Self {
+        Self {
+            max_attempts: 1,
+            batch_size: 1,
+            batch_connections: 1,
+            recorder: None,
+        }
+    }
+}
+
+/// prefix of the top-level keys that child tries are stored under
+pub(crate) const CHILD_STORAGE_PREFIX: &[u8] = b":child_storage:";
+/// prefix of the keys of default child tries, followed by the unique id of the trie
+const DEFAULT_CHILD_STORAGE_PREFIX: &[u8] = b":child_storage:default:";
+/// `ChildType` of default child tries
+const DEFAULT_CHILD_TYPE: u32 = 1;
+
+/// params of a child storage call: the child trie, its unique id and type, then `key` and `hash`
+fn child_params<H: Serialize>(child_storage_key: &StorageKey, key: &StorageKey, hash: &H) -> Value {
+    let id = &child_storage_key.0;
+    let unique_id = if id.starts_with(DEFAULT_CHILD_STORAGE_PREFIX) {
+        &id[DEFAULT_CHILD_STORAGE_PREFIX.len()..]
+    } else {
+        &id[..]
+    };
+    json!([
+        child_storage_key,
+        Bytes(unique_id.to_vec()),
+        DEFAULT_CHILD_TYPE,
+        key,
+        hash
+    ])
+}
+
+/// Proof of storage values at a block, as `state_getReadProof` returns it
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ReadProof<Hash> {
+    pub at: Hash,
+    /// the trie nodes needed to read the values from the state root
+    pub proof: Vec<Bytes>,
+}
+
+/// How requests reach the node, chosen by the scheme of the url
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Transport {
+    /// `ws://` or `wss://`, with subscriptions
+    Ws,
+    /// `http://` or `https://`; heads are polled for, since HTTP has no subscriptions
+    Http,
+}
+
+impl Transport {
+    pub(crate) fn from_url(url: &url::Url) -> Result<Self, ArchiveError> {
+        match url.scheme() {
+            "ws" | "wss" => Ok(Transport::Ws),
+            "http" | "https" => Ok(Transport::Http),
+            s => Err(ArchiveError::UnexpectedType(format!(
+                "Unsupported RPC url scheme {}",
+                s
+            ))),
+        }
+    }
+}
+
 impl<T: System> From<RpcChannel> for SubstrateRpc<T> {
     fn from(channel: RpcChannel) -> Self {
         Self {
@@ -47,6 +162,11 @@ impl<T: System> From<RpcChannel> for SubstrateRpc<T> {
             chain: channel.clone().into(),
             author: channel.clone().into(),
             system: channel.clone().into(),
+            raw: channel.clone().into(),
+            closed: Arc::new(AtomicBool::new(false)),
+            transport: Transport::Ws,
+            batch: None,
+            options: RequestOptions::default(),
         }
     }
 }
@@ -55,49 +175,285 @@ impl<T: System> From<RpcChannel> for SubstrateRpc<T> {
 pub struct SubstrateRpc<T: System> {
     state: StateClient<T::Hash>,
     chain: ChainClient<T::BlockNumber, T::Hash, <T as System>::Header, SubstrateBlock<T>>,
-    #[allow(dead_code)] // TODO remove
-    author: AuthorClient<T::Hash, T::Hash>, // TODO get types right
+    author: AuthorClient<T::Hash, T::Hash>,
     system: SystemClient<T::Hash, T::BlockNumber>,
+    /// for calls whose params differ between versions of Substrate
+    raw: TypedClient,
+    /// set once the websocket is closed, or an HTTP node could not be reached
+    closed: Arc<AtomicBool>,
+    transport: Transport,
+    /// where batch requests are sent, if connected over websockets
+    batch: Option<batch::Connection>,
+    options: RequestOptions,
 }
 
 impl<T> SubstrateRpc<T>
 where
     T: System,
 {
-    /// instantiate new client
-    pub(crate) async fn connect(url: &url::Url) -> Result<Self, ArchiveError> {
-        let (client, fut) = ws::raw_connect(websocket::ClientBuilder::from_url(url))
+    /// instantiate new client, over websockets or HTTP depending on the url
+    pub(crate) async fn connect(
+        url: &url::Url,
+        options: RequestOptions,
+    ) -> Result<Self, ArchiveError> {
+        let mut client = match Transport::from_url(url)? {
+            Transport::Ws => Self::connect_ws(url).await?,
+            Transport::Http => Self::connect_http(url).await?,
+        };
+        if client.transport == Transport::Ws {
+            client.batch = Some(batch::Connection::new(
+                url.clone(),
+                options.batch_connections,
+            ));
+        }
+        client.options = options;
+        Ok(client)
+    }
+
+    async fn connect_http(url: &url::Url) -> Result<Self, ArchiveError> {
+        let mut client: Self = http::connect(url.as_str())
             .compat()
             .map_err(|e| ArchiveError::from(e))
             .await?;
-        tokio::spawn(fut.compat());
+        client.transport = Transport::Http;
         Ok(client)
     }
 
-    /// send all new headers back to main thread
-    pub(crate) async fn subscribe_new_heads(
-        &self,
-    ) -> Result<impl Stream<Item = Result<T::Header, ArchiveError>>, ArchiveError> {
-        let stream = self
-            .chain
-            .subscribe_new_heads()
+    async fn connect_ws(url: &url::Url) -> Result<Self, ArchiveError> {
+        let (client, fut) = ws::raw_connect(websocket::ClientBuilder::from_url(url))
             .compat()
             .map_err(|e| ArchiveError::from(e))
             .await?;
+        let client: Self = client;
+        let closed = client.closed.clone();
+        // the connection future finishes once the websocket is closed
+        tokio::spawn(fut.compat().map(move |res| {
+            if let Err(e) = res {
+                log::warn!("Connection closed: {:?}", e);
+            }
+            closed.store(true, Ordering::SeqCst);
+        }));
+        Ok(client)
+    }
+
+    /// whether the connection to the node was dropped
+    /// HTTP has no connection to drop, so an HTTP node counts as down once a request
+    /// still could not reach it after every attempt
+    pub(crate) fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
 
-        Ok(stream.compat().map_err(|e| ArchiveError::from(e)))
+    /// send all new headers back to main thread
+    /// over HTTP, the best header is polled for instead
+    pub(crate) async fn subscribe_new_heads(&self) -> Result<HeadStream<T>, ArchiveError> {
+        if self.transport == Transport::Http {
+            return Ok(self.recorded("chain_subscribeNewHeads", self.poll_heads(false)));
+        }
+        let stream = metrics::time_rpc(
+            "chain_subscribeNewHeads",
+            self.chain.subscribe_new_heads().compat(),
+        )
+        .map_err(|e| ArchiveError::from(e))
+        .await?;
+        let stream = stream.compat().map_err(|e| ArchiveError::from(e)).boxed();
+        Ok(self.recorded("chain_subscribeNewHeads", stream))
     }
 
     /// send all finalized headers back to main thread
-    pub(crate) async fn subscribe_finalized_heads(
+    /// over HTTP, the finalized head is polled for instead
+    pub(crate) async fn subscribe_finalized_heads(&self) -> Result<HeadStream<T>, ArchiveError> {
+        if self.transport == Transport::Http {
+            return Ok(self.recorded("chain_subscribeFinalizedHeads", self.poll_heads(true)));
+        }
+        let stream = metrics::time_rpc(
+            "chain_subscribeFinalizedHeads",
+            self.chain.subscribe_finalized_heads().compat(),
+        )
+        .await?;
+        let stream = stream.compat().map_err(|e| ArchiveError::from(e)).boxed();
+        Ok(self.recorded("chain_subscribeFinalizedHeads", stream))
+    }
+
+    /// yields a header each time the head changes, checking every `POLL_INTERVAL`
+    /// follows the finalized head if `finalized`, otherwise the best head
+    fn poll_heads(&self, finalized: bool) -> HeadStream<T> {
+        let chain = self.chain.clone();
+        stream::unfold(None, move |last: Option<T::Hash>| {
+            let chain = chain.clone();
+            async move {
+                loop {
+                    time::delay_for(POLL_INTERVAL).await;
+                    let head: Result<Option<T::Header>, ArchiveError> = async {
+                        let hash = if finalized {
+                            let hash = metrics::time_rpc(
+                                "chain_getFinalizedHead",
+                                chain.finalized_head().compat(),
+                            )
+                            .await?;
+                            Some(hash)
+                        } else {
+                            None
+                        };
+                        Ok(
+                            metrics::time_rpc("chain_getHeader", chain.header(hash).compat())
+                                .await?,
+                        )
+                    }
+                    .await;
+                    match head {
+                        Ok(Some(head)) if Some(head.hash()) != last => {
+                            let hash = head.hash();
+                            return Some((Ok(head), Some(hash)));
+                        }
+                        Ok(_) => continue,
+                        Err(e) => return Some((Err(e), last)),
+                    }
+                }
+            }
+        })
+        .boxed()
+    }
+
+    /// whether the node can push notifications, rather than being polled
+    pub(crate) fn can_subscribe(&self) -> bool {
+        self.transport == Transport::Ws
+    }
+
+    /// send the changes to `keys` for every block that changes them
+    /// only websocket nodes can be subscribed to
+    pub(crate) async fn subscribe_storage(
+        &self,
+        keys: Vec<StorageKey>,
+    ) -> Result<ChangeStream<T>, ArchiveError> {
+        if !self.can_subscribe() {
+            return Err(ArchiveError::Transport(
+                "Storage subscriptions need a websocket connection".to_string(),
+            ));
+        }
+        let stream = metrics::time_rpc(
+            "state_subscribeStorage",
+            self.state.subscribe_storage(Some(keys)).compat(),
+        )
+        .await?;
+        let stream = stream.compat().map_err(|e| ArchiveError::from(e)).boxed();
+        Ok(self.recorded("state_subscribeStorage", stream))
+    }
+
+    /// send the runtime version each time it changes
+    /// over HTTP, the runtime version is polled for instead
+    pub(crate) async fn subscribe_runtime_version(&self) -> Result<VersionStream, ArchiveError> {
+        if self.transport == Transport::Http {
+            let stream = self.poll_runtime_version();
+            return Ok(self.recorded("state_subscribeRuntimeVersion", stream));
+        }
+        let stream = metrics::time_rpc(
+            "state_subscribeRuntimeVersion",
+            self.state.subscribe_runtime_version().compat(),
+        )
+        .await?;
+        let stream = stream.compat().map_err(|e| ArchiveError::from(e)).boxed();
+        Ok(self.recorded("state_subscribeRuntimeVersion", stream))
+    }
+
+    /// yields the runtime version each time its spec version changes, checking every `POLL_INTERVAL`
+    fn poll_runtime_version(&self) -> VersionStream {
+        let state = self.state.clone();
+        stream::unfold(None, move |last: Option<u32>| {
+            let state = state.clone();
+            async move {
+                loop {
+                    time::delay_for(POLL_INTERVAL).await;
+                    let version = metrics::time_rpc(
+                        "state_getRuntimeVersion",
+                        state.runtime_version(None).compat(),
+                    )
+                    .await
+                    .map_err(ArchiveError::from);
+                    match version {
+                        Ok(v) if Some(v.spec_version) != last => {
+                            let spec = v.spec_version;
+                            return Some((Ok(v), Some(spec)));
+                        }
+                        Ok(_) => continue,
+                        Err(e) => return Some((Err(e), last)),
+                    }
+                }
+            }
+        })
+        .boxed()
+    }
+
+    /// every item of a subscription is recorded, if recording
+    fn recorded<I>(
+        &self,
+        method: &'static str,
+        stream: BoxStream<'static, Result<I, ArchiveError>>,
+    ) -> BoxStream<'static, Result<I, ArchiveError>>
+    where
+        I: Serialize + Send + 'static,
+    {
+        match self.options.recorder.clone() {
+            Some(recorder) => stream
+                .inspect(move |item| {
+                    if let Ok(item) = item {
+                        recorder.notification(method, item)
+                    }
+                })
+                .boxed(),
+            None => stream,
+        }
+    }
+
+    /// Run a request, retrying transient failures
+    /// `params` are what the request sends, and are only used to record it
+    async fn request<F, Fut, R>(
         &self,
-    ) -> Result<impl Stream<Item = Result<T::Header, ArchiveError>>, ArchiveError> {
-        let stream = self.chain.subscribe_finalized_heads().compat().await?;
-        Ok(stream.compat().map_err(|e| ArchiveError::from(e)))
+        method: &'static str,
+        params: Value,
+        request: F,
+    ) -> Result<R, ArchiveError>
+    where
+        F: Fn() -> Fut,
+        Fut: Future01<Item = R, Error = RpcError>,
+        R: Serialize,
+    {
+        let result = retry::retry(method, self.options.max_attempts, || {
+            metrics::time_rpc(method, request().compat()).map_err(ArchiveError::from)
+        })
+        .await;
+        if let Some(recorder) = &self.options.recorder {
+            recorder.call(method, &params, &result);
+        }
+        match &result {
+            Err(e) if e.is_retryable() && self.transport == Transport::Http => {
+                log::warn!("{} could not reach the node, marking it down", method);
+                self.closed.store(true, Ordering::SeqCst);
+            }
+            _ => (),
+        }
+        result
+    }
+
+    /// each call of a batch is recorded, if recording
+    fn record_batch<R: Serialize>(
+        &self,
+        method: &str,
+        params: &[Value],
+        results: &[Result<R, ArchiveError>],
+    ) {
+        if let Some(recorder) = &self.options.recorder {
+            for (p, r) in params.iter().zip(results.iter()) {
+                recorder.call(method, p, r);
+            }
+        }
     }
 
     pub(crate) async fn metadata(&self, hash: Option<T::Hash>) -> Result<Metadata, ArchiveError> {
-        let metadata_bytes = self.state.metadata(hash).compat().await?;
+        let metadata_bytes = self
+            .request("state_getMetadata", json!([hash]), || {
+                self.state.metadata(hash)
+            })
+            .await?;
         let metadata: RuntimeMetadataPrefixed =
             Decode::decode(&mut &metadata_bytes[..]).expect("Decode failed");
         metadata.try_into().map_err(Into::into)
@@ -114,18 +470,41 @@ where
     ) -> Result<Option<StorageData>, ArchiveError> {
         // let hash: Vec<u8> = hash.encode();
         // let hash: T::Hash = Decode::decode(&mut hash.as_slice()).unwrap();
-        self.state
-            .storage(key, Some(hash))
-            .compat()
-            .map_err(Into::into)
-            .await
+        self.request("state_getStorage", json!([key, hash]), || {
+            self.state.storage(key.clone(), Some(hash))
+        })
+        .await
+    }
+
+    /// runtime version at a block, or at the best block if `hash` is `None`
+    pub(crate) async fn runtime_version(
+        &self,
+        hash: Option<T::Hash>,
+    ) -> Result<RuntimeVersion, ArchiveError> {
+        self.request("state_getRuntimeVersion", json!([hash]), || {
+            self.state.runtime_version(hash)
+        })
+        .await
+    }
+
+    /// encoded extrinsics waiting in the transaction pool
+    pub(crate) async fn pending_extrinsics(&self) -> Result<Vec<Bytes>, ArchiveError> {
+        self.request("author_pendingExtrinsics", json!([]), || {
+            self.author.pending_extrinsics()
+        })
+        .await
     }
 
     pub(crate) async fn properties(&self) -> Result<Properties, ArchiveError> {
-        self.system
-            .system_properties()
-            .compat()
-            .map_err(Into::into)
+        self.request("system_properties", json!([]), || {
+            self.system.system_properties()
+        })
+        .await
+    }
+
+    /// name of the chain the node is on
+    pub(crate) async fn chain_name(&self) -> Result<String, ArchiveError> {
+        self.request("system_chain", json!([]), || self.system.system_chain())
             .await
     }
 
@@ -134,18 +513,73 @@ where
         prefix: StorageKey,
         hash: Option<T::Hash>,
     ) -> Result<Vec<StorageKey>, ArchiveError> {
-        self.state
-            .storage_keys(prefix, hash)
-            .compat()
-            .map_err(Into::into)
-            .await
+        self.request("state_getKeys", json!([prefix, hash]), || {
+            self.state.storage_keys(prefix.clone(), hash)
+        })
+        .await
+    }
+
+    /// proof of the values under `keys` at a block
+    pub(crate) async fn read_proof(
+        &self,
+        keys: Vec<StorageKey>,
+        hash: T::Hash,
+    ) -> Result<ReadProof<T::Hash>, ArchiveError> {
+        let params = json!([keys, hash]);
+        self.request("state_getReadProof", params.clone(), || {
+            self.raw
+                .call_method("state_getReadProof", "ReadProof<Hash>", params.clone())
+        })
+        .await
+    }
+
+    /// keys of the child trie stored under `child_storage_key` that start with `prefix`
+    pub(crate) async fn child_storage_keys(
+        &self,
+        child_storage_key: &StorageKey,
+        prefix: &StorageKey,
+        hash: T::Hash,
+    ) -> Result<Vec<StorageKey>, ArchiveError> {
+        let params = child_params(child_storage_key, prefix, &hash);
+        self.request("state_getChildKeys", params.clone(), || {
+            self.raw
+                .call_method("state_getChildKeys", "Vec<StorageKey>", params.clone())
+        })
+        .await
+    }
+
+    /// a value of the child trie stored under `child_storage_key`
+    pub(crate) async fn child_storage(
+        &self,
+        child_storage_key: &StorageKey,
+        key: &StorageKey,
+        hash: T::Hash,
+    ) -> Result<Option<StorageData>, ArchiveError> {
+        let params = child_params(child_storage_key, key, &hash);
+        self.request("state_getChildStorage", params.clone(), || {
+            self.raw.call_method(
+                "state_getChildStorage",
+                "Option<StorageData>",
+                params.clone(),
+            )
+        })
+        .await
+    }
+
+    /// hash of the latest finalized block
+    pub(crate) async fn finalized_head(&self) -> Result<T::Hash, ArchiveError> {
+        self.request("chain_getFinalizedHead", json!([]), || {
+            self.chain.finalized_head()
+        })
+        .await
     }
 
     pub(crate) async fn header(
         &self,
         hash: Option<T::Hash>,
     ) -> Result<Option<T::Header>, ArchiveError> {
-        self.chain.header(hash).compat().map_err(Into::into).await
+        self.request("chain_getHeader", json!([hash]), || self.chain.header(hash))
+            .await
     }
 
     pub(crate) async fn block_from_number(
@@ -164,32 +598,109 @@ where
         match hash {
             ListOrValue::Value(v) => {
                 let block = self
-                    .chain
-                    .block(v)
-                    .compat()
-                    .map_err(|e| ArchiveError::from(e))
+                    .request("chain_getBlock", json!([v]), || self.chain.block(v))
                     .await?;
                 Ok(ListOrValue::Value(block))
             }
             ListOrValue::List(v) => {
                 let mut futures = Vec::new();
                 for hash in v.into_iter() {
-                    futures.push(self.chain.block(hash).compat().map_err(ArchiveError::from))
+                    futures.push(self.request("chain_getBlock", json!([hash]), move || {
+                        self.chain.block(hash)
+                    }))
                 }
                 Ok(ListOrValue::List(future::try_join_all(futures).await?))
             }
         }
     }
 
+    /// Hash of each block number, with the result of each call
+    /// Websocket nodes are sent batches of calls; HTTP nodes get the list in one call
+    pub(crate) async fn batch_hashes(
+        &self,
+        numbers: &[u64],
+    ) -> Result<Vec<Result<Option<T::Hash>, ArchiveError>>, ArchiveError> {
+        if let Some(connection) = &self.batch {
+            let params = numbers
+                .iter()
+                .map(|n| json!([format!("0x{:x}", n)]))
+                .collect::<Vec<Value>>();
+            let results = batch::call(
+                connection,
+                "chain_getBlockHash",
+                params.clone(),
+                self.options.batch_size,
+                self.options.max_attempts,
+            )
+            .await;
+            self.record_batch("chain_getBlockHash", &params, &results);
+            return Ok(results);
+        }
+        let list = numbers
+            .iter()
+            .map(|n| NumberOrHex::Hex(U256::from(*n)))
+            .collect::<Vec<NumberOrHex<T::BlockNumber>>>();
+        match self.hash(Some(ListOrValue::List(list))).await? {
+            ListOrValue::List(v) => Ok(v.into_iter().map(Ok).collect()),
+            ListOrValue::Value(_) => Err(ArchiveError::UnexpectedType(
+                "Expected List, got Value".to_string(),
+            )),
+        }
+    }
+
+    /// Fetch each block by hash, with the result of each call
+    /// Websocket nodes are sent batches of calls; HTTP nodes one request per block
+    pub(crate) async fn batch_blocks(
+        &self,
+        hashes: Vec<T::Hash>,
+    ) -> Result<Vec<Result<Option<SubstrateBlock<T>>, ArchiveError>>, ArchiveError> {
+        if let Some(connection) = &self.batch {
+            let params = hashes.iter().map(|h| json!([h])).collect::<Vec<Value>>();
+            let results = batch::call(
+                connection,
+                "chain_getBlock",
+                params.clone(),
+                self.options.batch_size,
+                self.options.max_attempts,
+            )
+            .await;
+            self.record_batch("chain_getBlock", &params, &results);
+            return Ok(results);
+        }
+        Ok(self.blocks(hashes).await)
+    }
+
+    /// Fetch each block by hash, with the result of each request
+    /// one block failing does not affect the others
+    pub(crate) async fn blocks(
+        &self,
+        hashes: Vec<T::Hash>,
+    ) -> Vec<Result<Option<SubstrateBlock<T>>, ArchiveError>> {
+        let futures = hashes.into_iter().map(|hash| {
+            self.request("chain_getBlock", json!([hash]), move || {
+                self.chain.block(Some(hash))
+            })
+        });
+        future::join_all(futures).await
+    }
+
     pub(crate) async fn hash(
         &self,
         number: Option<ListOrValue<NumberOrHex<T::BlockNumber>>>,
     ) -> Result<ListOrValue<Option<T::Hash>>, ArchiveError> {
-        self.chain
-            .block_hash(number)
-            .compat()
-            .map_err(Into::into)
-            .await
+        self.request("chain_getBlockHash", json!([number]), || {
+            self.chain.block_hash(copy_numbers(&number))
+        })
+        .await
+    }
+
+    /// hash of block 0
+    pub(crate) async fn genesis_hash(&self) -> Result<T::Hash, ArchiveError> {
+        let genesis = NumberOrHex::Hex(U256::zero());
+        match self.hash(Some(ListOrValue::Value(genesis))).await? {
+            ListOrValue::Value(Some(hash)) => Ok(hash),
+            _ => Err(ArchiveError::DataNotFound("Genesis hash".to_string())),
+        }
     }
 
     /// unsubscribe from finalized heads
@@ -205,58 +716,134 @@ where
     }
 }
 
+/// `ListOrValue` and `NumberOrHex` are not `Clone`, so are rebuilt for every attempt
+fn copy_numbers<N: Copy>(
+    numbers: &Option<ListOrValue<NumberOrHex<N>>>,
+) -> Option<ListOrValue<NumberOrHex<N>>> {
+    let copy = |n: &NumberOrHex<N>| match n {
+        NumberOrHex::Number(n) => NumberOrHex::Number(*n),
+        NumberOrHex::Hex(h) => NumberOrHex::Hex(*h),
+    };
+    numbers.as_ref().map(|numbers| match numbers {
+        ListOrValue::Value(n) => ListOrValue::Value(copy(n)),
+        ListOrValue::List(v) => ListOrValue::List(v.iter().map(copy).collect()),
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{tests::Runtime, types::*};
-    use std::str::FromStr;
+    use crate::{
+        rpc::mock::{Fixture, MockNode},
+        tests::Runtime,
+        types::*,
+    };
     use substrate_primitives::{storage::StorageKey, twox_128};
-    use substrate_primitives::{H256, U256};
     use tokio::runtime::Runtime as TokioRuntime;
 
-    fn connect() -> (TokioRuntime, SubstrateRpc<Runtime>) {
+    fn connect(node: &MockNode) -> (TokioRuntime, SubstrateRpc<Runtime>) {
         let mut runtime = TokioRuntime::new().unwrap();
         let rpc = runtime
             .block_on(SubstrateRpc::<Runtime>::connect(
-                &url::Url::parse("ws://127.0.0.1:9944").unwrap(),
+                node.url(),
+                RequestOptions::default(),
             ))
             .unwrap();
         (runtime, rpc)
     }
 
-    // [WARNING] Needs an Rpc running on port 9944
+    fn hash(byte: u8) -> <Runtime as System>::Hash {
+        <Runtime as System>::Hash::repeat_byte(byte)
+    }
+
     #[test]
     fn should_get_block() {
-        let (mut rt, rpc) = connect();
+        let node = MockNode::start(Fixture::load("node"));
+        let (mut rt, rpc) = connect(&node);
         let block = rt
-            .block_on(
-                rpc.block(
-                    "373c569f3520c7ba67a7ac1d6b8e4ead5bd27b1ec28f3e39f5f863c503956e31"
-                        .parse()
-                        .unwrap(),
-                ),
-            )
+            .block_on(rpc.block(ListOrValue::Value(Some(hash(0xa1)))))
             .unwrap();
-        println!("{:?}", block);
+        let block = match block {
+            ListOrValue::Value(Some(block)) => block,
+            _ => panic!("expected one block"),
+        };
+        assert_eq!(*block.block.header.number(), 1);
+        assert_eq!(block.block.header.parent_hash(), &hash(0xa0));
     }
 
-    // [WARNING] Requires an Rpc running on port 9944
     #[test]
     fn should_get_hash() {
-        let (mut rt, rpc) = connect();
-        let hash = rt.block_on(rpc.hash(NumberOrHex::Number(6))).unwrap();
-        println!("{:?}", hash);
+        let node = MockNode::start(Fixture::load("node"));
+        let (mut rt, rpc) = connect(&node);
+        let hash = rt
+            .block_on(rpc.hash(Some(ListOrValue::Value(NumberOrHex::Number(2)))))
+            .unwrap();
+        match hash {
+            ListOrValue::Value(h) => assert_eq!(h, Some(self::hash(0xa2))),
+            _ => panic!("expected one hash"),
+        }
     }
 
     #[test]
     fn should_get_storage() {
-        let (mut rt, rpc) = connect();
-        let timestamp_key = b"Timestamp Now";
-        let storage_key = StorageKey(twox_128(timestamp_key).to_vec());
-        let hash: <Runtime as System>::Hash =
-            "373c569f3520c7ba67a7ac1d6b8e4ead5bd27b1ec28f3e39f5f863c503956e31"
-                .parse()
-                .unwrap();
-        let storage = rt.block_on(rpc.storage(storage_key, hash));
+        let key = StorageKey(twox_128(b"Timestamp Now").to_vec());
+        let node = MockNode::start(Fixture::load("node").respond(
+            "state_getStorage",
+            Some(json!([key, hash(0xa1)])),
+            json!("0x2a00000000000000"),
+        ));
+        let (mut rt, rpc) = connect(&node);
+        let storage = rt.block_on(rpc.storage(key, hash(0xa1))).unwrap();
+        assert_eq!(storage, Some(StorageData(vec![42, 0, 0, 0, 0, 0, 0, 0])));
+    }
+
+    #[test]
+    fn should_get_child_storage() {
+        let child = StorageKey(b":child_storage:default:contract".to_vec());
+        let node = MockNode::start(
+            Fixture::load("node")
+                .respond(
+                    "state_getChildKeys",
+                    Some(json!([child, "0x636f6e7472616374", 1, "0x", hash(0xa1)])),
+                    json!(["0x01"]),
+                )
+                .respond("state_getChildStorage", None, json!("0x2a")),
+        );
+        let (mut rt, rpc) = connect(&node);
+        let keys = rt
+            .block_on(rpc.child_storage_keys(&child, &StorageKey(Vec::new()), hash(0xa1)))
+            .unwrap();
+        assert_eq!(keys, vec![StorageKey(vec![1])]);
+        let data = rt
+            .block_on(rpc.child_storage(&child, &keys[0], hash(0xa1)))
+            .unwrap();
+        assert_eq!(data, Some(StorageData(vec![42])));
+    }
+
+    #[test]
+    fn should_subscribe_to_finalized_heads() {
+        let node = MockNode::start(Fixture::load("node"));
+        let (mut rt, rpc) = connect(&node);
+        let heads = rt.block_on(async {
+            let stream = rpc.subscribe_finalized_heads().await.unwrap();
+            stream.take(2).collect::<Vec<_>>().await
+        });
+        let numbers = heads
+            .into_iter()
+            .map(|h| *h.unwrap().number())
+            .collect::<Vec<_>>();
+        assert_eq!(numbers, vec![1, 2]);
+    }
+
+    #[test]
+    fn should_batch_block_hashes() {
+        let node = MockNode::start(Fixture::load("node"));
+        let (mut rt, rpc) = connect(&node);
+        let hashes = rt.block_on(rpc.batch_hashes(&[0, 1, 7])).unwrap();
+        let hashes = hashes.into_iter().map(|h| h.ok()).collect::<Vec<_>>();
+        assert_eq!(
+            hashes,
+            vec![Some(Some(hash(0xa0))), Some(Some(hash(0xa1))), Some(None)]
+        );
     }
 }
@@ -14,27 +14,53 @@
 // You should have received a copy of the GNU General Public License
 // along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
 //! Default Runtime for tests
-use crate::{ExtractCall, Module, NotHandled, SrmlExt, System};
+use crate::{
+    frame::frame_system::Trait, ChainInfo, Error, ExtractCall, FrameExt, Module, NotHandled,
+    OldExtrinsic, RawExtrinsic, System, ToDatabaseExtrinsic,
+};
 use codec::{Decode, Encode, Error as CodecError, Input};
-#[cfg(test)]
-use node_runtime::{Call, Runtime as RuntimeT, SignedExtra};
-use srml_system::Trait;
+use node_runtime::{Address, Call, Runtime as RuntimeT, SignedExtra};
+use runtime_primitives::{MultiSignature, OpaqueExtrinsic};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Runtime;
 impl System for Runtime {
     type Call = CallWrapper;
+    type Extrinsic = ExtrinsicWrapper;
+    type Signature = MultiSignature;
+    type Address = Address;
     type Index = <RuntimeT as Trait>::Index;
     type BlockNumber = <RuntimeT as Trait>::BlockNumber;
     type Hash = <RuntimeT as Trait>::Hash;
     type Hashing = <RuntimeT as Trait>::Hashing;
     type AccountId = <RuntimeT as Trait>::AccountId;
-    type Lookup = <RuntimeT as Trait>::Lookup;
     type Header = <RuntimeT as Trait>::Header;
     type Event = <RuntimeT as Trait>::Event;
+    type Balance = <RuntimeT as pallet_balances::Trait>::Balance;
     type SignedExtra = SignedExtra;
 }
 
+/// A development chain with the given `system_properties`
+pub fn chain_info(properties: Value) -> ChainInfo {
+    let properties = match properties {
+        Value::Object(map) => map,
+        _ => panic!("properties are an object"),
+    };
+    ChainInfo::new(vec![0; 32], "Development".to_string(), properties)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExtrinsicWrapper(OpaqueExtrinsic);
+impl ToDatabaseExtrinsic for ExtrinsicWrapper {
+    fn to_database(&self) -> Result<RawExtrinsic, Error> {
+        let ext: OldExtrinsic<Address, CallWrapper, MultiSignature, SignedExtra> =
+            Decode::decode(&mut self.0.encode().as_slice())?;
+        Ok(ext.into())
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct CallWrapper {
     inner: Call,
@@ -54,13 +80,13 @@ impl Decode for CallWrapper {
 
 // define all calls/inherents that you want tracked by the archive node
 impl ExtractCall for CallWrapper {
-    fn extract_call(&self) -> (Module, &dyn SrmlExt) {
+    fn extract_call(&self) -> (Module, Box<dyn FrameExt>) {
         match &self.inner {
-            Call::Timestamp(call) => (Module::Timestamp, call),
-            Call::FinalityTracker(call) => (Module::FinalityTracker, call),
+            Call::Timestamp(call) => (Module::Timestamp, Box::new(call.clone())),
+            Call::FinalityTracker(call) => (Module::FinalityTracker, Box::new(call.clone())),
             _ => {
                 println!("Unsupported Module");
-                (Module::NotHandled, &NotHandled)
+                (Module::NotHandled, Box::new(NotHandled))
             }
         }
     }
@@ -19,12 +19,14 @@ mod traits;
 use chrono::{DateTime, TimeZone, Utc};
 use codec::Decode;
 use runtime_primitives::generic::{Block as BlockT, SignedBlock};
-use substrate_primitives::storage::StorageChangeSet;
-use substrate_primitives::storage::StorageData;
+use substrate_primitives::storage::{StorageData, StorageKey};
 
 pub use self::traits::{ExtractCall, ExtrinsicExt, System, ToDatabaseExtrinsic};
 
-use crate::{error::Error, metadata::subxt_metadata::StorageMetadata};
+use crate::{
+    database::models::InsertPendingExtrinsic, error::Error, events::DbEvent,
+    metadata::subxt_metadata::StorageMetadata,
+};
 
 /// A generic substrate block
 pub type SubstrateBlock<T> = SignedBlock<BlockT<<T as System>::Header, <T as System>::Extrinsic>>;
@@ -35,11 +37,15 @@ pub enum Data<T: System> {
     Header(Header<T>),
     FinalizedHead(Header<T>),
     Block(Block<T>),
+    BestBlock(BestBlock<T>),
     BatchBlock(BatchBlock<T>),
     BatchStorage(BatchStorage<T>), // include callback on storage types for exact diesel::call
     Storage(Storage<T>),
+    BatchChildStorage(BatchChildStorage<T>),
     Event(Event<T>),
-    SyncProgress(usize),
+    BatchEvent(BatchEvent<T>),
+    RuntimeVersion(RuntimeVersionRange<T>),
+    PendingExtrinsics(PendingExtrinsics),
 }
 
 // new types to allow implementing of traits
@@ -63,30 +69,65 @@ impl<T: System> Header<T> {
 #[derive(Debug)]
 pub struct Block<T: System> {
     inner: SubstrateBlock<T>,
+    /// hash the block was requested or announced by, if any
+    requested: Option<T::Hash>,
 }
 
 impl<T: System> Block<T> {
-    pub fn new(block: SubstrateBlock<T>) -> Self {
-        Self { inner: block }
+    pub fn new(block: SubstrateBlock<T>, requested: Option<T::Hash>) -> Self {
+        Self {
+            inner: block,
+            requested,
+        }
     }
 
     pub fn inner(&self) -> &SubstrateBlock<T> {
         &self.inner
     }
+
+    pub fn requested(&self) -> Option<&T::Hash> {
+        self.requested.as_ref()
+    }
+}
+
+/// NewType for a block on the best chain that has not been finalized
+#[derive(Debug)]
+pub struct BestBlock<T: System> {
+    inner: SubstrateBlock<T>,
+    /// hash the block was requested or announced by, if any
+    requested: Option<T::Hash>,
+}
+
+impl<T: System> BestBlock<T> {
+    pub fn new(block: SubstrateBlock<T>, requested: Option<T::Hash>) -> Self {
+        Self {
+            inner: block,
+            requested,
+        }
+    }
+
+    pub fn inner(&self) -> &SubstrateBlock<T> {
+        &self.inner
+    }
+
+    pub fn requested(&self) -> Option<&T::Hash> {
+        self.requested.as_ref()
+    }
 }
 
 /// NewType for committing many blocks to the database at once
+/// each block is paired with the hash it was requested by
 #[derive(Debug)]
 pub struct BatchBlock<T: System> {
-    inner: Vec<SubstrateBlock<T>>,
+    inner: Vec<(T::Hash, SubstrateBlock<T>)>,
 }
 
 impl<T: System> BatchBlock<T> {
-    pub fn new(blocks: Vec<SubstrateBlock<T>>) -> Self {
+    pub fn new(blocks: Vec<(T::Hash, SubstrateBlock<T>)>) -> Self {
         Self { inner: blocks }
     }
 
-    pub fn inner(&self) -> &Vec<SubstrateBlock<T>> {
+    pub fn inner(&self) -> &Vec<(T::Hash, SubstrateBlock<T>)> {
         &self.inner
     }
 }
@@ -94,38 +135,53 @@ impl<T: System> BatchBlock<T> {
 /// newType for Storage Data
 #[derive(Debug)]
 pub struct Storage<T: System> {
-    data: StorageData,
+    /// `None` if nothing is stored under this key at this block
+    data: Option<StorageData>,
     hash: T::Hash,
-    // meta: StorageMetadata,
+    key: StorageKey,
+    meta: StorageMetadata,
 }
 
 impl<T> Storage<T>
 where
     T: System,
 {
-    pub fn new(data: StorageData, hash: T::Hash /* meta: StorageMetadata */) -> Self {
+    pub fn new(
+        data: Option<StorageData>,
+        hash: T::Hash,
+        key: StorageKey,
+        meta: StorageMetadata,
+    ) -> Self {
         Self {
             data,
-            hash, /*, meta */
+            hash,
+            key,
+            meta,
         }
     }
 
-    pub fn data(&self) -> &StorageData {
-        &self.data
+    pub fn data(&self) -> Option<&StorageData> {
+        self.data.as_ref()
     }
 
     pub fn hash(&self) -> &T::Hash {
         &self.hash
     }
-    /*
-        pub fn metadata(&self) -> &StorageMetadata {
-            &self.meta
-        }
-    */
+
+    pub fn key(&self) -> &StorageKey {
+        &self.key
+    }
+
+    pub fn metadata(&self) -> &StorageMetadata {
+        &self.meta
+    }
 
     pub fn get_timestamp(&self) -> Result<DateTime<Utc>, Error> {
         // TODO: check if storage key type is actually from the timestamp module
-        let unix_time: i64 = Decode::decode(&mut self.data().0.as_slice())?;
+        let data = self
+            .data()
+            .ok_or_else(|| Error::DataNotFound("Timestamp".into()))?;
+        let unix_time: i64 = Decode::decode(&mut data.0.as_slice())?;
         Ok(Utc.timestamp_millis(unix_time)) // panics if time is incorrect
     }
 }
@@ -153,19 +209,203 @@ where
     }
 }
 
-/// NewType for committing Events to the database
-#[derive(Debug, PartialEq, Eq)]
+/// A value in a child trie at a block
+#[derive(Debug)]
+pub struct ChildStorage<T: System> {
+    hash: T::Hash,
+    /// top-level key the child trie is stored under
+    child_storage_key: StorageKey,
+    key: StorageKey,
+    data: StorageData,
+}
+
+impl<T> ChildStorage<T>
+where
+    T: System,
+{
+    pub fn new(
+        hash: T::Hash,
+        child_storage_key: StorageKey,
+        key: StorageKey,
+        data: StorageData,
+    ) -> Self {
+        Self {
+            hash,
+            child_storage_key,
+            key,
+            data,
+        }
+    }
+
+    pub fn hash(&self) -> &T::Hash {
+        &self.hash
+    }
+
+    pub fn child_storage_key(&self) -> &StorageKey {
+        &self.child_storage_key
+    }
+
+    pub fn key(&self) -> &StorageKey {
+        &self.key
+    }
+
+    pub fn data(&self) -> &StorageData {
+        &self.data
+    }
+}
+
+/// NewType for committing the child storage of many blocks at once
+#[derive(Debug)]
+pub struct BatchChildStorage<T: System> {
+    inner: Vec<ChildStorage<T>>,
+}
+
+impl<T> BatchChildStorage<T>
+where
+    T: System,
+{
+    pub fn new(data: Vec<ChildStorage<T>>) -> Self {
+        Self { inner: data }
+    }
+
+    pub fn inner(&self) -> &Vec<ChildStorage<T>> {
+        &self.inner
+    }
+
+    pub fn consume(self) -> Vec<ChildStorage<T>> {
+        self.inner
+    }
+}
+
+/// NewType for committing extrinsics newly seen in the transaction pool
+#[derive(Debug)]
+pub struct PendingExtrinsics {
+    inner: Vec<InsertPendingExtrinsic>,
+}
+
+impl PendingExtrinsics {
+    pub fn new(extrinsics: Vec<InsertPendingExtrinsic>) -> Self {
+        Self { inner: extrinsics }
+    }
+
+    pub fn inner(&self) -> &Vec<InsertPendingExtrinsic> {
+        &self.inner
+    }
+
+    pub fn consume(self) -> Vec<InsertPendingExtrinsic> {
+        self.inner
+    }
+}
+
+/// A range of blocks that were executed with the same runtime
+#[derive(Debug)]
+pub struct RuntimeVersionRange<T: System> {
+    spec_name: String,
+    spec_version: u32,
+    from_block: u64,
+    to_block: u64,
+    /// hash of `from_block`
+    from_hash: T::Hash,
+}
+
+impl<T> RuntimeVersionRange<T>
+where
+    T: System,
+{
+    pub fn new(spec_name: String, spec_version: u32, from: (u64, T::Hash), to_block: u64) -> Self {
+        Self {
+            spec_name,
+            spec_version,
+            from_block: from.0,
+            to_block,
+            from_hash: from.1,
+        }
+    }
+
+    pub fn spec_name(&self) -> &str {
+        &self.spec_name
+    }
+
+    pub fn spec_version(&self) -> u32 {
+        self.spec_version
+    }
+
+    pub fn from_block(&self) -> u64 {
+        self.from_block
+    }
+
+    pub fn to_block(&self) -> u64 {
+        self.to_block
+    }
+
+    pub fn from_hash(&self) -> &T::Hash {
+        &self.from_hash
+    }
+
+    /// widen the range up to `to_block`
+    pub fn extend(&mut self, to_block: u64) {
+        self.to_block = std::cmp::max(self.to_block, to_block);
+    }
+}
+
+/// NewType for committing the events of a block to the database
+#[derive(Debug)]
 pub struct Event<T: System> {
-    change_set: StorageChangeSet<T::Hash>,
+    hash: T::Hash,
+    events: Vec<DbEvent>,
+    /// the encoded events and the decoding error, if they could not be decoded
+    undecoded: Option<(Vec<u8>, String)>,
 }
 
 impl<T: System> Event<T> {
-    pub fn new(change_set: StorageChangeSet<T::Hash>) -> Self {
-        Self { change_set }
+    pub fn new(hash: T::Hash, events: Vec<DbEvent>) -> Self {
+        Self {
+            hash,
+            events,
+            undecoded: None,
+        }
+    }
+
+    /// events that could not be decoded, archived as they are
+    pub fn undecoded(hash: T::Hash, data: Vec<u8>, error: String) -> Self {
+        Self {
+            hash,
+            events: Vec::new(),
+            undecoded: Some((data, error)),
+        }
+    }
+
+    pub fn hash(&self) -> &T::Hash {
+        &self.hash
+    }
+
+    pub fn events(&self) -> &Vec<DbEvent> {
+        &self.events
+    }
+
+    /// the encoded events and why they could not be decoded
+    pub fn raw(&self) -> Option<&(Vec<u8>, String)> {
+        self.undecoded.as_ref()
+    }
+}
+
+/// NewType for committing the events of many blocks at once
+#[derive(Debug)]
+pub struct BatchEvent<T: System> {
+    inner: Vec<Event<T>>,
+}
+
+impl<T: System> BatchEvent<T> {
+    pub fn new(events: Vec<Event<T>>) -> Self {
+        Self { inner: events }
     }
 
-    pub fn change_set(&self) -> &StorageChangeSet<T::Hash> {
-        &self.change_set
+    pub fn inner(&self) -> &Vec<Event<T>> {
+        &self.inner
+    }
+
+    pub fn consume(self) -> Vec<Event<T>> {
+        self.inner
     }
 }
 
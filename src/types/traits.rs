@@ -108,6 +108,7 @@ pub trait System: Send + Sync + 'static + Debug {
         + Debug
         + MaybeDisplay
         + SimpleBitOps
+        + Ord
         + Default
         + Copy
         + CheckEqual
@@ -147,6 +148,9 @@ pub trait System: Send + Sync + 'static + Debug {
     /// The aggregated event type of the runtime.
     type Event: Parameter + Member;
 
+    /// The balance of an account, as `pallet_balances::Trait::Balance` of the runtime.
+    type Balance: Parameter + Member + MaybeDisplay + SimpleArithmetic + Default + Copy;
+
     /// The `SignedExtension` to the basic transaction logic.
     type SignedExtra: SignedExtension;
 }
@@ -0,0 +1,73 @@
+// Copyright 2017-2019 Parity Technologies (UK) Ltd.
+// This file is part of substrate-archive.
+
+// substrate-archive is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// substrate-archive is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with substrate-archive.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Checking archived storage against the state root of its block
+
+use state_machine::{read_proof_check, StorageProof};
+
+use crate::{error::Error as ArchiveError, types::System};
+
+/// whether each archived value is the one `proof` shows under its key, at `state_root`
+/// Errors if `proof` does not prove every key against `state_root`
+pub(crate) fn check<T>(
+    state_root: T::Hash,
+    proof: Vec<Vec<u8>>,
+    values: &[(Vec<u8>, Option<Vec<u8>>)],
+) -> Result<Vec<bool>, ArchiveError>
+where
+    T: System,
+{
+    let keys = values.iter().map(|(k, _)| k);
+    let proven = read_proof_check::<T::Hashing, _>(state_root, StorageProof::new(proof), keys)
+        .map_err(|e| ArchiveError::InvalidProof(e.to_string()))?;
+    Ok(values
+        .iter()
+        .map(|(key, value)| proven.get(key) == Some(value))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::Runtime;
+    use runtime_primitives::traits::Hash;
+
+    /// root of a trie with nothing in it
+    fn empty_root() -> <Runtime as System>::Hash {
+        <Runtime as System>::Hashing::hash(&[0u8])
+    }
+
+    #[test]
+    fn should_verify_absent_values() {
+        let values = vec![(b"key".to_vec(), None)];
+        let checked = check::<Runtime>(empty_root(), Vec::new(), &values).unwrap();
+        assert_eq!(checked, vec![true]);
+    }
+
+    #[test]
+    fn should_flag_values_the_proof_does_not_show() {
+        let values = vec![(b"key".to_vec(), None), (b"other".to_vec(), Some(vec![1]))];
+        let checked = check::<Runtime>(empty_root(), Vec::new(), &values).unwrap();
+        assert_eq!(checked, vec![true, false]);
+    }
+
+    #[test]
+    fn should_reject_proofs_of_another_root() {
+        let root = <Runtime as System>::Hashing::hash(b"not a trie");
+        let values = vec![(b"key".to_vec(), None)];
+        assert!(check::<Runtime>(root, Vec::new(), &values).is_err());
+    }
+}